@@ -0,0 +1,63 @@
+//! How long resolving what a large project needs from the registry takes.
+//!
+//! Run with `cargo bench --bench resolve`.
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use riff::dependency_registry::{DependencyRegistry, DependencyRegistryOptions};
+
+/// How many crates the synthetic project depends on, all of which the registry knows
+const CRATES: usize = 500;
+
+/// A registry with settings for `CRATES` crates, some shared between them like real `-sys` crates
+fn synthetic_registry() -> String {
+    let target = target_lexicon::HOST;
+    let dependencies = (0..CRATES)
+        .map(|i| {
+            format!(
+                r#""crate-{i}-sys": {{
+                    "build-inputs": [ "lib{i}", "pkg-config", "shared{shared}" ],
+                    "runtime-inputs": [ "lib{i}" ],
+                    "environment-variables": {{ "CRATE_{i}_NO_VENDOR": "1" }},
+                    "targets": {{ "{target}": {{ "build-inputs": [ "lib{i}-{target}" ] }} }},
+                    "versions": [ {{ "version": ">=2", "build-inputs": [ "lib{i}_2" ] }} ]
+                }}"#,
+                shared = i % 20,
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(",\n");
+    format!(
+        r#"{{ "version": 1, "language": {{ "rust": {{ "dependencies": {{ {dependencies} }} }} }} }}"#
+    )
+}
+
+fn resolve(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let cache_dir = tempfile::TempDir::new().unwrap();
+    std::env::set_var("XDG_CACHE_HOME", cache_dir.path());
+    let registry_file = cache_dir.path().join("registry.json");
+    std::fs::write(&registry_file, synthetic_registry()).unwrap();
+    let registry = runtime
+        .block_on(DependencyRegistry::new(
+            &DependencyRegistryOptions::new()
+                .offline(true)
+                .registry(registry_file.into()),
+        ))
+        .unwrap();
+
+    let names: Vec<_> = (0..CRATES).map(|i| format!("crate-{i}-sys")).collect();
+    let versions: Vec<_> = (0..CRATES)
+        .map(|i| semver::Version::new(i as u64 % 3, 0, 0))
+        .collect();
+
+    c.bench_function("resolve 500 crates", |b| {
+        b.iter_batched(
+            || names.iter().map(String::as_str).zip(&versions),
+            |crates| runtime.block_on(registry.resolve(crates)),
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+criterion_group!(benches, resolve);
+criterion_main!(benches);
@@ -1,17 +1,1124 @@
-use crate::dependency_registry::rust::RustDependencyData;
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::path::{Path, PathBuf};
 
-#[derive(serde::Deserialize)]
+use eyre::{eyre, WrapErr};
+use itertools::Itertools;
+use tokio::process::Command;
+
+use crate::crates_io::IndexVersion;
+
+/// Why the Rust project couldn't be read
+#[derive(Debug, thiserror::Error)]
+pub enum ProjectError {
+    #[error("Could not find a `Cargo.toml` in `{0}` or its parent directories")]
+    NoManifest(PathBuf),
+    #[error("Unable to parse `{0}`")]
+    InvalidManifest(PathBuf, #[source] toml_edit::TomlError),
+    #[error("Invalid `package.metadata.riff` of `{0}` in `{1}`")]
+    InvalidMetadata(String, PathBuf, #[source] toml_edit::de::Error),
+}
+
+impl ProjectError {
+    /// The code riff exits with, from `sysexits.h`: `EX_NOINPUT` when there is no `Cargo.toml`,
+    /// and `EX_DATAERR` when it can't be parsed
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            ProjectError::NoManifest(_) => 66,
+            ProjectError::InvalidManifest(..) | ProjectError::InvalidMetadata(..) => 65,
+        }
+    }
+}
+
+/// Find the project `dir` is part of, like Cargo does: the workspace root of the nearest
+/// `Cargo.toml` in `dir` or its parents
+///
+/// The search for a `Cargo.toml` doesn't cross into another filesystem.
+pub async fn find_project_dir(dir: &Path) -> color_eyre::Result<PathBuf> {
+    let dir = tokio::fs::canonicalize(dir)
+        .await
+        .wrap_err_with(|| format!("Unable to find the project directory `{}`", dir.display()))?;
+    let device = device(&dir).await;
+    let mut manifest_dir = None;
+    for ancestor in dir.ancestors() {
+        if device.is_some() && device != self::device(ancestor).await {
+            tracing::trace!(dir = %ancestor.display(), "Not searching for `Cargo.toml` in another filesystem");
+            break;
+        }
+        if tokio::fs::metadata(ancestor.join("Cargo.toml"))
+            .await
+            .map(|metadata| metadata.is_file())
+            .unwrap_or(false)
+        {
+            manifest_dir = Some(ancestor);
+            break;
+        }
+    }
+    let manifest_dir = manifest_dir.ok_or_else(|| ProjectError::NoManifest(dir.to_path_buf()))?;
+    let manifest_path = manifest_dir.join("Cargo.toml");
+    let manifest = tokio::fs::read_to_string(&manifest_path)
+        .await
+        .wrap_err_with(|| format!("Unable to read `{}`", manifest_path.display()))?;
+    if let Err(err) = manifest.parse::<toml_edit::DocumentMut>() {
+        return Err(ProjectError::InvalidManifest(manifest_path, err).into());
+    }
+
+    let mut locate_project_command = Command::new("cargo");
+    locate_project_command
+        .args(["locate-project", "--workspace", "--message-format", "plain"])
+        .arg("--manifest-path")
+        .arg(&manifest_path);
+    tracing::trace!(command = ?locate_project_command.as_std(), "Running");
+    match locate_project_command.output().await {
+        Ok(output) if output.status.success() => {
+            let workspace_manifest = PathBuf::from(String::from_utf8_lossy(&output.stdout).trim());
+            if let Some(workspace_dir) = workspace_manifest.parent() {
+                return Ok(workspace_dir.to_path_buf());
+            }
+        }
+        // `cargo metadata` reports the problem later, with more context
+        Ok(output) => tracing::debug!(
+            stderr = %String::from_utf8_lossy(&output.stderr).trim(),
+            "Could not find the workspace root, using the package directory"
+        ),
+        Err(err) => {
+            tracing::debug!(%err, "Could not run `cargo locate-project`, using the package directory")
+        }
+    }
+    Ok(manifest_dir.to_path_buf())
+}
+
+/// The ID of the filesystem `path` is on, if there is such a thing on this platform
+async fn device(path: &Path) -> Option<u64> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        tokio::fs::metadata(path)
+            .await
+            .ok()
+            .map(|metadata| metadata.dev())
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = path;
+        None
+    }
+}
+
+#[derive(Clone, serde::Deserialize)]
 pub struct CargoMetadata {
     pub packages: Vec<CargoMetadataPackage>,
+    pub workspace_members: Vec<String>,
+    pub resolve: Option<CargoMetadataResolve>,
+}
+
+impl CargoMetadata {
+    /// Only keep the packages needed to build the workspace, or just the `package` member of it
+    ///
+    /// The `[dev-dependencies]` of the workspace members are only kept if `dev` is set, the
+    /// `[dependencies]` and `[build-dependencies]` always are.
+    pub fn restrict(&mut self, package: Option<&str>, dev: bool) -> color_eyre::Result<()> {
+        let members = self
+            .packages
+            .iter()
+            .filter(|package| self.workspace_members.contains(&package.id))
+            .collect::<Vec<_>>();
+        let roots = match package {
+            None => self.workspace_members.clone(),
+            Some(name) => match members.iter().find(|package| package.name == name) {
+                Some(package) => vec![package.id.clone()],
+                None => {
+                    return Err(eyre!(
+                        "`{name}` is not a member of the workspace, which has the members: {}",
+                        members
+                            .iter()
+                            .map(|package| &package.name)
+                            .sorted()
+                            .join(", ")
+                    ))
+                }
+            },
+        };
+
+        let dependencies = self
+            .resolve
+            .iter()
+            .flat_map(|resolve| &resolve.nodes)
+            .map(|node| (node.id.as_str(), node.deps.as_slice()))
+            .collect::<HashMap<_, _>>();
+        let mut reachable = HashSet::new();
+        let mut queue = roots;
+        while let Some(id) = queue.pop() {
+            // Cargo only uses the dev-dependencies of workspace members
+            let follow_dev = dev && self.workspace_members.contains(&id);
+            if let Some(deps) = dependencies.get(id.as_str()) {
+                queue.extend(
+                    deps.iter()
+                        .filter(|dep| follow_dev || !dep.is_dev_only())
+                        .filter(|dep| !reachable.contains(&dep.pkg))
+                        .map(|dep| dep.pkg.clone()),
+                );
+            }
+            reachable.insert(id);
+        }
+
+        self.packages
+            .retain(|package| reachable.contains(&package.id));
+        Ok(())
+    }
+
+    /// Read what `cargo metadata` would output from the `Cargo.lock` and manifests of the project
+    /// in `project_dir`, for when `cargo` can't be run
+    ///
+    /// `Cargo.lock` doesn't record which features are enabled, or which dependencies are only
+    /// `[dev-dependencies]`. The optional dependencies of workspace members are only included if
+    /// `features` enable them, but every other locked package is included and no features are
+    /// reported as enabled. Workspace members are found in `workspace.members`, where only
+    /// `dir/*` globs are expanded.
+    pub async fn from_lockfile(
+        project_dir: &Path,
+        features: &CargoFeatures,
+    ) -> color_eyre::Result<Self> {
+        let lockfile_path = project_dir.join("Cargo.lock");
+        let lockfile = tokio::fs::read_to_string(&lockfile_path)
+            .await
+            .wrap_err_with(|| {
+                format!(
+                    "Unable to read `{}`, run `cargo generate-lockfile` to create it",
+                    lockfile_path.display()
+                )
+            })?;
+        let lockfile: Lockfile = toml_edit::de::from_str(&lockfile)
+            .wrap_err_with(|| format!("Unable to parse `{}`", lockfile_path.display()))?;
+
+        let mut metadata_by_name = HashMap::new();
+        let mut disabled_by_name = HashMap::new();
+        for manifest_dir in manifest_dirs(project_dir).await? {
+            let manifest_path = manifest_dir.join("Cargo.toml");
+            let manifest = tokio::fs::read_to_string(&manifest_path)
+                .await
+                .wrap_err_with(|| format!("Unable to read `{}`", manifest_path.display()))?;
+            let manifest: Manifest = toml_edit::de::from_str(&manifest)
+                .wrap_err_with(|| format!("Unable to parse `{}`", manifest_path.display()))?;
+            let disabled = manifest.disabled_optional_dependencies(features);
+            if let Some(package) = manifest.package {
+                disabled_by_name.insert(package.name.clone(), disabled);
+                metadata_by_name.insert(package.name, (package.metadata, manifest_path));
+            }
+        }
+        let no_disabled = HashSet::new();
+
+        let id = |name: &str, version: &str| format!("{name} {version}");
+        let nodes = lockfile
+            .package
+            .iter()
+            .map(|package| CargoMetadataNode {
+                id: id(&package.name, &package.version.to_string()),
+                deps: package
+                    .dependencies
+                    .iter()
+                    .filter_map(|dependency| {
+                        // Either `name`, if only one version is locked, or `name version (source)`
+                        let mut parts = dependency.split_whitespace();
+                        let name = parts.next()?;
+                        let disabled = match package.source {
+                            None => disabled_by_name.get(&package.name).unwrap_or(&no_disabled),
+                            Some(_) => &no_disabled,
+                        };
+                        if disabled.contains(name) {
+                            return None;
+                        }
+                        let pkg = match parts.next() {
+                            Some(version) => id(name, version),
+                            None => lockfile
+                                .package
+                                .iter()
+                                .find(|package| package.name == name)
+                                .map(|package| id(name, &package.version.to_string()))?,
+                        };
+                        Some(CargoMetadataNodeDep {
+                            pkg,
+                            dep_kinds: Vec::new(),
+                        })
+                    })
+                    .collect(),
+                features: Vec::new(),
+            })
+            .collect();
+        let workspace_members = lockfile
+            .package
+            .iter()
+            .filter(|package| package.source.is_none())
+            .map(|package| id(&package.name, &package.version.to_string()))
+            .collect();
+        let packages = lockfile
+            .package
+            .into_iter()
+            .map(|package| {
+                let (metadata, manifest_path) = match package.source {
+                    None => match metadata_by_name.remove(&package.name) {
+                        Some((metadata, manifest_path)) => (metadata, Some(manifest_path)),
+                        None => (None, None),
+                    },
+                    Some(_) => (None, None),
+                };
+                CargoMetadataPackage {
+                    id: id(&package.name, &package.version.to_string()),
+                    name: package.name,
+                    version: package.version,
+                    manifest_path,
+                    metadata,
+                }
+            })
+            .collect();
+
+        Ok(CargoMetadata {
+            packages,
+            workspace_members,
+            resolve: Some(CargoMetadataResolve { nodes }),
+        })
+    }
+
+    /// What `cargo metadata` would output for the crate `krate` from the crates.io index, as if it
+    /// were the only workspace member, along with the crates missing from `index` which are needed
+    /// to resolve it
+    ///
+    /// Every `[dependencies]` and `[build-dependencies]` entry for `triple` enabled by `features`
+    /// is followed, each at the newest version in `index` matching its requirement, with the
+    /// features every dependent asks of it. Fetch the missing crates and call this again until none
+    /// are missing.
+    pub(crate) fn from_index(
+        krate: &IndexVersion,
+        index: &HashMap<String, Vec<IndexVersion>>,
+        features: &CargoFeatures,
+        triple: &str,
+    ) -> (Self, BTreeSet<String>) {
+        let id = |version: &IndexVersion| format!("{} {}", version.name, version.vers);
+        let krate_id = id(krate);
+        // The version and requested features of every package, by ID
+        let mut requested = BTreeMap::from([(krate_id.clone(), (krate, features.clone()))]);
+        let mut edges: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+        let mut missing = BTreeSet::new();
+        let mut queue = vec![krate_id.clone()];
+        while let Some(package_id) = queue.pop() {
+            let (version, package_features) = requested[&package_id].clone();
+            let (enabled_features, enabled_optional) =
+                follow_features(&version.features(), &optional(version), &package_features);
+            for dependency in &version.deps {
+                if dependency.is_dev()
+                    || (dependency.optional && !enabled_optional.contains(&dependency.name))
+                    || !dependency.is_for(triple)
+                {
+                    continue;
+                }
+                let dependency_version = match index.get(dependency.crate_name()) {
+                    Some(versions) => {
+                        match crate::crates_io::newest_matching(versions, &dependency.req) {
+                            Some(dependency_version) => dependency_version,
+                            None => {
+                                tracing::debug!(name = dependency.crate_name(), req = %dependency.req, "No version in the crates.io index matches");
+                                continue;
+                            }
+                        }
+                    }
+                    None => {
+                        missing.insert(dependency.crate_name().to_string());
+                        continue;
+                    }
+                };
+                let dependency_id = id(dependency_version);
+                edges
+                    .entry(package_id.clone())
+                    .or_default()
+                    .insert(dependency_id.clone());
+
+                // The features it enables of the dependency, Eg with `name/feature`
+                let mut dependency_features = dependency.features.clone();
+                dependency_features.extend(enabled_features.iter().filter_map(|feature| {
+                    let (key, feature) = feature.split_once('/')?;
+                    (key.trim_end_matches('?') == dependency.name).then(|| feature.to_string())
+                }));
+                // Like Cargo, a package gets the union of what each of its dependents ask for
+                match requested.entry(dependency_id.clone()) {
+                    Entry::Vacant(entry) => {
+                        entry.insert((
+                            dependency_version,
+                            CargoFeatures {
+                                features: dependency_features,
+                                all_features: false,
+                                no_default_features: !dependency.default_features,
+                            },
+                        ));
+                        queue.push(dependency_id);
+                    }
+                    Entry::Occupied(mut entry) => {
+                        let (_, existing) = entry.get_mut();
+                        let mut changed = false;
+                        if existing.no_default_features && dependency.default_features {
+                            existing.no_default_features = false;
+                            changed = true;
+                        }
+                        for feature in dependency_features {
+                            if !existing.features.contains(&feature) {
+                                existing.features.push(feature);
+                                changed = true;
+                            }
+                        }
+                        if changed {
+                            queue.push(dependency_id);
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut packages = Vec::new();
+        let mut nodes = Vec::new();
+        for (package_id, (version, package_features)) in requested {
+            let (enabled_features, _) =
+                follow_features(&version.features(), &optional(version), &package_features);
+            let package = CargoMetadataPackage {
+                id: package_id.clone(),
+                name: version.name.clone(),
+                version: version.vers.clone(),
+                manifest_path: None,
+                metadata: None,
+            };
+            // The workspace member comes first, like from `cargo metadata`
+            if package_id == krate_id {
+                packages.insert(0, package);
+            } else {
+                packages.push(package);
+            }
+            nodes.push(CargoMetadataNode {
+                deps: edges
+                    .remove(&package_id)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|pkg| CargoMetadataNodeDep {
+                        pkg,
+                        dep_kinds: Vec::new(),
+                    })
+                    .collect(),
+                id: package_id,
+                features: enabled_features
+                    .into_iter()
+                    .filter(|feature| !feature.contains('/') && !feature.starts_with("dep:"))
+                    .sorted()
+                    .collect(),
+            });
+        }
+
+        let metadata = CargoMetadata {
+            packages,
+            workspace_members: vec![krate_id],
+            resolve: Some(CargoMetadataResolve { nodes }),
+        };
+        (metadata, missing)
+    }
+
+    /// The enabled features of every resolved package, by package ID
+    pub fn enabled_features(&self) -> HashMap<String, HashSet<String>> {
+        self.resolve
+            .iter()
+            .flat_map(|resolve| &resolve.nodes)
+            .map(|node| (node.id.clone(), node.features.iter().cloned().collect()))
+            .collect()
+    }
+}
+
+/// The directories of the manifests in the project in `project_dir`: its own and those of its
+/// workspace members
+async fn manifest_dirs(project_dir: &Path) -> color_eyre::Result<Vec<PathBuf>> {
+    let manifest_path = project_dir.join("Cargo.toml");
+    let manifest = tokio::fs::read_to_string(&manifest_path)
+        .await
+        .wrap_err_with(|| format!("Unable to read `{}`", manifest_path.display()))?;
+    let manifest: Manifest = toml_edit::de::from_str(&manifest)
+        .wrap_err_with(|| format!("Unable to parse `{}`", manifest_path.display()))?;
+
+    let mut manifest_dirs = vec![project_dir.to_path_buf()];
+    for member in manifest
+        .workspace
+        .map(|workspace| workspace.members)
+        .unwrap_or_default()
+    {
+        match member.strip_suffix("/*") {
+            Some(parent) => {
+                let mut entries = match tokio::fs::read_dir(project_dir.join(parent)).await {
+                    Ok(entries) => entries,
+                    Err(err) => {
+                        tracing::debug!(%err, member, "Could not list workspace members");
+                        continue;
+                    }
+                };
+                while let Some(entry) = entries.next_entry().await? {
+                    if entry.path().join("Cargo.toml").is_file() {
+                        manifest_dirs.push(entry.path());
+                    }
+                }
+            }
+            None if member.contains(['*', '?', '[']) => {
+                tracing::debug!(
+                    member,
+                    "Not expanding a workspace member glob, only `dir/*` is supported"
+                );
+            }
+            None => manifest_dirs.push(project_dir.join(member)),
+        }
+    }
+    Ok(manifest_dirs)
+}
+
+/// The parts of `Cargo.lock` [`CargoMetadata::from_lockfile`] reads
+#[derive(serde::Deserialize)]
+struct Lockfile {
+    #[serde(default)]
+    package: Vec<LockedPackage>,
+}
+
+#[derive(serde::Deserialize)]
+struct LockedPackage {
+    name: String,
+    version: semver::Version,
+    /// `None` for packages in the workspace, or other path dependencies
+    source: Option<String>,
+    #[serde(default)]
+    dependencies: Vec<String>,
+}
+
+/// The parts of `Cargo.toml` [`CargoMetadata::from_lockfile`] reads
+#[derive(serde::Deserialize)]
+struct Manifest {
+    package: Option<ManifestPackage>,
+    workspace: Option<ManifestWorkspace>,
+    #[serde(default)]
+    features: HashMap<String, Vec<String>>,
+    #[serde(default)]
+    dependencies: HashMap<String, ManifestDependency>,
+    #[serde(default, rename = "build-dependencies")]
+    build_dependencies: HashMap<String, ManifestDependency>,
+    #[serde(default)]
+    target: HashMap<String, ManifestTarget>,
+}
+
+impl Manifest {
+    /// The package names of the optional dependencies `features` don't enable
+    fn disabled_optional_dependencies(&self, features: &CargoFeatures) -> HashSet<String> {
+        let dependencies =
+            self.dependencies
+                .iter()
+                .chain(&self.build_dependencies)
+                .chain(self.target.values().flat_map(|target| {
+                    target.dependencies.iter().chain(&target.build_dependencies)
+                }))
+                .map(|(key, dependency)| match dependency {
+                    ManifestDependency::Detailed { optional, package } => {
+                        (key.as_str(), package.as_deref().unwrap_or(key), *optional)
+                    }
+                    ManifestDependency::Version(_) => (key.as_str(), key.as_str(), false),
+                })
+                .collect::<Vec<_>>();
+        let optional = dependencies
+            .iter()
+            .filter(|(_, _, optional)| *optional)
+            .map(|(key, package, _)| (*key, *package))
+            .collect::<HashMap<_, _>>();
+        // A package can also be a required dependency, Eg a build dependency
+        let required = dependencies
+            .iter()
+            .filter(|(_, _, optional)| !optional)
+            .map(|(_, package, _)| *package)
+            .collect::<HashSet<_>>();
+        let keys = optional.keys().copied().collect();
+        let (_, enabled) = follow_features(&self.features, &keys, features);
+        optional
+            .into_iter()
+            .filter(|(key, package)| !enabled.contains(*key) && !required.contains(package))
+            .map(|(_, package)| package.to_string())
+            .collect()
+    }
+}
+
+/// The optional dependencies of `version`, by their key in `Cargo.toml`
+fn optional(version: &IndexVersion) -> HashSet<&str> {
+    version
+        .deps
+        .iter()
+        .filter(|dependency| dependency.optional)
+        .map(|dependency| dependency.name.as_str())
+        .collect()
+}
+
+/// Follow `features` through the `declared` features of a package, returning the enabled
+/// features and the keys of the `optional` dependencies they enable
+fn follow_features(
+    declared: &HashMap<String, Vec<String>>,
+    optional: &HashSet<&str>,
+    features: &CargoFeatures,
+) -> (HashSet<String>, HashSet<String>) {
+    if features.all_features {
+        let enabled = declared
+            .keys()
+            .map(String::as_str)
+            .chain(optional.iter().copied())
+            .map(String::from)
+            .collect();
+        return (
+            enabled,
+            optional.iter().copied().map(String::from).collect(),
+        );
+    }
+
+    let mut queue = features
+        .features
+        .iter()
+        .map(String::as_str)
+        .collect::<Vec<_>>();
+    if !features.no_default_features {
+        queue.push("default");
+    }
+    let mut seen = HashSet::new();
+    let mut enabled = HashSet::new();
+    while let Some(feature) = queue.pop() {
+        if !seen.insert(feature) {
+            continue;
+        }
+        // `dep:name` and `name/feature` enable `name`, `name?/feature` doesn't
+        let dependency = feature.strip_prefix("dep:").unwrap_or(feature);
+        let dependency = match dependency.split_once('/') {
+            Some((dependency, _)) if !dependency.ends_with('?') => dependency,
+            Some(_) => continue,
+            None => dependency,
+        };
+        if optional.contains(dependency) {
+            enabled.insert(dependency.to_string());
+        }
+        if !feature.starts_with("dep:") {
+            if let Some(implied) = declared.get(dependency) {
+                queue.extend(implied.iter().map(String::as_str));
+            }
+        }
+    }
+    let seen = seen
+        .into_iter()
+        .filter(|feature| declared.contains_key(*feature) || optional.contains(feature))
+        .map(String::from)
+        .collect();
+    (seen, enabled)
+}
+
+#[derive(serde::Deserialize)]
+struct ManifestTarget {
+    #[serde(default)]
+    dependencies: HashMap<String, ManifestDependency>,
+    #[serde(default, rename = "build-dependencies")]
+    build_dependencies: HashMap<String, ManifestDependency>,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum ManifestDependency {
+    Detailed {
+        #[serde(default)]
+        optional: bool,
+        /// The name of the package, if the dependency is renamed
+        package: Option<String>,
+    },
+    Version(serde::de::IgnoredAny),
+}
+
+#[derive(serde::Deserialize)]
+struct ManifestPackage {
+    name: String,
+    metadata: Option<RiffMetadata>,
 }
 
 #[derive(serde::Deserialize)]
+struct ManifestWorkspace {
+    #[serde(default)]
+    members: Vec<String>,
+}
+
+#[derive(Clone, serde::Deserialize)]
 pub struct CargoMetadataPackage {
+    pub id: String,
     pub name: String,
+    /// The version locked in `Cargo.lock`
+    pub version: semver::Version,
+    /// The package's `Cargo.toml`, not known for the locked packages of `from_lockfile`
+    #[serde(default)]
+    pub manifest_path: Option<PathBuf>,
     pub metadata: Option<RiffMetadata>,
 }
 
-#[derive(serde::Deserialize)]
+#[derive(Clone, serde::Deserialize)]
+pub struct CargoMetadataResolve {
+    pub nodes: Vec<CargoMetadataNode>,
+}
+
+#[derive(Clone, serde::Deserialize)]
+pub struct CargoMetadataNode {
+    pub id: String,
+    pub deps: Vec<CargoMetadataNodeDep>,
+    /// The features of the package enabled by the selected [`CargoFeatures`]
+    #[serde(default)]
+    pub features: Vec<String>,
+}
+
+#[derive(Clone, serde::Deserialize)]
+pub struct CargoMetadataNodeDep {
+    pub pkg: String,
+    #[serde(default)]
+    pub dep_kinds: Vec<CargoMetadataDepKind>,
+}
+
+impl CargoMetadataNodeDep {
+    /// If this is only a `[dev-dependencies]` entry, and not also a normal or build dependency
+    pub fn is_dev_only(&self) -> bool {
+        !self.dep_kinds.is_empty()
+            && self
+                .dep_kinds
+                .iter()
+                .all(|dep_kind| dep_kind.kind.as_deref() == Some("dev"))
+    }
+}
+
+#[derive(Clone, serde::Deserialize)]
+pub struct CargoMetadataDepKind {
+    /// `None` for `[dependencies]`, otherwise `dev` or `build`
+    pub kind: Option<String>,
+}
+
+#[derive(Clone, serde::Deserialize)]
 pub struct RiffMetadata {
-    pub riff: Option<RustDependencyData>,
+    /// Kept as JSON so an invalid `package.metadata.riff` can be reported along with its package
+    pub riff: Option<serde_json::Value>,
+}
+
+/// The Cargo features to resolve dependencies with, mirroring Cargo's own flags
+///
+/// `cargo metadata` only reports packages which are activated by the selected features, so
+/// optional dependencies (and the external dependencies they bring) follow the selection.
+#[derive(Debug, Clone, Default, clap::Args)]
+pub struct CargoFeatures {
+    /// Comma separated list of features to activate
+    #[clap(long, value_delimiter = ',')]
+    pub features: Vec<String>,
+    /// Activate all available features
+    #[clap(long)]
+    pub all_features: bool,
+    /// Do not activate the `default` feature
+    #[clap(long)]
+    pub no_default_features: bool,
+}
+
+impl CargoFeatures {
+    pub fn apply(&self, command: &mut Command) {
+        if !self.features.is_empty() {
+            command.arg("--features").arg(self.features.join(","));
+        }
+        if self.all_features {
+            command.arg("--all-features");
+        }
+        if self.no_default_features {
+            command.arg("--no-default-features");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeSet, HashMap, HashSet};
+
+    use itertools::Itertools;
+    use tempfile::TempDir;
+    use tokio::fs::{create_dir_all, write};
+
+    use super::{find_project_dir, CargoFeatures, CargoMetadata, Manifest, ProjectError};
+    use crate::crates_io::IndexVersion;
+
+    #[tokio::test]
+    async fn cargo_metadata_from_lockfile() -> eyre::Result<()> {
+        let temp_dir = TempDir::new()?;
+        let root = temp_dir.path();
+        write(
+            root.join("Cargo.toml"),
+            "[workspace]\nmembers = [\"crates/*\"]\n",
+        )
+        .await?;
+        create_dir_all(root.join("crates/app")).await?;
+        write(
+            root.join("crates/app/Cargo.toml"),
+            r#"
+[package]
+name = "app"
+version.workspace = true
+
+[package.metadata.riff]
+build-inputs = [ "hello" ]
+"#,
+        )
+        .await?;
+        write(
+            root.join("Cargo.lock"),
+            r#"
+version = 3
+
+[[package]]
+name = "app"
+version = "0.1.0"
+dependencies = [
+ "openssl-sys",
+ "bitflags 2.4.0",
+]
+
+[[package]]
+name = "bitflags"
+version = "1.3.2"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+
+[[package]]
+name = "bitflags"
+version = "2.4.0"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+
+[[package]]
+name = "openssl-sys"
+version = "0.9.90"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+"#,
+        )
+        .await?;
+
+        let mut metadata = CargoMetadata::from_lockfile(root, &Default::default()).await?;
+        assert_eq!(metadata.workspace_members, vec!["app 0.1.0".to_string()]);
+        let app = &metadata.packages[0];
+        assert_eq!(app.name, "app");
+        assert!(app
+            .metadata
+            .as_ref()
+            .and_then(|metadata| metadata.riff.as_ref())
+            .is_some());
+
+        metadata.restrict(None, false)?;
+        let mut packages = metadata
+            .packages
+            .iter()
+            .map(|package| package.id.as_str())
+            .collect::<Vec<_>>();
+        packages.sort();
+        assert_eq!(
+            packages,
+            ["app 0.1.0", "bitflags 2.4.0", "openssl-sys 0.9.90"]
+        );
+
+        assert!(
+            CargoMetadata::from_lockfile(&root.join("crates/app"), &Default::default())
+                .await
+                .is_err()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn manifest_disabled_optional_dependencies() -> eyre::Result<()> {
+        let manifest: Manifest = toml_edit::de::from_str(
+            r#"
+[package]
+name = "app"
+
+[features]
+default = ["tls"]
+tls = ["dep:openssl"]
+compress = ["zstd/zstdmt", "serde?/derive"]
+everything = ["tls", "compress", "gpu"]
+
+[dependencies]
+openssl = { version = "0.10", optional = true }
+zstd = { version = "0.13", optional = true }
+serde = { version = "1", optional = true }
+gpu = { package = "wgpu", version = "0.19", optional = true }
+log = "0.4"
+
+[target.'cfg(unix)'.dependencies]
+libc = { version = "0.2", optional = true }
+"#,
+        )?;
+        let disabled = |features: CargoFeatures| {
+            let mut disabled = manifest
+                .disabled_optional_dependencies(&features)
+                .into_iter()
+                .collect::<Vec<_>>();
+            disabled.sort();
+            disabled
+        };
+
+        assert_eq!(
+            disabled(Default::default()),
+            ["libc", "serde", "wgpu", "zstd"]
+        );
+        assert_eq!(
+            disabled(CargoFeatures {
+                no_default_features: true,
+                ..Default::default()
+            }),
+            ["libc", "openssl", "serde", "wgpu", "zstd"]
+        );
+        assert_eq!(
+            disabled(CargoFeatures {
+                features: vec!["everything".into()],
+                ..Default::default()
+            }),
+            ["libc", "serde"]
+        );
+        assert_eq!(
+            disabled(CargoFeatures {
+                features: vec!["libc".into()],
+                no_default_features: true,
+                ..Default::default()
+            }),
+            ["openssl", "serde", "wgpu", "zstd"]
+        );
+        assert!(disabled(CargoFeatures {
+            all_features: true,
+            ..Default::default()
+        })
+        .is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn find_project_dir_walks_up_to_workspace() -> eyre::Result<()> {
+        let temp_dir = TempDir::new()?;
+        let root = tokio::fs::canonicalize(temp_dir.path()).await?;
+        write(
+            root.join("Cargo.toml"),
+            "[workspace]\nmembers = [\"member\"]\n",
+        )
+        .await?;
+        create_dir_all(root.join("member/src/bin")).await?;
+        write(
+            root.join("member/Cargo.toml"),
+            "[package]\nname = \"member\"\nversion = \"0.1.0\"\n",
+        )
+        .await?;
+        write(root.join("member/src/lib.rs"), "").await?;
+
+        assert_eq!(find_project_dir(&root.join("member/src/bin")).await?, root);
+        assert_eq!(find_project_dir(&root.join("member")).await?, root);
+        assert_eq!(find_project_dir(&root).await?, root);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn find_project_dir_standalone_package() -> eyre::Result<()> {
+        let temp_dir = TempDir::new()?;
+        let root = tokio::fs::canonicalize(temp_dir.path()).await?;
+        create_dir_all(root.join("package/src")).await?;
+        write(
+            root.join("package/Cargo.toml"),
+            "[package]\nname = \"package\"\nversion = \"0.1.0\"\n",
+        )
+        .await?;
+        write(root.join("package/src/lib.rs"), "").await?;
+
+        assert_eq!(
+            find_project_dir(&root.join("package/src")).await?,
+            root.join("package")
+        );
+        assert!(matches!(
+            find_project_dir(&root).await.unwrap_err().downcast_ref(),
+            Some(ProjectError::NoManifest(dir)) if dir == &root
+        ));
+
+        write(
+            root.join("package/Cargo.toml"),
+            "[package\nname = \"package\"\n",
+        )
+        .await?;
+        assert!(matches!(
+            find_project_dir(&root.join("package")).await.unwrap_err().downcast_ref(),
+            Some(ProjectError::InvalidManifest(manifest, _)) if manifest == &root.join("package/Cargo.toml")
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn cargo_metadata_restrict() -> eyre::Result<()> {
+        let metadata = || -> eyre::Result<CargoMetadata> {
+            Ok(serde_json::from_str(
+                r#"{
+                "packages": [
+                    { "id": "a", "name": "a", "version": "0.1.0", "metadata": null },
+                    { "id": "b", "name": "b", "version": "0.1.0", "metadata": null },
+                    { "id": "a-sys", "name": "a-sys", "version": "0.1.0", "metadata": null },
+                    { "id": "shared", "name": "shared", "version": "0.1.0", "metadata": null },
+                    { "id": "test-sys", "name": "test-sys", "version": "0.1.0", "metadata": null },
+                    { "id": "cc", "name": "cc", "version": "0.1.0", "metadata": null }
+                ],
+                "workspace_members": [ "a", "b" ],
+                "resolve": {
+                    "nodes": [
+                        { "id": "a", "deps": [
+                            { "pkg": "a-sys", "dep_kinds": [ { "kind": null } ] },
+                            { "pkg": "shared", "dep_kinds": [ { "kind": null } ] }
+                        ] },
+                        { "id": "b", "deps": [
+                            { "pkg": "shared", "dep_kinds": [ { "kind": null } ] },
+                            { "pkg": "test-sys", "dep_kinds": [ { "kind": "dev" } ] }
+                        ] },
+                        { "id": "a-sys", "deps": [
+                            { "pkg": "cc", "dep_kinds": [ { "kind": "build" } ] },
+                            { "pkg": "shared", "dep_kinds": [ { "kind": "dev" } ] }
+                        ] },
+                        { "id": "shared", "deps": [] },
+                        { "id": "test-sys", "deps": [] },
+                        { "id": "cc", "deps": [] }
+                    ]
+                }
+            }"#,
+            )?)
+        };
+        let names = |metadata: &CargoMetadata| {
+            metadata
+                .packages
+                .iter()
+                .map(|package| package.name.clone())
+                .collect::<Vec<_>>()
+        };
+
+        let mut all = metadata()?;
+        all.restrict(None, false)?;
+        assert_eq!(names(&all), ["a", "b", "a-sys", "shared", "cc"]);
+
+        let mut b = metadata()?;
+        b.restrict(Some("b"), false)?;
+        assert_eq!(names(&b), ["b", "shared"]);
+
+        let mut b = metadata()?;
+        b.restrict(Some("b"), true)?;
+        assert_eq!(names(&b), ["b", "shared", "test-sys"]);
+
+        let err = metadata()?.restrict(Some("a-sys"), true).unwrap_err();
+        assert!(err.to_string().contains("members: a, b"), "{err}");
+        Ok(())
+    }
+
+    #[test]
+    fn cargo_metadata_from_index() {
+        let krate = &crate::crates_io::parse_index(&
+            r#"{"name":"app","vers":"1.0.0","cksum":"","features":{"default":["tls"],"tls":["dep:openssl","zstd?/std"]},"deps":[
+                {"name":"openssl","req":"^0.10","features":[],"optional":true,"default_features":true,"target":null,"kind":"normal"},
+                {"name":"zstd","req":"^0.13","features":[],"optional":true,"default_features":true,"target":null,"kind":"normal"},
+                {"name":"cmake","req":"^0.1","features":[],"optional":false,"default_features":true,"target":null,"kind":"build"},
+                {"name":"lz","package":"lz4-sys","req":"^1","features":["static"],"optional":false,"default_features":false,"target":null,"kind":"normal"},
+                {"name":"criterion","req":"^0.5","features":[],"optional":false,"default_features":true,"target":null,"kind":"dev"}
+            ]}"#
+            .replace('\n', ""),
+        )[0];
+        let index = |name: &str, versions: &[&str], deps: &str| {
+            let content = versions
+                .iter()
+                .map(|version| format!(r#"{{"name":"{name}","vers":"{version}","deps":[{deps}],"cksum":"","features":{{"static":[],"vendored":["openssl-src"]}}}}"#))
+                .join("\n");
+            (name.to_string(), crate::crates_io::parse_index(&content))
+        };
+        let index = HashMap::from([
+            index(
+                "openssl",
+                &["0.10.1", "0.10.60", "0.11.0"],
+                r#"{"name":"openssl-sys","req":"^0.9","features":["vendored"],"optional":false,"default_features":true,"target":null,"kind":"normal"}"#,
+            ),
+            index("zstd", &["0.13.0"], ""),
+            index("cmake", &["0.1.50"], ""),
+            index("lz4-sys", &["1.9.4"], ""),
+            index("criterion", &["0.5.1"], ""),
+            index(
+                "openssl-sys",
+                &["0.9.90"],
+                r#"{"name":"openssl-src","req":"^300","features":[],"optional":true,"default_features":true,"target":null,"kind":"build"},{"name":"vcpkg","req":"^0.2","features":[],"optional":false,"default_features":true,"target":"cfg(target_env = \"msvc\")","kind":"build"}"#,
+            ),
+        ]);
+        let linux = "x86_64-unknown-linux-gnu";
+
+        // Each call follows the tree until it needs a crate missing from the index
+        let (_, missing) =
+            CargoMetadata::from_index(krate, &HashMap::new(), &Default::default(), linux);
+        assert_eq!(
+            missing,
+            BTreeSet::from([
+                "cmake".to_string(),
+                "lz4-sys".to_string(),
+                "openssl".to_string()
+            ])
+        );
+        let (_, missing) = CargoMetadata::from_index(krate, &index, &Default::default(), linux);
+        assert_eq!(missing, BTreeSet::from(["openssl-src".to_string()]));
+
+        let mut index = index;
+        index.extend([index_entry("openssl-src", "300.1.0")]);
+        let (metadata, missing) =
+            CargoMetadata::from_index(krate, &index, &Default::default(), linux);
+        assert!(missing.is_empty());
+        assert_eq!(metadata.workspace_members, ["app 1.0.0"]);
+        assert_eq!(metadata.packages[0].id, "app 1.0.0");
+        let packages = metadata
+            .packages
+            .iter()
+            .map(|package| package.id.as_str())
+            .sorted()
+            .collect::<Vec<_>>();
+        assert_eq!(
+            packages,
+            [
+                "app 1.0.0",
+                "cmake 0.1.50",
+                "lz4-sys 1.9.4",
+                "openssl 0.10.60",
+                "openssl-src 300.1.0",
+                "openssl-sys 0.9.90"
+            ]
+        );
+        let features = metadata.enabled_features();
+        assert_eq!(
+            features["app 1.0.0"],
+            HashSet::from(["default".to_string(), "tls".to_string()])
+        );
+        assert_eq!(
+            features["lz4-sys 1.9.4"],
+            HashSet::from(["static".to_string()])
+        );
+        assert_eq!(
+            features["openssl-sys 0.9.90"],
+            HashSet::from(["vendored".to_string(), "openssl-src".to_string()])
+        );
+
+        let (metadata, _) = CargoMetadata::from_index(
+            krate,
+            &index,
+            &CargoFeatures {
+                no_default_features: true,
+                ..Default::default()
+            },
+            linux,
+        );
+        assert!(!metadata
+            .packages
+            .iter()
+            .any(|package| package.name.starts_with("openssl")));
+
+        // `vcpkg` is only a dependency on MSVC
+        let (_, missing) =
+            CargoMetadata::from_index(krate, &index, &Default::default(), "x86_64-pc-windows-msvc");
+        assert_eq!(missing, BTreeSet::from(["vcpkg".to_string()]));
+    }
+
+    /// An index file with one version of `name`, and no dependencies
+    fn index_entry(name: &str, version: &str) -> (String, Vec<IndexVersion>) {
+        let content = format!(
+            r#"{{"name":"{name}","vers":"{version}","deps":[],"cksum":"","features":{{}}}}"#
+        );
+        (name.to_string(), crate::crates_io::parse_index(&content))
+    }
 }
@@ -0,0 +1,154 @@
+//! The `cache` subcommand.
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use atty::Stream;
+use clap::{Args, Subcommand};
+use eyre::WrapErr;
+
+use crate::color::Colorize;
+use crate::dependency_registry::{
+    cache_dir, cached_registry_path, format_age, registry_refreshed_at,
+    DEPENDENCY_REGISTRY_CACHE_PATH,
+};
+use crate::flake_generator::FLAKES_CACHE_PATH;
+
+/// Manage the files riff caches
+#[derive(Debug, Args)]
+pub struct Cache {
+    #[clap(subcommand)]
+    command: CacheCommands,
+}
+
+#[derive(Debug, Subcommand)]
+enum CacheCommands {
+    Clear(Clear),
+    Path(CachePath),
+}
+
+impl Cache {
+    pub async fn cmd(&self) -> color_eyre::Result<Option<i32>> {
+        match &self.command {
+            CacheCommands::Clear(clear) => clear.cmd().await,
+            CacheCommands::Path(path) => path.cmd().await,
+        }
+    }
+}
+
+/// Remove the cached registry
+///
+/// Riff falls back to its bundled registry until the cache is refreshed again.
+#[derive(Debug, Args)]
+struct Clear {
+    /// Remove everything riff caches, including generated flakes, not just the registry
+    #[clap(long)]
+    all: bool,
+    /// Remove the generated flakes and their locks instead of the registry
+    ///
+    /// They are generated and locked again the next time they are needed.
+    #[clap(long, conflicts_with = "all")]
+    flakes: bool,
+    /// Do not ask for confirmation
+    #[clap(long, short)]
+    yes: bool,
+    #[clap(from_global)]
+    cache_dir: Option<PathBuf>,
+}
+
+impl Clear {
+    async fn cmd(&self) -> color_eyre::Result<Option<i32>> {
+        let path = if self.all {
+            cache_dir(self.cache_dir.as_deref())?
+        } else if self.flakes {
+            cache_dir(self.cache_dir.as_deref())?.join(FLAKES_CACHE_PATH)
+        } else {
+            cached_registry_path(self.cache_dir.as_deref())?
+        };
+
+        if !path.exists() {
+            return Ok(Some(0));
+        }
+
+        if !self.yes && atty::is(Stream::Stdin) && !confirm(&path)? {
+            return Ok(Some(1));
+        }
+
+        if path.is_dir() {
+            tokio::fs::remove_dir_all(&path).await
+        } else {
+            tokio::fs::remove_file(&path).await
+        }
+        .wrap_err_with(|| format!("Unable to remove `{}`", path.display()))?;
+        eprintln!(
+            "{check} Removed `{path}`",
+            check = "✓".green(),
+            path = path.display().to_string().green(),
+        );
+
+        Ok(Some(0))
+    }
+}
+
+/// Print where riff keeps its cached files, and when the cached registry was last refreshed
+#[derive(Debug, Args)]
+struct CachePath {
+    /// Print the paths as a JSON object
+    #[clap(long)]
+    json: bool,
+    #[clap(from_global)]
+    cache_dir: Option<PathBuf>,
+}
+
+impl CachePath {
+    async fn cmd(&self) -> color_eyre::Result<Option<i32>> {
+        let cache_dir = cache_dir(self.cache_dir.as_deref())?;
+        let registry_cache = cache_dir.join(DEPENDENCY_REGISTRY_CACHE_PATH);
+        let flakes_dir = cache_dir.join(FLAKES_CACHE_PATH);
+        // In seconds since the Unix epoch
+        let registry_refreshed_at = registry_refreshed_at(&registry_cache)
+            .await
+            .and_then(|refreshed_at| refreshed_at.duration_since(UNIX_EPOCH).ok())
+            .map(|since_epoch| since_epoch.as_secs());
+
+        if self.json {
+            let paths = serde_json::json!({
+                "registry_cache": registry_cache,
+                "registry_refreshed_at": registry_refreshed_at,
+                "flakes_dir": flakes_dir,
+                "cache_dir": cache_dir,
+            });
+            println!("{}", serde_json::to_string_pretty(&paths)?);
+        } else {
+            println!("registry_cache: {}", registry_cache.display());
+            match registry_refreshed_at {
+                Some(refreshed_at) => {
+                    let age = SystemTime::now()
+                        .duration_since(UNIX_EPOCH + Duration::from_secs(refreshed_at))
+                        .unwrap_or_default();
+                    println!(
+                        "registry_refreshed_at: {refreshed_at} ({} ago)",
+                        format_age(age)
+                    );
+                }
+                None => println!("registry_refreshed_at: never"),
+            }
+            println!("flakes_dir: {}", flakes_dir.display());
+            println!("cache_dir: {}", cache_dir.display());
+        }
+
+        Ok(Some(0))
+    }
+}
+
+fn confirm(path: &Path) -> color_eyre::Result<bool> {
+    eprint!(
+        "Remove `{path}`? [y/N] ",
+        path = path.display().to_string().green()
+    );
+    std::io::stderr().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
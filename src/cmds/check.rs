@@ -0,0 +1,366 @@
+//! The `check` subcommand.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::str::FromStr;
+
+use clap::{Args, ValueEnum};
+use eyre::{eyre, WrapErr};
+use itertools::Itertools;
+use semver::VersionReq;
+use serde::Serialize;
+
+use super::GenerateArgs;
+use crate::cargo_metadata::{CargoFeatures, CargoMetadata};
+use crate::color::Colorize;
+use crate::crates_io;
+use crate::dependency_registry::{DependencyRegistry, RefreshState};
+use crate::dev_env::{CrateContribution, DevEnvironment};
+
+/// The version of the `--format json` report, bumped when fields are removed or change meaning
+const REPORT_VERSION: u32 = 1;
+
+/// Check which dependencies riff would provide, without starting a shell
+///
+/// For example, check a crate published on crates.io:
+///
+///     $ riff check --crate tokio@1.35
+#[derive(Debug, Args)]
+pub struct Check {
+    #[clap(flatten)]
+    generate: GenerateArgs,
+    /// A crate published on crates.io to check instead of a local project (`name` or `name@version`)
+    #[clap(
+        long = "crate",
+        value_name = "NAME[@VERSION]",
+        conflicts_with_all = ["project_dir", "package", "all_features", "no_cargo_metadata"]
+    )]
+    krate: Option<CrateSpec>,
+    /// How to print the dependencies, `json` prints a report for tooling to stdout and nothing else
+    #[clap(long, value_enum, default_value_t = Format::Human)]
+    format: Format,
+}
+
+/// The output formats of `riff check`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Format {
+    Human,
+    Json,
+}
+
+/// The `--format json` report of the dependencies riff provides
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct Report<'a> {
+    /// [`REPORT_VERSION`]
+    version: u32,
+    registry: RegistryReport,
+    /// The target triple cross compiled to, if not the host
+    target: Option<&'a str>,
+    /// The channel of the Rust toolchain from rust-overlay, if not the host's
+    rust_toolchain: Option<&'a str>,
+    /// The crates which added anything, sorted by name and version
+    crates: Vec<&'a CrateContribution>,
+    build_inputs: BTreeSet<&'a String>,
+    native_build_inputs: BTreeSet<&'a String>,
+    environment_variables: BTreeMap<&'a String, &'a String>,
+    runtime_inputs: BTreeSet<&'a String>,
+    darwin_frameworks: BTreeSet<&'a String>,
+    /// The `-sys` and `-src` crates with no settings, whose system libraries may be missing
+    unmapped_crates: &'a BTreeSet<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct RegistryReport {
+    /// If the registry was refreshed, or the cache was recent enough not to be
+    fresh: bool,
+    #[serde(flatten)]
+    refresh_state: RefreshState,
+}
+
+impl<'a> Report<'a> {
+    fn new(dev_env: &'a DevEnvironment<'_>, refresh_state: RefreshState) -> Self {
+        Report {
+            version: REPORT_VERSION,
+            registry: RegistryReport {
+                fresh: matches!(
+                    refresh_state,
+                    RefreshState::Recent | RefreshState::Succeeded
+                ),
+                refresh_state,
+            },
+            target: dev_env.target.as_ref().map(|target| target.triple.as_str()),
+            rust_toolchain: dev_env
+                .rust_toolchain
+                .as_ref()
+                .map(|rust_toolchain| rust_toolchain.channel.as_str()),
+            crates: dev_env
+                .contributions
+                .iter()
+                .sorted_by(|a, b| (&a.name, &a.version).cmp(&(&b.name, &b.version)))
+                .collect(),
+            build_inputs: dev_env.build_inputs.iter().collect(),
+            native_build_inputs: dev_env.native_build_inputs.iter().collect(),
+            environment_variables: dev_env.environment_variables.iter().collect(),
+            runtime_inputs: dev_env.runtime_inputs.iter().collect(),
+            darwin_frameworks: dev_env.darwin_frameworks.iter().collect(),
+            unmapped_crates: &dev_env.unmapped_crates,
+        }
+    }
+}
+
+impl Check {
+    pub async fn cmd(&self) -> color_eyre::Result<Option<i32>> {
+        let options = self.generate.options();
+        let registry = DependencyRegistry::new(&options.registry_options().await?).await?;
+        let mut dev_env = DevEnvironment::new(&registry);
+        options.configure(&mut dev_env);
+        dev_env.quiet = options.quiet || self.format == Format::Json;
+
+        let project_dir = match &self.krate {
+            Some(krate) => {
+                if self.generate.offline {
+                    return Err(eyre!(
+                        "Checking `{krate}` requires fetching it from crates.io, which is not possible with `--offline`"
+                    ));
+                }
+                let triple = match &dev_env.target {
+                    Some(target) => target.triple.clone(),
+                    None => target_lexicon::HOST.to_string(),
+                };
+                let metadata = krate
+                    .metadata(&self.generate.features, &triple)
+                    .await
+                    .wrap_err_with(|| format!("Could not resolve `{krate}` from crates.io"))?;
+                dev_env.detect_metadata(metadata).await?;
+                None
+            }
+            None => {
+                let project_dir = match &self.generate.project_dir {
+                    Some(dir) => dir.clone(),
+                    None => {
+                        std::env::current_dir().wrap_err("Current working directory was invalid")?
+                    }
+                };
+                let project_dir = crate::cargo_metadata::find_project_dir(&project_dir).await?;
+                dev_env
+                    .detect(
+                        &project_dir,
+                        &self.generate.features,
+                        self.generate.package.as_deref(),
+                    )
+                    .await?;
+                Some(project_dir)
+            }
+        };
+        options.extend(&mut dev_env)?;
+        options
+            .select_rust_toolchain(&mut dev_env, project_dir.as_deref())
+            .await?;
+
+        match self.format {
+            Format::Human => {
+                if let Some(target) = &dev_env.target {
+                    eprintln!(
+                        "{check} Cross compiling to `{triple}`, with the build inputs from `{cross_pkgs}`",
+                        check = "✓".green(),
+                        triple = target.triple.cyan(),
+                        cross_pkgs = format!("pkgsCross.{}", target.nixpkgs_cross).cyan(),
+                    );
+                }
+                registry.warn_if_stale().await
+            }
+            Format::Json => {
+                let report = Report::new(&dev_env, registry.refresh_state());
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            }
+        }
+
+        Ok(Some(0))
+    }
+}
+
+/// A crate on crates.io, optionally constrained to a version
+#[derive(Debug, Clone)]
+pub struct CrateSpec {
+    name: String,
+    version: VersionReq,
+}
+
+impl CrateSpec {
+    /// The metadata of the newest matching version of this crate and its dependencies for
+    /// `triple`, with the given features, read from the crates.io index without downloading any
+    /// crate
+    async fn metadata(
+        &self,
+        features: &CargoFeatures,
+        triple: &str,
+    ) -> color_eyre::Result<CargoMetadata> {
+        let http_client = crates_io::http_client()?;
+        let versions = crates_io::fetch_index(&http_client, &self.name).await?;
+        let krate = crates_io::newest_matching(&versions, &self.version).ok_or_else(|| {
+            eyre!(
+                "No version of `{}` on crates.io matches `{}`",
+                self.name,
+                self.version
+            )
+        })?;
+
+        // Each round resolves one more level of the dependency tree
+        let mut index = HashMap::new();
+        loop {
+            let (metadata, missing) = CargoMetadata::from_index(krate, &index, features, triple);
+            if missing.is_empty() {
+                return Ok(metadata);
+            }
+            let mut fetches = tokio::task::JoinSet::new();
+            for name in missing {
+                let http_client = http_client.clone();
+                fetches.spawn(async move {
+                    let versions = crates_io::fetch_index(&http_client, &name).await;
+                    (name, versions)
+                });
+            }
+            while let Some(fetched) = fetches.join_next().await {
+                let (name, versions) = fetched?;
+                index.insert(name, versions?);
+            }
+        }
+    }
+}
+
+impl FromStr for CrateSpec {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, version) = match s.split_once('@') {
+            Some((name, version)) => {
+                // Like `cargo install`, a bare version means that exact version.
+                let version = if version.starts_with(|c: char| c.is_ascii_digit()) {
+                    format!("={version}")
+                } else {
+                    version.to_string()
+                };
+                let version = VersionReq::parse(&version)
+                    .wrap_err_with(|| format!("`{version}` is not a valid version requirement"))?;
+                (name, version)
+            }
+            None => (s, VersionReq::STAR),
+        };
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(eyre!("`{name}` is not a valid crate name"));
+        }
+        Ok(Self {
+            name: name.to_string(),
+            version,
+        })
+    }
+}
+
+impl std::fmt::Display for CrateSpec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}@{}", self.name, self.version)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use tempfile::TempDir;
+
+    use super::{CrateSpec, RegistryReport, Report};
+    use crate::dependency_registry::{DependencyRegistry, DependencyRegistryOptions, RefreshState};
+    use crate::dev_env::{CrateContribution, CrateSettingsSource, DevEnvironment};
+    use crate::rust_toolchain::RustChannel;
+
+    #[test]
+    fn crate_spec_parse() -> eyre::Result<()> {
+        let spec: CrateSpec = "tokio@1.35".parse()?;
+        assert_eq!(spec.name, "tokio");
+        assert_eq!(spec.version.to_string(), "=1.35");
+
+        let spec: CrateSpec = "openssl-sys@^0.9".parse()?;
+        assert_eq!(spec.version.to_string(), "^0.9");
+
+        let spec: CrateSpec = "openssl-sys".parse()?;
+        assert_eq!(spec.version.to_string(), "*");
+
+        assert!("not a crate".parse::<CrateSpec>().is_err());
+        assert!("tokio@wat".parse::<CrateSpec>().is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn report_json() -> eyre::Result<()> {
+        let cache_dir = TempDir::new()?;
+        let registry = DependencyRegistry::new(
+            &DependencyRegistryOptions::new()
+                .cache_dir(cache_dir.path())
+                .offline(true),
+        )
+        .await?;
+        let mut dev_env = DevEnvironment::new(&registry);
+        dev_env.build_inputs.insert("openssl".into());
+        dev_env.contributions.push(CrateContribution {
+            name: "openssl-sys".into(),
+            version: semver::Version::new(0, 9, 90),
+            source: CrateSettingsSource::Registry,
+            build_inputs: BTreeSet::from(["openssl".to_string()]),
+            native_build_inputs: BTreeSet::from(["pkg-config".to_string()]),
+            environment_variables: Default::default(),
+            runtime_inputs: Default::default(),
+            darwin_frameworks: Default::default(),
+        });
+        dev_env.unmapped_crates.insert("mystery-sys".into());
+
+        let report = serde_json::to_value(Report::new(&dev_env, registry.refresh_state()))?;
+        assert_eq!(report["version"], 1);
+        assert_eq!(
+            report["registry"],
+            serde_json::json!({ "fresh": false, "refresh-state": "offline" })
+        );
+        assert_eq!(report["target"], serde_json::Value::Null);
+        assert_eq!(report["rust-toolchain"], serde_json::Value::Null);
+        assert_eq!(report["crates"][0]["name"], "openssl-sys");
+        assert_eq!(report["crates"][0]["version"], "0.9.90");
+        assert_eq!(report["crates"][0]["source"], "registry");
+        assert_eq!(
+            report["crates"][0]["build-inputs"],
+            serde_json::json!(["openssl"])
+        );
+        assert_eq!(
+            report["crates"][0]["native-build-inputs"],
+            serde_json::json!(["pkg-config"])
+        );
+        assert_eq!(report["build-inputs"], serde_json::json!(["openssl"]));
+        assert_eq!(
+            report["unmapped-crates"],
+            serde_json::json!(["mystery-sys"])
+        );
+
+        dev_env.target = Some("aarch64-unknown-linux-gnu".parse()?);
+        dev_env.rust_toolchain = RustChannel::Channel("nightly".into()).into();
+        let report = serde_json::to_value(Report::new(&dev_env, registry.refresh_state()))?;
+        assert_eq!(report["target"], "aarch64-unknown-linux-gnu");
+        assert_eq!(report["rust-toolchain"], "nightly");
+
+        let failed = serde_json::to_value(RegistryReport {
+            fresh: false,
+            refresh_state: RefreshState::Failed("timed out".into()),
+        })?;
+        assert_eq!(
+            failed,
+            serde_json::json!({
+                "fresh": false,
+                "refresh-state": "failed",
+                "refresh-error": "timed out",
+            })
+        );
+        Ok(())
+    }
+}
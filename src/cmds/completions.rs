@@ -0,0 +1,28 @@
+//! The `completions` subcommand.
+
+use clap::{Args, CommandFactory};
+use clap_complete::Shell;
+
+use crate::Cli;
+
+/// Print a shell completion script to stdout
+///
+/// For example, to enable completions in the current bash session:
+///
+///     $ source <(riff completions bash)
+#[derive(Debug, Args)]
+pub struct Completions {
+    /// The shell to generate completions for
+    #[clap(value_enum)]
+    shell: Shell,
+}
+
+impl Completions {
+    pub async fn cmd(&self) -> color_eyre::Result<Option<i32>> {
+        let mut command = Cli::command();
+        let name = command.get_name().to_string();
+        clap_complete::generate(self.shell, &mut command, name, &mut std::io::stdout());
+
+        Ok(Some(0))
+    }
+}
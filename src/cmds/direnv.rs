@@ -0,0 +1,165 @@
+//! The `direnv` subcommand.
+
+use std::path::Path;
+
+use clap::Args;
+use eyre::{eyre, WrapErr};
+
+use super::GenerateArgs;
+use crate::color::Colorize;
+use crate::flake_generator::{self, FlakeDir};
+
+/// The line which starts the block `riff direnv` manages in `.envrc`
+const BLOCK_START: &str = "# >>> riff >>>";
+/// The line which ends the block `riff direnv` manages in `.envrc`
+const BLOCK_END: &str = "# <<< riff <<<";
+
+/// Add a block to your `.envrc` which loads the riff environment with direnv
+///
+/// Entering the project directory then loads the environment, without running `riff shell`. Run
+/// it again after changing the project's dependencies. For example, preview the block:
+///
+///     $ riff direnv --print
+#[derive(Debug, Args)]
+pub struct Direnv {
+    #[clap(flatten)]
+    generate: GenerateArgs,
+    /// Print the block instead of writing it to `.envrc`
+    #[clap(long)]
+    print: bool,
+}
+
+impl Direnv {
+    pub async fn cmd(&self) -> color_eyre::Result<Option<i32>> {
+        let project_dir = match &self.generate.project_dir {
+            Some(dir) => dir.clone(),
+            None => std::env::current_dir().wrap_err("Current working directory was invalid")?,
+        };
+        let project_dir = crate::cargo_metadata::find_project_dir(&project_dir).await?;
+        let flake_dir = flake_generator::generate_flake(&self.generate.options()).await?;
+        if let FlakeDir::Temporary(_) = flake_dir {
+            return Err(eyre!(
+                "The flake could not be written to the cache directory, pass `--flake-dir` to choose where to write it"
+            ));
+        }
+        let block = envrc_block(&flake_dir.installable(self.generate.package.as_deref())?);
+
+        if self.print {
+            print!("{block}");
+            return Ok(Some(0));
+        }
+
+        let envrc_path = project_dir.join(".envrc");
+        let envrc = match tokio::fs::read_to_string(&envrc_path).await {
+            Ok(envrc) => envrc,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(err) => {
+                return Err(err)
+                    .wrap_err_with(|| format!("Unable to read `{}`", envrc_path.display()))
+            }
+        };
+        match update_envrc(&envrc, &block)? {
+            Some(updated) => {
+                tokio::fs::write(&envrc_path, updated)
+                    .await
+                    .wrap_err_with(|| format!("Unable to write `{}`", envrc_path.display()))?;
+                eprintln!(
+                    "{check} Wrote the riff block to `{envrc_path}`, run `{direnv_allow}` to load it",
+                    check = "✓".green(),
+                    envrc_path = envrc_path.display().to_string().green(),
+                    direnv_allow = direnv_allow(&project_dir).cyan(),
+                );
+            }
+            None => eprintln!(
+                "`{envrc_path}` already contains the riff block, nothing to do.",
+                envrc_path = envrc_path.display().to_string().green(),
+            ),
+        }
+
+        Ok(Some(0))
+    }
+}
+
+/// The `direnv allow` command to run for `project_dir`
+fn direnv_allow(project_dir: &Path) -> String {
+    match std::env::current_dir() {
+        Ok(current_dir) if current_dir == project_dir => "direnv allow".to_string(),
+        _ => format!("direnv allow {}", project_dir.display()),
+    }
+}
+
+/// The block which loads the flake at `flake_ref` with direnv's `use flake`
+fn envrc_block(flake_ref: &str) -> String {
+    format!(
+        "\
+        {BLOCK_START}\n\
+        # Managed by `riff direnv`, run it again after changing the project's dependencies\n\
+        use flake {flake_ref}\n\
+        {BLOCK_END}\n\
+        "
+    )
+}
+
+/// Add `block` to the end of `envrc`, or replace the riff block already in it
+///
+/// Returns `None` if `envrc` already contains `block`.
+fn update_envrc(envrc: &str, block: &str) -> color_eyre::Result<Option<String>> {
+    let start = envrc
+        .lines()
+        .position(|line| line.trim_end() == BLOCK_START);
+    let end = envrc.lines().position(|line| line.trim_end() == BLOCK_END);
+    let updated = match (start, end) {
+        (None, None) if envrc.is_empty() || envrc.ends_with('\n') => format!("{envrc}{block}"),
+        (None, None) => format!("{envrc}\n{block}"),
+        (Some(start), Some(end)) if start < end => {
+            let lines: Vec<_> = envrc.split_inclusive('\n').collect();
+            let after = lines[end + 1..].concat();
+            format!("{}{block}{after}", lines[..start].concat())
+        }
+        _ => {
+            return Err(eyre!(
+                "`.envrc` contains only one of the `{BLOCK_START}` and `{BLOCK_END}` lines, remove the riff block and try again"
+            ))
+        }
+    };
+    Ok((updated != envrc).then_some(updated))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{envrc_block, update_envrc};
+
+    #[test]
+    fn update_envrc_appends() -> eyre::Result<()> {
+        let block = envrc_block("path:///cache/riff/flakes/abc");
+        assert_eq!(update_envrc("", &block)?, Some(block.clone()));
+        assert_eq!(
+            update_envrc("dotenv\n", &block)?,
+            Some(format!("dotenv\n{block}"))
+        );
+        assert_eq!(
+            update_envrc("dotenv", &block)?,
+            Some(format!("dotenv\n{block}"))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn update_envrc_replaces() -> eyre::Result<()> {
+        let old = envrc_block("path:///cache/riff/flakes/abc");
+        let new = envrc_block("path:///cache/riff/flakes/def");
+        let envrc = format!("dotenv\n{old}layout python\n");
+        assert_eq!(
+            update_envrc(&envrc, &new)?,
+            Some(format!("dotenv\n{new}layout python\n"))
+        );
+        assert_eq!(update_envrc(&envrc, &old)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn update_envrc_requires_both_markers() {
+        let block = envrc_block("path:///cache/riff/flakes/abc");
+        assert!(update_envrc("# >>> riff >>>\nuse flake\n", &block).is_err());
+    }
+}
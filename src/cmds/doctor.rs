@@ -0,0 +1,231 @@
+//! The `doctor` subcommand.
+
+use std::path::Path;
+use std::time::SystemTime;
+
+use clap::Args;
+use tokio::process::Command;
+
+use super::RegistryArgs;
+use crate::color::Colorize;
+use crate::config::Config;
+use crate::dependency_registry::{
+    cached_registry_path, format_age, registry_refreshed_at, DependencyRegistry,
+    DependencyRegistryData, RefreshState,
+};
+
+/// Check that your system is ready to run riff
+///
+/// Exits non-zero if a hard requirement, like `nix`, is missing.
+#[derive(Debug, Args)]
+pub struct Doctor {
+    #[clap(from_global)]
+    offline: bool,
+    #[clap(flatten)]
+    registry: RegistryArgs,
+}
+
+/// The outcome of a single check
+struct Diagnosis {
+    /// What was checked
+    description: &'static str,
+    /// Whether riff cannot work without this check passing
+    required: bool,
+    /// Details on success, or a remediation hint on failure
+    outcome: Result<String, String>,
+}
+
+impl Doctor {
+    pub async fn cmd(&self) -> color_eyre::Result<Option<i32>> {
+        let diagnoses = [
+            check_nix().await,
+            check_nix_flakes().await,
+            check_cargo().await,
+            check_cache_dir(self.registry.cache_dir.as_deref()).await,
+            check_registry_cache(self.registry.cache_dir.as_deref()).await,
+            self.check_registry_refresh().await,
+        ];
+
+        let mut failed_required = false;
+        for diagnosis in &diagnoses {
+            match &diagnosis.outcome {
+                Ok(details) => {
+                    eprintln!(
+                        "{check} {description}: {details}",
+                        check = "✓".green(),
+                        description = diagnosis.description,
+                    )
+                }
+                Err(hint) => {
+                    failed_required |= diagnosis.required;
+                    let mark = if diagnosis.required {
+                        "✗".red().to_string()
+                    } else {
+                        "!".yellow().to_string()
+                    };
+                    eprintln!(
+                        "{mark} {description}\n  {hint}",
+                        description = diagnosis.description,
+                    )
+                }
+            }
+        }
+
+        Ok(Some(if failed_required { 1 } else { 0 }))
+    }
+}
+
+/// Run a command, returning its trimmed stdout if it succeeded and a description of the failure
+/// if not
+async fn command_output(command: &mut Command) -> Result<String, String> {
+    match command.output().await {
+        Ok(output) if output.status.success() => {
+            Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+        }
+        Ok(output) => Err(String::from_utf8_lossy(&output.stderr).trim().to_string()),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+async fn check_nix() -> Diagnosis {
+    Diagnosis {
+        description: "`nix` is installed",
+        required: true,
+        outcome: command_output(Command::new("nix").arg("--version"))
+            .await
+            .map_err(|err| {
+                format!(
+                    "Could not run `{nix}` ({err}). Get instructions for installing Nix: {nix_install_url}",
+                    nix = "nix --version".cyan(),
+                    nix_install_url = "https://nixos.org/download.html".blue().underline(),
+                )
+            }),
+    }
+}
+
+async fn check_nix_flakes() -> Diagnosis {
+    Diagnosis {
+        description: "`nix` supports the `flakes` and `nix-command` features",
+        required: true,
+        outcome: command_output(
+            Command::new("nix")
+                .args(["--extra-experimental-features", "flakes nix-command"])
+                .args(["eval", "--raw", "--expr", "\"ok\""]),
+        )
+        .await
+        .map(|_| "evaluation works".to_string())
+        .map_err(|err| {
+            format!(
+                "Could not evaluate an expression with `{nix_eval}` ({err}). Riff needs Nix 2.4 or newer.",
+                nix_eval = "nix eval".cyan(),
+            )
+        }),
+    }
+}
+
+async fn check_cargo() -> Diagnosis {
+    Diagnosis {
+        description: "`cargo` is installed",
+        required: false,
+        outcome: command_output(Command::new("cargo").arg("--version"))
+            .await
+            .map_err(|err| {
+                format!(
+                    "Could not run `{cargo}` ({err}). Riff will read the dependencies from `Cargo.lock` instead, which misses those only needed by some features. Get instructions for installing Cargo: {rust_install_url}",
+                    cargo = "cargo --version".cyan(),
+                    rust_install_url = "https://www.rust-lang.org/tools/install".blue().underline(),
+                )
+            }),
+    }
+}
+
+async fn check_cache_dir(cache_dir: Option<&Path>) -> Diagnosis {
+    let outcome = async {
+        let cached_registry = cached_registry_path(cache_dir)
+            .map_err(|err| format!("{:#}", eyre::Report::new(err)))?;
+        let cache_dir = cached_registry
+            .parent()
+            .ok_or_else(|| "The cache directory has no parent".to_string())?;
+        let probe = cache_dir.join(format!(".doctor{}", std::process::id()));
+        tokio::fs::write(&probe, "")
+            .await
+            .map_err(|err| format!("Could not write to `{}`: {err}", cache_dir.display()))?;
+        tokio::fs::remove_file(&probe).await.ok();
+        Ok(cache_dir.display().to_string())
+    }
+    .await;
+    Diagnosis {
+        description: "The cache directory is writable",
+        required: false,
+        outcome: outcome.map_err(|err: String| {
+            format!("{err}. Riff will not be able to cache the registry; check `$XDG_CACHE_HOME` or `--cache-dir`.")
+        }),
+    }
+}
+
+async fn check_registry_cache(cache_dir: Option<&Path>) -> Diagnosis {
+    let outcome = async {
+        let cached_registry = cached_registry_path(cache_dir)
+            .map_err(|err| format!("{:#}", eyre::Report::new(err)))?;
+        let content = tokio::fs::read_to_string(&cached_registry)
+            .await
+            .map_err(|err| format!("Could not read `{}`: {err}", cached_registry.display()))?;
+        if content.is_empty() {
+            return Err(format!(
+                "`{}` is empty, riff will use its bundled registry until it is refreshed",
+                cached_registry.display()
+            ));
+        }
+        DependencyRegistryData::from_json(&content).map_err(|err| {
+            format!(
+                "`{}` could not be parsed ({err}), try removing it",
+                cached_registry.display()
+            )
+        })?;
+        let refreshed = match registry_refreshed_at(&cached_registry)
+            .await
+            .map(|refreshed_at| SystemTime::now().duration_since(refreshed_at))
+        {
+            Some(Ok(age)) => format!("last refreshed {} ago", format_age(age)),
+            _ => "last refreshed at an unknown time".to_string(),
+        };
+        Ok(format!("{}, {refreshed}", cached_registry.display()))
+    }
+    .await;
+    Diagnosis {
+        description: "The cached registry exists and parses",
+        required: false,
+        outcome,
+    }
+}
+
+impl Doctor {
+    async fn check_registry_refresh(&self) -> Diagnosis {
+        let outcome = async {
+            let options = Config::load()
+                .await
+                .map_err(|err| format!("{err:#}"))?
+                .registry_options()
+                .overridden_by(self.registry.options().offline(self.offline));
+            let mut registry = DependencyRegistry::new(&options)
+                .await
+                .map_err(|err| format!("{:#}", eyre::Report::new(err)))?;
+            if !(self.offline || self.registry.no_refresh) {
+                // The error is in the refresh state
+                registry.refresh_now().await.ok();
+            }
+            match registry.refresh_state() {
+                RefreshState::Failed(err) => Err(format!(
+                    "{err}. Riff will use the cached or bundled registry, which may be out of date."
+                )),
+                state => Ok(state.to_string()),
+            }
+        }
+        .await;
+        Diagnosis {
+            description: "The registry can be refreshed",
+            required: false,
+            outcome,
+        }
+    }
+}
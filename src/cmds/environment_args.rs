@@ -0,0 +1,172 @@
+//! Arguments shared by the subcommands which run something in the development environment.
+
+use clap::Args;
+use eyre::eyre;
+
+use super::GenerateArgs;
+use crate::color::Colorize;
+use crate::flake_generator::{self, FlakeDir, GenerateOptions};
+use crate::nix_dev_env::{EnvironmentOptions, NixDevEnv};
+
+#[derive(Debug, Args, Clone, Default)]
+pub struct EnvironmentArgs {
+    /// An extra argument to pass to `nix print-dev-env` (may be repeated)
+    ///
+    /// Arguments are forwarded as-is, without any shell quoting, for example
+    /// `--nix-arg=--override-input --nix-arg=nixpkgs --nix-arg=github:NixOS/nixpkgs`.
+    #[clap(long = "nix-arg", value_name = "ARG", allow_hyphen_values = true)]
+    pub(crate) nix_args: Vec<String>,
+    /// Do not inherit the ambient environment, except for `HOME`, `TERM` and `USER`
+    #[clap(long)]
+    pub(crate) pure: bool,
+    /// Like `--pure`, but do not keep `HOME`, `TERM` and `USER` either
+    #[clap(long, requires = "pure")]
+    pub(crate) strict_pure: bool,
+    /// Keep an environment variable from the ambient environment with `--pure` (may be repeated)
+    #[clap(long, value_name = "KEY")]
+    pub(crate) keep: Vec<String>,
+    /// Remove an environment variable from the environment (may be repeated)
+    #[clap(long, value_name = "KEY")]
+    pub(crate) unset: Vec<String>,
+    /// Print the commands riff would run instead of running them
+    ///
+    /// The generated flake is kept, so it can be inspected too.
+    #[clap(long)]
+    pub(crate) dry_run: bool,
+    /// Keep the generated flake, and print where it is once the command has exited
+    ///
+    /// Without `--flake-dir`, the flake is usually kept in the cache directory anyway, but not if
+    /// that couldn't be created.
+    #[clap(long)]
+    pub(crate) keep_flake: bool,
+    /// Use `nix-shell` with a generated `shell.nix`, for Nix installations without flakes
+    ///
+    /// Nixpkgs is fetched from GitHub at `--nixpkgs-rev`, or `package.metadata.riff.nixpkgs`, and
+    /// isn't locked.
+    #[clap(long, conflicts_with = "nixpkgs_flake")]
+    pub(crate) legacy_nix: bool,
+}
+
+impl EnvironmentArgs {
+    pub(crate) fn options(&self) -> color_eyre::Result<EnvironmentOptions> {
+        if let Some(key) = self.keep.iter().find(|key| self.unset.contains(key)) {
+            return Err(eyre!(
+                "`{key}` was passed to both `--keep` and `--unset`, which is contradictory"
+            ));
+        }
+        Ok(EnvironmentOptions {
+            pure: self.pure,
+            strict_pure: self.strict_pure,
+            keep: self.keep.clone(),
+            unset: self.unset.clone(),
+        })
+    }
+
+    /// Generate the flake for `options`, or a `shell.nix` with `--legacy-nix`
+    ///
+    /// `riff shell` and `riff run` share the flake, and its lock, when their options are the same.
+    pub(crate) async fn generate_flake(
+        &self,
+        options: GenerateOptions,
+    ) -> color_eyre::Result<FlakeDir> {
+        flake_generator::generate_flake(&options.legacy_nix(self.legacy_nix)).await
+    }
+
+    /// Evaluate the environment of the flake in `flake_dir`, or its `shell.nix` with
+    /// `--legacy-nix`
+    pub(crate) async fn dev_env(
+        &self,
+        flake_dir: &FlakeDir,
+        generate: &GenerateArgs,
+    ) -> color_eyre::Result<NixDevEnv> {
+        if self.legacy_nix {
+            crate::nix_dev_env::get_nix_shell_dev_env(flake_dir, &self.nix_args, generate.quiet)
+                .await
+        } else {
+            crate::nix_dev_env::get_nix_dev_env(
+                flake_dir,
+                generate.package.as_deref(),
+                generate.offline,
+                &self.nix_args,
+                generate.quiet,
+            )
+            .await
+        }
+    }
+
+    /// Print how the environment of `flake_dir` is evaluated and `command` is run in it, for
+    /// `--dry-run`
+    pub(crate) fn print_dry_run(
+        &self,
+        flake_dir: &FlakeDir,
+        generate: &GenerateArgs,
+        command: &[String],
+    ) -> color_eyre::Result<()> {
+        crate::nix_dev_env::print_dry_run(
+            flake_dir,
+            generate.package.as_deref(),
+            generate.offline,
+            &self.nix_args,
+            self.legacy_nix,
+            command,
+        )
+    }
+
+    /// Drop `flake_dir`, removing a temporary flake, unless `--keep-flake` or `--dry-run` keep it
+    pub(crate) fn finish(&self, flake_dir: FlakeDir) {
+        if self.keep_flake {
+            let path = flake_dir.keep();
+            eprintln!(
+                "The generated flake is in `{path}`",
+                path = path.display().to_string().green(),
+            );
+        } else if self.dry_run {
+            flake_dir.keep();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::EnvironmentArgs;
+    use crate::flake_generator::FlakeDir;
+
+    #[test]
+    fn environment_args_keep_unset_conflict() {
+        let args = EnvironmentArgs {
+            keep: vec!["CARGO_HOME".into(), "RUSTFLAGS".into()],
+            unset: vec!["RUSTFLAGS".into()],
+            ..Default::default()
+        };
+        let err = args.options().unwrap_err();
+        assert!(err.to_string().contains("`RUSTFLAGS`"));
+
+        let args = EnvironmentArgs {
+            keep: vec!["CARGO_HOME".into()],
+            unset: vec!["RUSTFLAGS".into()],
+            ..Default::default()
+        };
+        assert!(args.options().is_ok());
+    }
+
+    #[test]
+    fn environment_args_finish_keeps_flake() -> eyre::Result<()> {
+        let flake_dir = FlakeDir::Temporary(TempDir::new()?);
+        let path = flake_dir.path().to_path_buf();
+        EnvironmentArgs::default().finish(flake_dir);
+        assert!(!path.exists());
+
+        let flake_dir = FlakeDir::Temporary(TempDir::new()?);
+        let path = flake_dir.path().to_path_buf();
+        let args = EnvironmentArgs {
+            keep_flake: true,
+            ..Default::default()
+        };
+        args.finish(flake_dir);
+        assert!(path.exists());
+        std::fs::remove_dir(&path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,115 @@
+//! The `explain` subcommand.
+
+use std::collections::{HashMap, HashSet};
+
+use clap::Args;
+use itertools::Itertools;
+
+use super::RegistryArgs;
+use crate::color::Colorize;
+use crate::config::Config;
+use crate::dependency_registry::rust::RustDependencyTargetData;
+use crate::dependency_registry::DependencyRegistry;
+
+/// The exit code used when the registry has no mapping for the crate
+const NO_MAPPING_FOUND_EXIT_CODE: i32 = 3;
+
+/// Explain which Nix inputs the registry maps a crate to
+///
+/// Exits with code 3 if the registry has no mapping for the crate. For example:
+///
+///     $ riff explain openssl-sys
+#[derive(Debug, Args)]
+pub struct Explain {
+    /// The name of the crate
+    #[clap(name = "crate")]
+    krate: String,
+    /// Match a registry entry whose name differs from the crate's in `-` and `_`
+    #[clap(long)]
+    ignore_name_separators: bool,
+    #[clap(from_global)]
+    offline: bool,
+    #[clap(flatten)]
+    registry: RegistryArgs,
+}
+
+impl Explain {
+    pub async fn cmd(&self) -> color_eyre::Result<Option<i32>> {
+        let registry_options = Config::load()
+            .await?
+            .registry_options()
+            .overridden_by(self.registry.options().offline(self.offline));
+        let registry = DependencyRegistry::new(&registry_options).await?;
+        let language_registry = registry.language().await;
+        let source = registry.source().await;
+
+        let (key, dep_config) = match language_registry
+            .rust
+            .find_dependency(&self.krate, self.ignore_name_separators)
+        {
+            Some(found) => found,
+            None => {
+                eprintln!(
+                    "{krate}: no mapping found in {source}",
+                    krate = self.krate.cyan(),
+                );
+                return Ok(Some(NO_MAPPING_FOUND_EXIT_CODE));
+            }
+        };
+
+        println!("{krate} (from {source})", krate = key.cyan().bold());
+        print_target_data(&dep_config.default, 1);
+        for (target, target_config) in dep_config.targets.iter().sorted_by_key(|(k, _)| *k) {
+            let host_marker = if *target == target_lexicon::HOST.to_string() {
+                " (this system)"
+            } else {
+                ""
+            };
+            println!("  {target}{host_marker}:", target = target.yellow());
+            print_target_data(target_config, 2);
+        }
+        for (feature, feature_config) in dep_config.features.iter().sorted_by_key(|(k, _)| *k) {
+            println!("  feature {feature}:", feature = feature.yellow());
+            print_target_data(feature_config, 2);
+        }
+        for rule in &dep_config.versions {
+            println!("  version {}:", rule.version.to_string().yellow());
+            print_target_data(&rule.data.default, 2);
+        }
+
+        Ok(Some(0))
+    }
+}
+
+fn print_target_data(data: &RustDependencyTargetData, depth: usize) {
+    let indent = "  ".repeat(depth);
+    print_inputs(&indent, "build-inputs", &data.build_inputs);
+    print_inputs(&indent, "native-build-inputs", &data.native_build_inputs);
+    print_inputs(&indent, "runtime-inputs", &data.runtime_inputs);
+    print_inputs(&indent, "darwin-frameworks", &data.darwin_frameworks);
+    print_environment_variables(&indent, &data.environment_variables);
+    if data.libclang {
+        println!("{indent}libclang: {}", "true".cyan());
+    }
+    if data.pkg_config_path {
+        println!("{indent}pkg-config-path: {}", "true".cyan());
+    }
+}
+
+fn print_inputs(indent: &str, label: &str, inputs: &HashSet<String>) {
+    if !inputs.is_empty() {
+        println!(
+            "{indent}{label}: {}",
+            inputs.iter().sorted().map(|v| v.cyan()).join(", ")
+        );
+    }
+}
+
+fn print_environment_variables(indent: &str, environment_variables: &HashMap<String, String>) {
+    if !environment_variables.is_empty() {
+        println!("{indent}environment-variables:");
+        for (key, value) in environment_variables.iter().sorted() {
+            println!("{indent}  {key} = {value}", key = key.green());
+        }
+    }
+}
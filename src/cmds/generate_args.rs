@@ -0,0 +1,158 @@
+//! Arguments shared by the subcommands which generate a flake.
+
+use std::path::PathBuf;
+
+use clap::Args;
+
+use super::RegistryArgs;
+use crate::cargo_metadata::CargoFeatures;
+use crate::cross_target::CrossTarget;
+use crate::dev_env::{
+    parse_build_input, parse_environment_variable, parse_nixpkgs_flake, parse_nixpkgs_rev,
+};
+use crate::flake_generator::GenerateOptions;
+use crate::rust_toolchain::{parse_component, RustChannel};
+use crate::telemetry::TelemetryLevel;
+
+#[derive(Debug, Args, Clone, Default)]
+pub struct GenerateArgs {
+    /// A directory in the project, defaults to the current directory
+    ///
+    /// Like Cargo, riff uses the workspace of the nearest `Cargo.toml` in it or its parents.
+    #[clap(long, value_parser)]
+    pub(crate) project_dir: Option<PathBuf>,
+    /// The directory to write the generated flake to, reusing any `flake.lock` already there
+    ///
+    /// Defaults to a directory under `$XDG_CACHE_HOME/riff/flakes` specific to the project.
+    #[clap(long, value_parser)]
+    pub(crate) flake_dir: Option<PathBuf>,
+    /// Only provide the dependencies of this workspace member, instead of every member
+    ///
+    /// The flake has a `devShell` for each member, and this selects the member's, like
+    /// `nix develop path://<flake-dir>#<package>`.
+    #[clap(long, short)]
+    pub(crate) package: Option<String>,
+    /// A Rust target triple to cross compile to, Eg `aarch64-unknown-linux-gnu`
+    #[clap(long, value_name = "TRIPLE")]
+    pub(crate) target: Option<CrossTarget>,
+    /// The Nixpkgs git revision or branch to use, overriding `package.metadata.riff.nixpkgs`
+    #[clap(long, value_name = "REV", value_parser = parse_nixpkgs_rev)]
+    pub(crate) nixpkgs_rev: Option<String>,
+    /// A flake reference to use for Nixpkgs, Eg an internal mirror like `git+ssh://git@example.com/nixpkgs`
+    #[clap(
+        long,
+        value_name = "FLAKEREF",
+        value_parser = parse_nixpkgs_flake,
+        conflicts_with = "nixpkgs_rev"
+    )]
+    pub(crate) nixpkgs_flake: Option<String>,
+    /// Ignore any `rust-toolchain.toml`, using the Rust toolchain from Nixpkgs
+    #[clap(long)]
+    pub(crate) no_toolchain: bool,
+    /// The Rust channel to provide (`stable`, `beta`, `nightly`, a version like `1.75.0`, or `none` to use the host's)
+    ///
+    /// Overrides `package.metadata.riff.rust-channel` and any `rust-toolchain.toml`.
+    #[clap(long, value_name = "CHANNEL")]
+    pub(crate) rust: Option<RustChannel>,
+    /// Add a component to the Rust toolchain, Eg `rust-analyzer` or `miri`, may be repeated
+    ///
+    /// Needs a toolchain from rust-overlay, chosen with `--rust`, `rust-channel` or a
+    /// `rust-toolchain.toml`.
+    #[clap(long = "rust-component", value_name = "COMPONENT", value_parser = parse_component)]
+    pub(crate) rust_components: Vec<String>,
+    /// Leave out the external dependencies only needed by `[dev-dependencies]`
+    #[clap(long)]
+    pub(crate) no_dev: bool,
+    /// Read the dependencies from `Cargo.lock` instead of running `cargo metadata`, which misses
+    /// those only needed by some features
+    #[clap(long)]
+    pub(crate) no_cargo_metadata: bool,
+    /// Match registry entries whose names differ from a crate's in `-` and `_`, Eg `foo_sys` for
+    /// `foo-sys`
+    ///
+    /// An exact match is always preferred, and case is ignored if there isn't one.
+    #[clap(long)]
+    pub(crate) ignore_name_separators: bool,
+    /// A Nixpkgs attribute path to add to the environment, Eg `protobuf` (may be repeated)
+    ///
+    /// Added along with the build inputs from the registry and `package.metadata.riff`, for trying
+    /// out a dependency without editing `Cargo.toml`.
+    #[clap(long = "build-input", value_name = "ATTR", value_parser = parse_build_input)]
+    pub(crate) build_inputs: Vec<String>,
+    /// Leave a crate out of the environment, Eg `openssl-sys` (may be repeated)
+    ///
+    /// Along with any in `package.metadata.riff.exclude`, for when the registry adds inputs a
+    /// crate doesn't need with the project's features. Matched case-insensitively.
+    #[clap(long = "exclude", value_name = "CRATE")]
+    pub(crate) excluded_crates: Vec<String>,
+    /// An environment variable to set in the environment, Eg `RUSTFLAGS=-Dwarnings` (may be repeated)
+    ///
+    /// Overrides any variable with the same name set by the registry or
+    /// `package.metadata.riff.environment-variables`.
+    #[clap(long = "env", value_name = "KEY=VALUE", value_parser = parse_environment_variable)]
+    pub(crate) environment_variables: Vec<(String, String)>,
+    /// Don't print the detected dependencies, or warn about `-sys` crates riff has no settings for
+    #[clap(long)]
+    pub(crate) quiet: bool,
+    #[clap(flatten)]
+    pub(crate) features: CargoFeatures,
+    #[clap(from_global)]
+    pub(crate) disable_telemetry: bool,
+    #[clap(from_global)]
+    pub(crate) telemetry_level: TelemetryLevel,
+    #[clap(from_global)]
+    pub(crate) offline: bool,
+    #[clap(flatten)]
+    pub(crate) registry: RegistryArgs,
+}
+
+impl GenerateArgs {
+    pub(crate) fn options(&self) -> GenerateOptions {
+        let mut options = GenerateOptions::new()
+            .features(self.features.clone())
+            .offline(self.offline)
+            .no_toolchain(self.no_toolchain)
+            .no_dev(self.no_dev)
+            .no_cargo_metadata(self.no_cargo_metadata)
+            .ignore_name_separators(self.ignore_name_separators)
+            .quiet(self.quiet)
+            .disable_telemetry(
+                self.disable_telemetry || self.telemetry_level == TelemetryLevel::None,
+            );
+        if let Some(project_dir) = &self.project_dir {
+            options = options.project_dir(project_dir);
+        }
+        if let Some(package) = &self.package {
+            options = options.package(package);
+        }
+        if let Some(target) = &self.target {
+            options = options.target(target.clone());
+        }
+        if let Some(nixpkgs_rev) = &self.nixpkgs_rev {
+            options = options.nixpkgs_rev(nixpkgs_rev);
+        }
+        if let Some(nixpkgs_flake) = &self.nixpkgs_flake {
+            options = options.nixpkgs_flake(nixpkgs_flake);
+        }
+        for build_input in &self.build_inputs {
+            options = options.build_input(build_input);
+        }
+        for name in &self.excluded_crates {
+            options = options.exclude(name);
+        }
+        for (key, value) in &self.environment_variables {
+            options = options.environment_variable(key, value);
+        }
+        if let Some(rust) = &self.rust {
+            options = options.rust_channel(rust.clone());
+        }
+        for component in &self.rust_components {
+            options = options.rust_component(component);
+        }
+        options.registry = self.registry.options();
+        if let Some(flake_dir) = &self.flake_dir {
+            options = options.flake_dir(flake_dir);
+        }
+        options
+    }
+}
@@ -0,0 +1,188 @@
+//! The `init` subcommand.
+
+use std::path::PathBuf;
+
+use clap::Args;
+use eyre::{eyre, WrapErr};
+use similar::TextDiff;
+use toml_edit::{DocumentMut, Item, Table};
+
+use crate::color::Colorize;
+
+/// Add a `package.metadata.riff` block to your `Cargo.toml`
+///
+/// Does nothing if the block already exists. For example, preview the change:
+///
+///     $ riff init --dry-run
+#[derive(Debug, Args)]
+pub struct Init {
+    /// The root directory of the project
+    #[clap(long, value_parser)]
+    project_dir: Option<PathBuf>,
+    /// Print the change as a diff instead of writing it
+    #[clap(long)]
+    dry_run: bool,
+}
+
+impl Init {
+    pub async fn cmd(&self) -> color_eyre::Result<Option<i32>> {
+        let project_dir = match &self.project_dir {
+            Some(dir) => dir.clone(),
+            None => std::env::current_dir().wrap_err("Current working directory was invalid")?,
+        };
+        let manifest_path = project_dir.join("Cargo.toml");
+        let manifest = tokio::fs::read_to_string(&manifest_path)
+            .await
+            .wrap_err_with(|| format!("Unable to read `{}`", manifest_path.display()))?;
+
+        let scaffolded = match scaffold_riff_metadata(&manifest)
+            .wrap_err_with(|| format!("Unable to edit `{}`", manifest_path.display()))?
+        {
+            Some(scaffolded) => scaffolded,
+            None => {
+                eprintln!(
+                    "`{manifest_path}` already contains a `{metadata}` block, nothing to do.",
+                    manifest_path = manifest_path.display().to_string().green(),
+                    metadata = "package.metadata.riff".cyan(),
+                );
+                return Ok(Some(0));
+            }
+        };
+
+        if self.dry_run {
+            let manifest_path = manifest_path.display().to_string();
+            print!(
+                "{}",
+                TextDiff::from_lines(&manifest, &scaffolded)
+                    .unified_diff()
+                    .header(&manifest_path, &manifest_path)
+            );
+        } else {
+            tokio::fs::write(&manifest_path, scaffolded)
+                .await
+                .wrap_err_with(|| format!("Unable to write `{}`", manifest_path.display()))?;
+            eprintln!(
+                "{check} Added a `{metadata}` block to `{manifest_path}`",
+                check = "✓".green(),
+                metadata = "package.metadata.riff".cyan(),
+                manifest_path = manifest_path.display().to_string().green(),
+            );
+        }
+
+        Ok(Some(0))
+    }
+}
+
+/// Add a commented `package.metadata.riff` scaffold to a manifest, preserving its formatting
+///
+/// Returns `None` if the manifest already has a `package.metadata.riff` block.
+fn scaffold_riff_metadata(manifest: &str) -> color_eyre::Result<Option<String>> {
+    let mut document: DocumentMut = manifest.parse()?;
+    // New tables are otherwise placed right after their parent, append them to the end instead.
+    let end_position = last_position(document.as_table()) + 1;
+
+    let package = document
+        .get_mut("package")
+        .and_then(Item::as_table_like_mut)
+        .ok_or_else(|| {
+            eyre!("No `[package]` table found, is this a virtual workspace manifest?")
+        })?;
+    if package
+        .get("metadata")
+        .and_then(|v| v.get("riff"))
+        .is_some()
+    {
+        return Ok(None);
+    }
+    let metadata = package
+        .entry("metadata")
+        .or_insert_with(|| {
+            let mut metadata = Table::new();
+            metadata.set_implicit(true);
+            Item::Table(metadata)
+        })
+        .as_table_like_mut()
+        .ok_or_else(|| eyre!("`package.metadata` is not a table"))?;
+
+    let mut riff = Table::new();
+    riff.set_position(Some(end_position));
+    riff.decor_mut()
+        .set_prefix("\n# Declare external dependencies riff can't infer from your dependencies\n");
+    riff.insert("build-inputs", Item::Value(toml_edit::Array::new().into()));
+    riff.insert(
+        "runtime-inputs",
+        Item::Value(toml_edit::Array::new().into()),
+    );
+    if let Some(mut key) = riff.key_mut("build-inputs") {
+        key.leaf_decor_mut()
+            .set_prefix("# Nix packages needed to build, for example \"openssl\"\n");
+    }
+    if let Some(mut key) = riff.key_mut("runtime-inputs") {
+        key.leaf_decor_mut()
+            .set_prefix("# Nix packages to add to `LD_LIBRARY_PATH`, for example \"libGL\"\n");
+    }
+
+    let mut environment_variables = Table::new();
+    environment_variables.set_position(Some(end_position + 1));
+    environment_variables.decor_mut().set_prefix(
+        "\n# Environment variables to set in the shell, for example:\n# HI = \"BYE\"\n",
+    );
+    riff.insert("environment-variables", Item::Table(environment_variables));
+
+    metadata.insert("riff", Item::Table(riff));
+
+    Ok(Some(document.to_string()))
+}
+
+fn last_position(table: &Table) -> isize {
+    table
+        .iter()
+        .filter_map(|(_, item)| item.as_table())
+        .map(last_position)
+        .chain(table.position())
+        .max()
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::scaffold_riff_metadata;
+
+    #[test]
+    fn scaffold_preserves_formatting() -> eyre::Result<()> {
+        let manifest = r#"[package]
+name    = "riff-test" # a comment
+version = "0.1.0"
+
+[dependencies]
+openssl = "0.10"
+"#;
+        let scaffolded = scaffold_riff_metadata(manifest)?.expect("should scaffold");
+        assert!(scaffolded.starts_with(manifest));
+        assert!(scaffolded.contains("[package.metadata.riff]"));
+        assert!(scaffolded.contains("build-inputs = []"));
+        assert!(scaffolded.contains("[package.metadata.riff.environment-variables]"));
+
+        let parsed: toml_edit::DocumentMut = scaffolded.parse()?;
+        assert!(parsed["package"]["metadata"]["riff"]["runtime-inputs"].is_array());
+        Ok(())
+    }
+
+    #[test]
+    fn scaffold_is_noop_when_present() -> eyre::Result<()> {
+        let manifest = r#"[package]
+name = "riff-test"
+version = "0.1.0"
+
+[package.metadata.riff]
+build-inputs = [ "openssl" ]
+"#;
+        assert!(scaffold_riff_metadata(manifest)?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn scaffold_requires_package() {
+        assert!(scaffold_riff_metadata("[workspace]\nmembers = []\n").is_err());
+    }
+}
@@ -1,12 +1,38 @@
+mod cache;
+mod check;
+mod completions;
+mod direnv;
+mod doctor;
+mod environment_args;
+mod explain;
+mod generate_args;
+mod init;
 mod print_dev_env;
+mod print_flake;
+mod registry_args;
 mod run;
 mod shell;
+mod telemetry;
 
 use clap::Subcommand;
 
+use environment_args::EnvironmentArgs;
+use generate_args::GenerateArgs;
+use registry_args::RegistryArgs;
+
 #[derive(Debug, Subcommand)]
 pub enum Commands {
     Shell(shell::Shell),
     Run(run::Run),
+    #[clap(visible_alias = "env")]
     PrintDevEnv(print_dev_env::PrintDevEnv),
+    Check(check::Check),
+    PrintFlake(print_flake::PrintFlake),
+    Direnv(direnv::Direnv),
+    Explain(explain::Explain),
+    Init(init::Init),
+    Doctor(doctor::Doctor),
+    Cache(cache::Cache),
+    Completions(completions::Completions),
+    Telemetry(telemetry::Telemetry),
 }
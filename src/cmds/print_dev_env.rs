@@ -1,87 +1,107 @@
-//! The `run` subcommand.
+//! The `print-dev-env` subcommand.
 
-use std::{path::PathBuf, process::Stdio};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
 
 use clap::Args;
 use eyre::WrapErr;
-use owo_colors::OwoColorize;
+use tempfile::NamedTempFile;
 use tokio::process::Command;
 
+use super::GenerateArgs;
 use crate::flake_generator;
+use crate::spawn_error::{SpawnError, Tool};
 
 /// print shell code that can be sourced by bash to reproduce the riff environment
 ///
 /// For example, run `cargo build` inside riff:
 ///
 ///     $ eval $(riff print-dev-env)
+///
+/// Or write it to a file once, in CI, and source that in each step:
+///
+///     $ riff env --out riff-env.sh
 #[derive(Debug, Args)]
 pub struct PrintDevEnv {
-    /// The root directory of the project
-    #[clap(long, value_parser)]
-    project_dir: Option<PathBuf>,
-    #[clap(from_global)]
-    disable_telemetry: bool,
-    #[clap(from_global)]
-    offline: bool,
+    #[clap(flatten)]
+    generate: GenerateArgs,
     #[clap(long)]
     json: bool,
+    /// Write the shell code to this file instead of stdout
+    #[clap(long, value_name = "FILE")]
+    out: Option<PathBuf>,
 }
 
 impl PrintDevEnv {
     pub async fn cmd(&self) -> color_eyre::Result<Option<i32>> {
-        let flake_dir = flake_generator::generate_flake_from_project_dir(
-            self.project_dir.clone(),
-            self.offline,
-            self.disable_telemetry,
-        )
-        .await?;
+        let flake_dir = flake_generator::generate_flake(&self.generate.options()).await?;
 
         let mut nix_print_dev_env_command = Command::new("nix");
         nix_print_dev_env_command
             .arg("print-dev-env")
             .args(["--extra-experimental-features", "flakes nix-command"])
             .arg("-L")
-            .arg(format!("path://{}", flake_dir.path().to_str().unwrap()))
+            .arg(flake_dir.installable(self.generate.package.as_deref())?)
             .stdin(Stdio::inherit())
-            .stdout(Stdio::inherit())
             .stderr(Stdio::inherit());
+        // Written next to `--out` and renamed over it once nix succeeds, so a failure never leaves
+        // a partial script behind to be sourced
+        let out_file = match &self.out {
+            Some(out) => {
+                let out_dir = match out.parent() {
+                    Some(parent) if !parent.as_os_str().is_empty() => parent,
+                    _ => Path::new("."),
+                };
+                let out_file = NamedTempFile::new_in(out_dir).wrap_err_with(|| {
+                    format!("Unable to create a file in `{}`", out_dir.display())
+                })?;
+                // Temporary files are only readable by their owner, unlike the file it replaces
+                #[cfg(unix)]
+                {
+                    use std::os::unix::fs::PermissionsExt;
+                    out_file
+                        .as_file()
+                        .set_permissions(std::fs::Permissions::from_mode(0o644))?;
+                }
+                nix_print_dev_env_command.stdout(out_file.reopen()?);
+                Some(out_file)
+            }
+            None => {
+                nix_print_dev_env_command.stdout(Stdio::inherit());
+                None
+            }
+        };
 
         if self.json {
             nix_print_dev_env_command.arg("--json");
         }
 
-        // TODO(@hoverbear): Try to enable this somehow. Right now since we don't keep the lock
-        // in a consistent place, we can't reliably pick up a lock generated in online mode.
-        //
-        // If we stored the generated flake/lock in a consistent place this could be enabled.
-        //
-        // if self.offline {
-        //     nix_develop_command.arg("--offline");
-        // }
+        if self.generate.offline && flake_dir.is_locked() {
+            nix_print_dev_env_command.arg("--offline");
+        }
 
         tracing::trace!(command = ?nix_print_dev_env_command.as_std(), "Running");
-        let nix_print_dev_env_exit = match nix_print_dev_env_command
-            .spawn()
-            .wrap_err("Failed to spawn `nix print-dev-env`")?
-            .wait_with_output()
-            .await
-        {
-            Ok(nix_print_dev_env_exit) => nix_print_dev_env_exit,
-            Err(err) => {
-                let err_msg = format!(
-                    "\
-                    Could not execute `{nix_print_dev_env}`. Is `{nix}` installed?\n\n\
-                    Get instructions for installing Nix: {nix_install_url}\
-                    ",
-                    nix_print_dev_env = "nix print-dev-env".cyan(),
-                    nix = "nix".cyan(),
-                    nix_install_url = "https://nixos.org/download.html".blue().underline(),
-                );
-                eprintln!("{err_msg}\n\nUnderlying error:\n{err}", err = err.red());
-                std::process::exit(1);
-            }
-        };
+        let nix_print_dev_env_exit = match nix_print_dev_env_command.spawn() {
+            Ok(child) => child.wait_with_output().await,
+            Err(err) => Err(err),
+        }
+        .map_err(|err| SpawnError::new("nix print-dev-env", Tool::Nix, err))?;
+
+        if !nix_print_dev_env_exit.status.success() {
+            crate::nix_dev_env::warn_if_oom_killed(
+                "nix print-dev-env",
+                &nix_print_dev_env_exit.status,
+                None,
+            );
+            crate::nix_dev_env::warn_if_nix_too_old("nix print-dev-env").await;
+        } else if let (Some(out), Some(out_file)) = (&self.out, out_file) {
+            out_file
+                .persist(out)
+                .wrap_err_with(|| format!("Unable to write `{}`", out.display()))?;
+        }
 
-        Ok(nix_print_dev_env_exit.status.code())
+        Ok(crate::nix_dev_env::exit_code(
+            &nix_print_dev_env_exit.status,
+        ))
     }
 }
@@ -0,0 +1,56 @@
+//! The `print-flake` subcommand.
+
+use std::io::Write;
+
+use atty::Stream;
+use clap::Args;
+use eyre::WrapErr;
+
+use super::GenerateArgs;
+use crate::color::Colorize;
+use crate::flake_generator;
+
+/// Print the `flake.nix` riff generates for your project
+///
+/// For example, check it in alongside your project:
+///
+///     $ riff print-flake > flake.nix
+#[derive(Debug, Args)]
+pub struct PrintFlake {
+    #[clap(flatten)]
+    generate: GenerateArgs,
+    /// Also print the generated `flake.lock`
+    #[clap(long)]
+    flake_lock: bool,
+}
+
+impl PrintFlake {
+    pub async fn cmd(&self) -> color_eyre::Result<Option<i32>> {
+        let flake_dir = flake_generator::generate_flake(&self.generate.options()).await?;
+
+        let mut files = vec!["flake.nix"];
+        if self.flake_lock {
+            files.push("flake.lock");
+        }
+
+        let is_tty = atty::is(Stream::Stdout);
+        let mut stdout = std::io::stdout().lock();
+        for (index, file) in files.into_iter().enumerate() {
+            let contents = tokio::fs::read_to_string(flake_dir.path().join(file))
+                .await
+                .wrap_err_with(|| format!("Unable to read generated {file}"))?;
+            if index != 0 {
+                writeln!(stdout)?;
+            }
+            if is_tty {
+                writeln!(stdout, "{}", format!("==> {file} <==").bold())?;
+            }
+            write!(stdout, "{contents}")?;
+            if !contents.ends_with('\n') {
+                writeln!(stdout)?;
+            }
+        }
+
+        Ok(Some(0))
+    }
+}
@@ -0,0 +1,67 @@
+//! The global arguments which control how the dependency registry is loaded.
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use clap::Args;
+
+use crate::dependency_registry::{DependencyRegistryOptions, RegistryLocation};
+
+#[derive(Debug, Args, Clone, Default)]
+pub struct RegistryArgs {
+    #[clap(from_global)]
+    pub(crate) registry: Vec<RegistryLocation>,
+    #[clap(from_global)]
+    pub(crate) registry_mirror: Vec<String>,
+    #[clap(from_global)]
+    pub(crate) no_verify: bool,
+    #[clap(from_global)]
+    pub(crate) registry_ttl: Option<u64>,
+    #[clap(from_global)]
+    pub(crate) refresh: bool,
+    #[clap(from_global)]
+    pub(crate) no_refresh: bool,
+    #[clap(from_global)]
+    pub(crate) no_fallback: bool,
+    #[clap(from_global)]
+    pub(crate) registry_proxy: Option<String>,
+    #[clap(from_global)]
+    pub(crate) registry_timeout: Option<u64>,
+    #[clap(from_global)]
+    pub(crate) cache_dir: Option<PathBuf>,
+    #[clap(from_global)]
+    pub(crate) warn_stale: bool,
+}
+
+impl RegistryArgs {
+    /// The options set on the command line, which override those in the configuration file
+    pub(crate) fn options(&self) -> DependencyRegistryOptions {
+        let mut options = DependencyRegistryOptions::new()
+            .no_verify(self.no_verify)
+            .refresh(self.refresh)
+            .no_refresh(self.no_refresh)
+            .no_fallback(self.no_fallback);
+        for registry in &self.registry {
+            options = options.registry(registry.clone());
+        }
+        for mirror in &self.registry_mirror {
+            options = options.mirror(mirror);
+        }
+        if let Some(registry_proxy) = &self.registry_proxy {
+            options = options.proxy(registry_proxy);
+        }
+        if let Some(cache_dir) = &self.cache_dir {
+            options = options.cache_dir(cache_dir);
+        }
+        if self.warn_stale {
+            options = options.warn_stale(true);
+        }
+        if let Some(registry_timeout) = self.registry_timeout {
+            options = options.timeout(Duration::from_secs(registry_timeout));
+        }
+        if let Some(registry_ttl) = self.registry_ttl {
+            options = options.ttl(Duration::from_secs(registry_ttl));
+        }
+        options
+    }
+}
@@ -1,12 +1,28 @@
 //! The `run` subcommand.
 
-use std::path::PathBuf;
+use std::path::Path;
+use std::time::Duration;
 
 use clap::Args;
 use eyre::WrapErr;
-use owo_colors::OwoColorize;
+use notify::{RecursiveMode, Watcher};
+use tokio::process::Child;
+use tokio::sync::mpsc::UnboundedReceiver;
 
-use crate::flake_generator;
+use super::{EnvironmentArgs, GenerateArgs};
+use crate::color::Colorize;
+use crate::flake_generator::FlakeDir;
+use crate::nix_dev_env::EnvironmentOptions;
+
+/// How long `--watch` waits for changes to stop before restarting, so saving both `Cargo.toml`
+/// and `Cargo.lock` restarts once
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(300);
+
+/// The files `--watch` restarts the command when they change
+const WATCHED_FILES: [&str; 2] = ["Cargo.toml", "Cargo.lock"];
+
+/// The exit code when the command runs longer than `--timeout`, like `timeout(1)`
+const TIMEOUT_EXIT_CODE: i32 = 124;
 
 /// Run a command with your project's dependencies
 ///
@@ -17,41 +33,111 @@ use crate::flake_generator;
 /// Run cargo check and cargo build at the same time:
 ///
 ///     $ riff run -- sh -c 'cargo check && cargo build'
+///
+/// Exits with the command's exit code, or 128 plus the signal number if it was killed by a signal,
+/// or 124 if it ran longer than `--timeout`.
 #[derive(Debug, Args)]
 pub struct Run {
-    /// The root directory of the project
-    #[clap(long, value_parser)]
-    project_dir: Option<PathBuf>,
+    #[clap(flatten)]
+    generate: GenerateArgs,
+    #[clap(flatten)]
+    environment: EnvironmentArgs,
+    /// Run the command again, with a regenerated environment, whenever the workspace's
+    /// `Cargo.toml` or `Cargo.lock` changes
+    ///
+    /// A command still running is stopped first. Press Ctrl+C to stop watching.
+    #[clap(long, conflicts_with = "dry_run")]
+    watch: bool,
+    /// Stop the command, and everything it started, if it runs longer than this
+    ///
+    /// The command is sent `SIGTERM`, then `SIGKILL` if it hasn't exited 5 seconds later. It runs
+    /// in its own process group to be stopped along with its children, so it can't read from the
+    /// terminal.
+    #[clap(long, value_name = "SECONDS", conflicts_with = "watch")]
+    timeout: Option<u64>,
     /// The command to run with your project's dependencies
     #[clap(required = true)]
-    pub(crate) command: Vec<String>,
-    #[clap(from_global)]
-    disable_telemetry: bool,
-    #[clap(from_global)]
-    offline: bool,
-    // TODO(@cole-h): support additional nix develop args?
+    pub command: Vec<String>,
 }
 
 impl Run {
     pub async fn cmd(&self) -> color_eyre::Result<Option<i32>> {
-        let flake_dir = flake_generator::generate_flake_from_project_dir(
-            self.project_dir.clone(),
-            self.offline,
-            self.disable_telemetry,
-        )
-        .await?;
+        let environment = self.environment.options()?;
+        if self.watch {
+            return self.watch(&environment).await;
+        }
+        let flake_dir = self.generate_flake().await?;
+
+        // `flake_dir` is only dropped, removing a temporary flake, once the command has exited
+        let code = self.run(&flake_dir, &environment).await;
+        self.environment.finish(flake_dir);
+        code
+    }
+
+    /// Run the command in the environment of the flake in `flake_dir` until it exits, or print
+    /// how with `--dry-run`
+    async fn run(
+        &self,
+        flake_dir: &FlakeDir,
+        environment: &EnvironmentOptions,
+    ) -> color_eyre::Result<Option<i32>> {
+        if self.environment.dry_run {
+            self.environment
+                .print_dry_run(flake_dir, &self.generate, &self.command)?;
+            return Ok(Some(0));
+        }
+
+        let mut child = self.spawn(flake_dir, environment).await?;
 
-        let dev_env = crate::nix_dev_env::get_nix_dev_env(flake_dir.path()).await?;
+        let wait = crate::nix_dev_env::wait_forwarding_signals(&mut child);
+        let status = match self.timeout {
+            Some(timeout) => match tokio::time::timeout(Duration::from_secs(timeout), wait).await {
+                Ok(status) => status?,
+                Err(_) => {
+                    eprintln!(
+                        "{warning} The command ran longer than `{timeout_flag}`, stopping it",
+                        warning = "!".yellow(),
+                        timeout_flag = format!("--timeout {timeout}").cyan(),
+                    );
+                    crate::nix_dev_env::stop_child(&mut child).await?;
+                    return Ok(Some(TIMEOUT_EXIT_CODE));
+                }
+            },
+            None => wait.await?,
+        };
+        Ok(crate::nix_dev_env::exit_code(&status))
+    }
+
+    /// Generate the flake
+    async fn generate_flake(&self) -> color_eyre::Result<FlakeDir> {
+        self.environment
+            .generate_flake(self.generate.options())
+            .await
+    }
+
+    /// Start the command in the environment of the flake in `flake_dir`
+    async fn spawn(
+        &self,
+        flake_dir: &FlakeDir,
+        environment: &EnvironmentOptions,
+    ) -> color_eyre::Result<Child> {
+        let dev_env = self.environment.dev_env(flake_dir, &self.generate).await?;
 
         let command_name = &self.command[0];
 
-        let mut command = crate::nix_dev_env::run_in_dev_env(&dev_env, command_name).await?;
+        let mut command =
+            crate::nix_dev_env::run_in_dev_env(&dev_env, command_name, environment).await?;
 
         command.args(&self.command[1..]);
+        // In its own process group, `--timeout` stops everything the command started
+        #[cfg(unix)]
+        if self.timeout.is_some() {
+            command.process_group(0);
+        }
 
-        Ok(command
+        command
             .spawn()
-            .map_err(|err| {
+            .inspect_err(|err| {
                 if err.kind() == std::io::ErrorKind::NotFound {
                     eprintln!(
                         "The command you attempted to run was not found.
@@ -61,21 +147,148 @@ Try running it in a shell; for example:
                             format!("riff run -- sh -c '{}'", self.command.join(" ")).cyan(),
                     );
                 };
-                err
             })
-            .wrap_err(format!("Cannot run the command `{command_name}`"))?
-            .wait_with_output()
-            .await?
-            .status
-            .code())
+            .wrap_err(format!("Cannot run the command `{command_name}`"))
     }
+
+    /// Run the command, and run it again whenever [`WATCHED_FILES`] change, until Ctrl+C
+    async fn watch(&self, environment: &EnvironmentOptions) -> color_eyre::Result<Option<i32>> {
+        let project_dir = match &self.generate.project_dir {
+            Some(dir) => dir.clone(),
+            None => std::env::current_dir().wrap_err("Current working directory was invalid")?,
+        };
+        let project_dir = crate::cargo_metadata::find_project_dir(&project_dir).await?;
+
+        let (sender, mut changes) = tokio::sync::mpsc::unbounded_channel();
+        let mut watcher =
+            notify::recommended_watcher(move |event: notify::Result<notify::Event>| match event {
+                Ok(event) if event.paths.iter().any(|path| is_watched(path)) => {
+                    sender.send(()).ok();
+                }
+                Ok(_) => (),
+                Err(err) => tracing::debug!(%err, "Error watching the project"),
+            })
+            .wrap_err("Unable to watch the project for changes")?;
+        // The directory is watched, rather than the files, to see editors replacing them.
+        watcher
+            .watch(&project_dir, RecursiveMode::NonRecursive)
+            .wrap_err_with(|| format!("Unable to watch `{}`", project_dir.display()))?;
+        eprintln!(
+            "Watching `{cargo_toml}` and `{cargo_lock}` in `{project_dir}`, press Ctrl+C to stop",
+            cargo_toml = "Cargo.toml".cyan(),
+            cargo_lock = "Cargo.lock".cyan(),
+            project_dir = project_dir.display().to_string().green(),
+        );
+
+        let mut contents = watched_contents(&project_dir).await;
+        loop {
+            let mut running = match self.generate_flake().await {
+                Ok(flake_dir) => match self.spawn(&flake_dir, environment).await {
+                    Ok(child) => Some((flake_dir, child)),
+                    Err(err) => {
+                        eprintln!("{err:?}");
+                        None
+                    }
+                },
+                Err(err) => {
+                    eprintln!("{err:?}");
+                    None
+                }
+            };
+            if running.is_none() {
+                eprintln!("Waiting for changes");
+            }
+
+            loop {
+                let exited = async {
+                    match &mut running {
+                        Some((_, child)) => child.wait().await,
+                        None => std::future::pending().await,
+                    }
+                };
+                tokio::select! {
+                    status = exited => {
+                        let status = status.wrap_err("Unable to wait for the command")?;
+                        eprintln!(
+                            "The command exited with code {code}, waiting for changes",
+                            code = crate::nix_dev_env::exit_code(&status)
+                                .map(|code| code.to_string())
+                                .unwrap_or_else(|| "unknown".to_string())
+                                .cyan(),
+                        );
+                        if let Some((flake_dir, _)) = running.take() {
+                            self.environment.finish(flake_dir);
+                        }
+                    }
+                    _ = changes.recv() => {
+                        debounce(&mut changes).await;
+                        let changed = watched_contents(&project_dir).await;
+                        if changed != contents {
+                            contents = changed;
+                            break;
+                        }
+                        tracing::debug!("Watched files were touched but not changed");
+                    }
+                    _ = tokio::signal::ctrl_c() => {
+                        if let Some((flake_dir, mut child)) = running {
+                            crate::nix_dev_env::stop_child(&mut child).await?;
+                            self.environment.finish(flake_dir);
+                        }
+                        return Ok(Some(130));
+                    }
+                }
+            }
+
+            eprintln!(
+                "{check} The project changed, restarting",
+                check = "✓".green(),
+            );
+            if let Some((flake_dir, mut child)) = running {
+                crate::nix_dev_env::stop_child(&mut child).await?;
+                self.environment.finish(flake_dir);
+            }
+        }
+    }
+}
+
+/// If `path` is one of [`WATCHED_FILES`]
+fn is_watched(path: &Path) -> bool {
+    path.file_name()
+        .map(|name| WATCHED_FILES.iter().any(|watched| name == *watched))
+        .unwrap_or(false)
+}
+
+/// Wait until no changes have been seen for [`WATCH_DEBOUNCE`]
+async fn debounce(changes: &mut UnboundedReceiver<()>) {
+    while let Ok(Some(())) = tokio::time::timeout(WATCH_DEBOUNCE, changes.recv()).await {}
+}
+
+/// The contents of [`WATCHED_FILES`] in `project_dir`, so touching them without changing them
+/// doesn't restart the command
+async fn watched_contents(project_dir: &Path) -> Vec<Option<Vec<u8>>> {
+    let mut contents = Vec::new();
+    for file in WATCHED_FILES {
+        contents.push(tokio::fs::read(project_dir.join(file)).await.ok());
+    }
+    contents
 }
 
 #[cfg(test)]
 mod tests {
     use tempfile::TempDir;
 
-    use super::Run;
+    use std::path::Path;
+
+    use super::{is_watched, EnvironmentArgs, GenerateArgs, Run};
+    use crate::cmds::RegistryArgs;
+
+    #[test]
+    fn run_watches_manifest_and_lockfile() {
+        assert!(is_watched(Path::new("/project/Cargo.toml")));
+        assert!(is_watched(Path::new("/project/Cargo.lock")));
+        assert!(!is_watched(Path::new("/project/Cargo.toml.swp")));
+        assert!(!is_watched(Path::new("/project/src/main.rs")));
+    }
 
     // We can't run this test by default because it calls Nix. Calling Nix inside Nix doesn't appear
     // to work very well (at least, for this use case).
@@ -83,7 +296,6 @@ mod tests {
     #[ignore]
     fn run_succeeds() {
         let cache_dir = TempDir::new().unwrap();
-        std::env::set_var("XDG_CACHE_HOME", cache_dir.path());
         let temp_dir = TempDir::new().unwrap();
         std::fs::write(temp_dir.path().join("lib.rs"), "fn main () {}").unwrap();
         std::fs::write(
@@ -104,13 +316,75 @@ path = "lib.rs"
         .unwrap();
 
         let run = Run {
-            project_dir: Some(temp_dir.path().to_owned()),
+            generate: GenerateArgs {
+                project_dir: Some(temp_dir.path().to_owned()),
+                offline: true,
+                disable_telemetry: true,
+                registry: RegistryArgs {
+                    cache_dir: Some(cache_dir.path().to_owned()),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            environment: Default::default(),
+            watch: false,
+            timeout: None,
             command: ["sh", "-c", "exit 6"]
                 .into_iter()
                 .map(String::from)
                 .collect(),
-            offline: true,
-            disable_telemetry: true,
+        };
+
+        let run_cmd = tokio_test::task::spawn(run.cmd());
+        let run_cmd = tokio_test::block_on(run_cmd);
+        assert_eq!(run_cmd.unwrap(), Some(6));
+    }
+
+    // See `run_succeeds`; also checks the exit code survives `--pure`.
+    #[test]
+    #[ignore]
+    fn run_pure_succeeds() {
+        let cache_dir = TempDir::new().unwrap();
+        let temp_dir = TempDir::new().unwrap();
+        std::fs::write(temp_dir.path().join("lib.rs"), "fn main () {}").unwrap();
+        std::fs::write(
+            temp_dir.path().join("Cargo.toml"),
+            r#"
+[package]
+name = "riff-test"
+version = "0.1.0"
+edition = "2021"
+
+[lib]
+name = "riff_test"
+path = "lib.rs"
+
+[dependencies]
+        "#,
+        )
+        .unwrap();
+
+        let run = Run {
+            generate: GenerateArgs {
+                project_dir: Some(temp_dir.path().to_owned()),
+                offline: true,
+                disable_telemetry: true,
+                registry: RegistryArgs {
+                    cache_dir: Some(cache_dir.path().to_owned()),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            environment: EnvironmentArgs {
+                pure: true,
+                ..Default::default()
+            },
+            watch: false,
+            timeout: None,
+            command: ["bash", "-c", "test -n \"$HOME\" && exit 6"]
+                .into_iter()
+                .map(String::from)
+                .collect(),
         };
 
         let run_cmd = tokio_test::task::spawn(run.cmd());
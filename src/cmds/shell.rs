@@ -1,50 +1,99 @@
 //! The `shell` subcommand.
-use std::path::PathBuf;
+use std::path::Path;
 
 use clap::Args;
-use eyre::WrapErr;
+use eyre::{eyre, WrapErr};
 
-use crate::flake_generator;
+use super::{EnvironmentArgs, GenerateArgs};
+use crate::config::Config;
+use crate::flake_generator::FlakeDir;
+use crate::nix_dev_env::EnvironmentOptions;
 
 /// Start a development shell
 #[derive(Debug, Args, Clone)]
 pub struct Shell {
-    /// The root directory of the project
-    #[clap(long, value_parser)]
-    project_dir: Option<PathBuf>,
-    #[clap(from_global)]
-    disable_telemetry: bool,
-    #[clap(from_global)]
-    offline: bool,
+    #[clap(flatten)]
+    generate: GenerateArgs,
+    #[clap(flatten)]
+    environment: EnvironmentArgs,
+    /// The shell to start, a name looked up in the environment's `PATH` or a path [default: the
+    /// `shell` key of the configuration file, or `$SHELL`]
+    #[clap(long, value_name = "PROGRAM")]
+    shell: Option<String>,
 }
 
 impl Shell {
     pub async fn cmd(self) -> color_eyre::Result<Option<i32>> {
-        let flake_dir = flake_generator::generate_flake_from_project_dir(
-            self.project_dir,
-            self.offline,
-            self.disable_telemetry,
-        )
-        .await?;
+        let environment = self.environment.options()?;
+        let shell = self.program().await?;
+        let flake_dir = self
+            .environment
+            .generate_flake(self.generate.options())
+            .await?;
+
+        // `flake_dir` is only dropped, removing a temporary flake, once the shell has exited
+        let code = self.enter(&flake_dir, &environment, &shell).await;
+        self.environment.finish(flake_dir);
+        code
+    }
 
-        let dev_env = crate::nix_dev_env::get_nix_dev_env(flake_dir.path()).await?;
+    /// Start the shell in the environment of the flake in `flake_dir`, or print how with
+    /// `--dry-run`
+    async fn enter(
+        &self,
+        flake_dir: &FlakeDir,
+        environment: &EnvironmentOptions,
+        shell: &str,
+    ) -> color_eyre::Result<Option<i32>> {
+        if self.environment.dry_run {
+            self.environment
+                .print_dry_run(flake_dir, &self.generate, &[shell.to_owned()])?;
+            return Ok(Some(0));
+        }
 
-        let shell = crate::nix_dev_env::get_shell().await?;
+        let dev_env = self.environment.dev_env(flake_dir, &self.generate).await?;
 
-        Ok(crate::nix_dev_env::run_in_dev_env(&dev_env, &shell)
+        let mut child = crate::nix_dev_env::run_in_dev_env(&dev_env, shell, environment)
             .await?
             .spawn()
-            .wrap_err(format!("Cannot run the shell `{shell}`"))?
-            .wait_with_output()
-            .await?
-            .status
-            .code())
+            .wrap_err(format!("Cannot run the shell `{shell}`"))?;
+
+        let status = crate::nix_dev_env::wait_forwarding_signals(&mut child).await?;
+        Ok(crate::nix_dev_env::exit_code(&status))
+    }
+
+    /// The shell to start, from `--shell`, the configuration file, or the user's shell
+    ///
+    /// A name is looked up once the environment is loaded, as it may be one of the environment's
+    /// inputs, but a path is checked before generating the flake.
+    async fn program(&self) -> color_eyre::Result<String> {
+        let shell = match &self.shell {
+            Some(shell) => shell.clone(),
+            None => match Config::load().await?.shell {
+                Some(shell) => shell,
+                None => return crate::nix_dev_env::get_shell().await,
+            },
+        };
+        check_shell(&shell)?;
+        Ok(shell)
+    }
+}
+
+/// Check the shell `shell` names is a file, if it is a path rather than a name
+fn check_shell(shell: &str) -> color_eyre::Result<()> {
+    if shell.is_empty() {
+        return Err(eyre!("The shell to start cannot be empty"));
     }
+    if shell.contains('/') && !Path::new(shell).is_file() {
+        return Err(eyre!("The shell `{shell}` does not exist"));
+    }
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::cmds::RegistryArgs;
     use tempfile::TempDir;
     use tokio::fs::write;
 
@@ -57,7 +106,6 @@ mod tests {
     #[ignore]
     async fn shell_succeeds() -> eyre::Result<()> {
         let cache_dir = TempDir::new()?;
-        std::env::set_var("XDG_CACHE_HOME", cache_dir.path());
         let temp_dir = TempDir::new()?;
         write(temp_dir.path().join("lib.rs"), "fn main () {}").await?;
         write(
@@ -81,13 +129,31 @@ shellHook = "exit 6"
         .await?;
 
         let shell = Shell {
-            project_dir: Some(temp_dir.path().to_owned()),
-            offline: true,
-            disable_telemetry: true,
+            generate: GenerateArgs {
+                project_dir: Some(temp_dir.path().to_owned()),
+                offline: true,
+                disable_telemetry: true,
+                registry: RegistryArgs {
+                    cache_dir: Some(cache_dir.path().to_owned()),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            environment: Default::default(),
+            shell: None,
         };
 
         let shell_cmd = shell.cmd().await?;
         assert_eq!(shell_cmd, Some(6));
         Ok(())
     }
+
+    #[test]
+    fn check_shell_paths() -> eyre::Result<()> {
+        check_shell("fish")?;
+        check_shell("/bin/sh")?;
+        assert!(check_shell("/nonexistent/bin/fish").is_err());
+        assert!(check_shell("").is_err());
+        Ok(())
+    }
 }
@@ -0,0 +1,68 @@
+//! The `telemetry` subcommand.
+
+use clap::{Args, Subcommand};
+
+use crate::color::Colorize;
+use crate::telemetry::{TelemetryLevel, TELEMETRY_HEADER_NAME};
+
+/// Inspect the telemetry riff sends
+#[derive(Debug, Args)]
+pub struct Telemetry {
+    #[clap(subcommand)]
+    command: TelemetryCommands,
+}
+
+#[derive(Debug, Subcommand)]
+enum TelemetryCommands {
+    Show(Show),
+}
+
+impl Telemetry {
+    pub async fn cmd(&self) -> color_eyre::Result<Option<i32>> {
+        match &self.command {
+            TelemetryCommands::Show(show) => show.cmd().await,
+        }
+    }
+}
+
+/// Print the telemetry header exactly as it would be sent, without sending it
+///
+/// Commands which detect a project also send the languages it uses, unless `--telemetry-level`
+/// is `minimal`.
+#[derive(Debug, Args)]
+struct Show {
+    #[clap(from_global)]
+    disable_telemetry: bool,
+    #[clap(from_global)]
+    telemetry_level: TelemetryLevel,
+    #[clap(from_global)]
+    offline: bool,
+}
+
+impl Show {
+    async fn cmd(&self) -> color_eyre::Result<Option<i32>> {
+        if self.disable_telemetry
+            || self.telemetry_level == TelemetryLevel::None
+            || crate::telemetry::disabled_by_env()
+        {
+            eprintln!("Telemetry is disabled, nothing is sent.");
+            return Ok(Some(0));
+        }
+
+        let telemetry = crate::telemetry::Telemetry::new().await;
+        if self.offline {
+            eprintln!(
+                "Nothing is sent with `{offline}`.",
+                offline = "--offline".cyan()
+            );
+        } else {
+            eprintln!(
+                "Sent to {url} at the `{level}` level:",
+                url = telemetry.url().blue().underline(),
+                level = telemetry.level().cyan(),
+            );
+        }
+        println!("{TELEMETRY_HEADER_NAME}: {}", telemetry.as_header_data()?);
+        Ok(Some(0))
+    }
+}
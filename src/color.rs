@@ -0,0 +1,118 @@
+//! Whether riff's output is colored, set with `--color`.
+//!
+//! Riff styles its output with [`Colorize`], which works like [`owo_colors::OwoColorize`] but
+//! writes plain text when color is turned off.
+
+use std::fmt::{self, Display};
+use std::sync::OnceLock;
+
+use atty::Stream;
+use clap::ValueEnum;
+use owo_colors::{OwoColorize, Style};
+
+static ENABLED: OnceLock<bool> = OnceLock::new();
+
+/// When to color the output, the values of `--color`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum ColorChoice {
+    /// Color the output if stderr is a terminal and `NO_COLOR` isn't set
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    fn enabled(self) -> bool {
+        match self {
+            ColorChoice::Auto => no_color_unset() && atty::is(Stream::Stderr),
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+        }
+    }
+}
+
+/// If `NO_COLOR` is unset or empty, see <https://no-color.org>
+fn no_color_unset() -> bool {
+    std::env::var_os("NO_COLOR").is_none_or(|no_color| no_color.is_empty())
+}
+
+/// Set when to color the output, before anything is printed
+///
+/// Without this, [`ColorChoice::Auto`] is used.
+pub fn init(choice: ColorChoice) {
+    if ENABLED.set(choice.enabled()).is_err() {
+        tracing::debug!(?choice, "The color choice was already set");
+    }
+}
+
+/// If output should be colored, including the progress spinners
+pub fn enabled() -> bool {
+    *ENABLED.get_or_init(|| ColorChoice::Auto.enabled())
+}
+
+/// Styles for anything displayed, which are only written if [`enabled`]
+pub trait Colorize: Display {
+    fn bold(&self) -> Styled<'_, Self> {
+        Styled::new(self, Style::new().bold())
+    }
+
+    fn underline(&self) -> Styled<'_, Self> {
+        Styled::new(self, Style::new().underline())
+    }
+
+    fn red(&self) -> Styled<'_, Self> {
+        Styled::new(self, Style::new().red())
+    }
+
+    fn green(&self) -> Styled<'_, Self> {
+        Styled::new(self, Style::new().green())
+    }
+
+    fn yellow(&self) -> Styled<'_, Self> {
+        Styled::new(self, Style::new().yellow())
+    }
+
+    fn blue(&self) -> Styled<'_, Self> {
+        Styled::new(self, Style::new().blue())
+    }
+
+    fn cyan(&self) -> Styled<'_, Self> {
+        Styled::new(self, Style::new().cyan())
+    }
+}
+
+impl<T: Display + ?Sized> Colorize for T {}
+
+/// A value displayed with a [`Style`], if [`enabled`]
+pub struct Styled<'a, T: ?Sized> {
+    value: &'a T,
+    style: Style,
+}
+
+impl<'a, T: ?Sized> Styled<'a, T> {
+    fn new(value: &'a T, style: Style) -> Self {
+        Self { value, style }
+    }
+}
+
+impl<T: Display + ?Sized> Display for Styled<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if enabled() {
+            (&self.value).style(self.style).fmt(f)
+        } else {
+            self.value.fmt(f)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ColorChoice;
+
+    #[test]
+    fn color_choice_enabled() {
+        assert!(ColorChoice::Always.enabled());
+        assert!(!ColorChoice::Never.enabled());
+    }
+}
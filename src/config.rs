@@ -0,0 +1,212 @@
+//! The riff configuration file, `$XDG_CONFIG_HOME/riff/config.toml`.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::Duration;
+
+use eyre::WrapErr;
+use serde::de::IgnoredAny;
+use serde::Deserialize;
+use xdg::BaseDirectories;
+
+use crate::color::Colorize;
+use crate::dependency_registry::{DependencyRegistryOptions, RegistryLocation};
+use crate::RIFF_XDG_PREFIX;
+
+pub(crate) const CONFIG_PATH: &str = "config.toml";
+
+/// Settings read from the configuration file, which is optional
+///
+/// Command line flags, and the environment variables they read, take precedence over these.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct Config {
+    /// Registries layered over the public one, before any passed with `--registry`
+    #[serde(default)]
+    pub(crate) registries: Vec<RegistryLocation>,
+    /// The URLs to refresh the public registry from, tried in order
+    #[serde(default)]
+    pub(crate) registry_mirrors: Vec<String>,
+    /// The minisign public key the refreshed registry must be signed with
+    pub(crate) registry_public_key: Option<String>,
+    /// How many seconds a cached registry is used before it is refreshed
+    pub(crate) registry_ttl: Option<u64>,
+    /// Whether to warn when the registry couldn't be refreshed and the cache is stale
+    pub(crate) warn_stale: Option<bool>,
+    /// How many days old the cached registry is before `warn-stale` warns about it
+    pub(crate) registry_stale_days: Option<u64>,
+    /// The proxy to fetch the registry through
+    pub(crate) registry_proxy: Option<String>,
+    /// Whether to fail rather than use the bundled registry when there's no cached registry
+    pub(crate) no_fallback: Option<bool>,
+    /// How many seconds fetching the registry may take
+    pub(crate) registry_timeout: Option<u64>,
+    /// How many times to try fetching the refreshed registry
+    pub(crate) registry_fetch_attempts: Option<u32>,
+    /// How many milliseconds to wait before first retrying to fetch the registry
+    pub(crate) registry_retry_delay_ms: Option<u64>,
+    /// The Nixpkgs git revision or branch to use for projects which don't pin one
+    pub(crate) nixpkgs_rev: Option<String>,
+    /// Where telemetry is sent instead of Determinate Systems, unless `RIFF_TELEMETRY_URL` is set
+    pub(crate) telemetry_url: Option<String>,
+    /// The shell `riff shell` starts, unless `--shell` is passed
+    pub(crate) shell: Option<String>,
+    /// Keys riff doesn't know, which are warned about rather than failing to load the file
+    #[serde(flatten)]
+    pub(crate) unknown: BTreeMap<String, IgnoredAny>,
+}
+
+impl Config {
+    /// Load the configuration file, if there is one
+    #[tracing::instrument]
+    pub(crate) async fn load() -> color_eyre::Result<Self> {
+        let xdg_dirs = match BaseDirectories::with_prefix(RIFF_XDG_PREFIX) {
+            Ok(xdg_dirs) => xdg_dirs,
+            // Eg in a minimal container without `$HOME`, where there can't be a configuration file
+            Err(err) => {
+                tracing::debug!(%err, "No XDG base directories, not loading the configuration file");
+                return Ok(Default::default());
+            }
+        };
+        let path = match xdg_dirs.find_config_file(Path::new(CONFIG_PATH)) {
+            Some(path) => path,
+            None => return Ok(Default::default()),
+        };
+        let content = tokio::fs::read_to_string(&path)
+            .await
+            .wrap_err_with(|| format!("Unable to read `{}`", path.display()))?;
+        let config = Self::parse(&content)
+            .wrap_err_with(|| format!("Unable to parse `{}`", path.display()))?;
+        for key in config.unknown.keys() {
+            eprintln!(
+                "{warning} Ignoring the unknown key `{key}` in `{path}`",
+                warning = "!".yellow(),
+                key = key.cyan(),
+                path = path.display(),
+            );
+        }
+        tracing::debug!(path = %path.display(), ?config, "Loaded the configuration file");
+        Ok(config)
+    }
+
+    pub(crate) fn parse(content: &str) -> color_eyre::Result<Self> {
+        Ok(toml_edit::de::from_str(content)?)
+    }
+
+    /// The registry options set in the configuration file, for the command line to add to
+    pub(crate) fn registry_options(&self) -> DependencyRegistryOptions {
+        let mut options = DependencyRegistryOptions::new();
+        for registry in &self.registries {
+            options = options.registry(registry.clone());
+        }
+        for mirror in &self.registry_mirrors {
+            options = options.mirror(mirror);
+        }
+        if let Some(public_key) = &self.registry_public_key {
+            options = options.public_key(public_key);
+        }
+        if let Some(registry_ttl) = self.registry_ttl {
+            options = options.ttl(Duration::from_secs(registry_ttl));
+        }
+        if let Some(warn_stale) = self.warn_stale {
+            options = options.warn_stale(warn_stale);
+        }
+        if let Some(registry_proxy) = &self.registry_proxy {
+            options = options.proxy(registry_proxy);
+        }
+        if let Some(no_fallback) = self.no_fallback {
+            options = options.no_fallback(no_fallback);
+        }
+        if let Some(registry_timeout) = self.registry_timeout {
+            options = options.timeout(Duration::from_secs(registry_timeout));
+        }
+        if let Some(registry_fetch_attempts) = self.registry_fetch_attempts {
+            options = options.fetch_attempts(registry_fetch_attempts);
+        }
+        if let Some(registry_retry_delay_ms) = self.registry_retry_delay_ms {
+            options = options.retry_delay(Duration::from_millis(registry_retry_delay_ms));
+        }
+        if let Some(registry_stale_days) = self.registry_stale_days {
+            options = options.stale_after(Duration::from_secs(registry_stale_days * 24 * 60 * 60));
+        }
+        options
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::Config;
+    use crate::dependency_registry::{DependencyRegistryOptions, RegistryLocation};
+
+    #[test]
+    fn config_parse() -> eyre::Result<()> {
+        let config = Config::parse(
+            r#"
+registries = [ "https://registry.example.com/riff.json", "/etc/riff/registry.json" ]
+registry-mirrors = [ "https://cdn.example.com/riff.json", "https://registry.riff.determinate.systems/riff-registry.json" ]
+registry-public-key = "RWQf6LRCGA9i53mlYecO4IzT51TGPpvWucNSCh1CBM0QTaLn73Y7GFO3"
+registry-ttl = 600
+warn-stale = false
+registry-stale-days = 2
+registry-proxy = "http://proxy.example.com:8080"
+registry-timeout = 10
+no-fallback = true
+registry-fetch-attempts = 5
+registry-retry-delay-ms = 100
+nixpkgs-rev = "nixos-23.11"
+shell = "fish"
+telemetry-url = "https://telemetry.example.com/riff"
+registry-colour = "blue"
+            "#,
+        )?;
+        assert_eq!(config.nixpkgs_rev.as_deref(), Some("nixos-23.11"));
+        assert_eq!(config.shell.as_deref(), Some("fish"));
+        assert_eq!(
+            config.telemetry_url.as_deref(),
+            Some("https://telemetry.example.com/riff")
+        );
+        assert_eq!(
+            config.unknown.keys().collect::<Vec<_>>(),
+            ["registry-colour"]
+        );
+        let options = config.registry_options().overridden_by(
+            DependencyRegistryOptions::new()
+                .registry("./registry.json".parse()?)
+                .ttl(Duration::from_secs(0)),
+        );
+        assert_eq!(
+            options.registries,
+            vec![
+                RegistryLocation::Url("https://registry.example.com/riff.json".into()),
+                RegistryLocation::File("/etc/riff/registry.json".into()),
+                RegistryLocation::File("./registry.json".into()),
+            ]
+        );
+        assert_eq!(
+            options.mirrors,
+            [
+                "https://cdn.example.com/riff.json",
+                "https://registry.riff.determinate.systems/riff-registry.json",
+            ]
+        );
+        assert!(options.public_key.is_some());
+        assert_eq!(options.ttl, Some(Duration::from_secs(0)));
+        assert_eq!(options.warn_stale, Some(false));
+        assert_eq!(options.timeout, Some(Duration::from_secs(10)));
+        assert!(options.no_fallback);
+        assert_eq!(options.fetch_attempts, Some(5));
+        assert_eq!(options.retry_delay, Some(Duration::from_millis(100)));
+        assert_eq!(
+            options.proxy.as_deref(),
+            Some("http://proxy.example.com:8080")
+        );
+        assert_eq!(
+            options.stale_after,
+            Some(Duration::from_secs(2 * 24 * 60 * 60))
+        );
+        assert!(Config::parse("")?.registries.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,193 @@
+//! Reading crates published on crates.io from its sparse index, without downloading them.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use eyre::{eyre, WrapErr};
+use semver::{Version, VersionReq};
+
+/// The sparse index of crates.io, see <https://doc.rust-lang.org/cargo/reference/registry-index.html>
+pub(crate) const CRATES_IO_INDEX_URL: &str = "https://index.crates.io";
+
+/// How long fetching a crate's index file may take
+const INDEX_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A published version of a crate, one line of its index file
+#[derive(Debug, Clone, serde::Deserialize)]
+pub(crate) struct IndexVersion {
+    pub(crate) name: String,
+    pub(crate) vers: Version,
+    pub(crate) deps: Vec<IndexDependency>,
+    #[serde(default)]
+    features: HashMap<String, Vec<String>>,
+    /// The features using `dep:` or `?/` syntax, kept apart for older versions of Cargo
+    #[serde(default)]
+    features2: HashMap<String, Vec<String>>,
+    #[serde(default)]
+    pub(crate) yanked: bool,
+}
+
+impl IndexVersion {
+    /// Every feature the crate declares, Eg in `[features]` of its `Cargo.toml`
+    pub(crate) fn features(&self) -> HashMap<String, Vec<String>> {
+        self.features
+            .iter()
+            .chain(&self.features2)
+            .map(|(name, implied)| (name.clone(), implied.clone()))
+            .collect()
+    }
+}
+
+/// A dependency of an [`IndexVersion`], as declared in its `Cargo.toml`
+#[derive(Debug, Clone, serde::Deserialize)]
+pub(crate) struct IndexDependency {
+    /// The key of the dependency in `Cargo.toml`, which differs from the crate if it's renamed
+    pub(crate) name: String,
+    pub(crate) req: VersionReq,
+    #[serde(default)]
+    pub(crate) features: Vec<String>,
+    #[serde(default)]
+    pub(crate) optional: bool,
+    #[serde(default = "default_features")]
+    pub(crate) default_features: bool,
+    /// `normal`, `build` or `dev`, Cargo leaves it out for `normal` in old entries
+    #[serde(default)]
+    pub(crate) kind: Option<String>,
+    /// The crate depended on, if it's renamed
+    #[serde(default)]
+    pub(crate) package: Option<String>,
+    /// The platform it's only for, Eg `cfg(unix)` from `[target.'cfg(unix)'.dependencies]`
+    #[serde(default)]
+    pub(crate) target: Option<String>,
+}
+
+fn default_features() -> bool {
+    true
+}
+
+impl IndexDependency {
+    /// The name of the crate depended on
+    pub(crate) fn crate_name(&self) -> &str {
+        self.package.as_deref().unwrap_or(&self.name)
+    }
+
+    /// If this is a `[dev-dependencies]` entry
+    pub(crate) fn is_dev(&self) -> bool {
+        self.kind.as_deref() == Some("dev")
+    }
+
+    /// If this is a dependency when building for the target `triple`
+    pub(crate) fn is_for(&self, triple: &str) -> bool {
+        match &self.target {
+            Some(platform) => crate::cross_target::platform_matches(platform, triple),
+            None => true,
+        }
+    }
+}
+
+/// The path of the index file of the crate `name`, Eg `to/ki/tokio`
+pub(crate) fn index_path(name: &str) -> String {
+    let name = name.to_lowercase();
+    match name.len() {
+        1 => format!("1/{name}"),
+        2 => format!("2/{name}"),
+        3 => format!("3/{}/{name}", &name[..1]),
+        _ => format!("{}/{}/{name}", &name[..2], &name[2..4]),
+    }
+}
+
+/// Parse an index file, skipping any version which can't be parsed, Eg an old one with a
+/// version requirement Cargo no longer accepts
+pub(crate) fn parse_index(content: &str) -> Vec<IndexVersion> {
+    content
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .filter_map(|line| match serde_json::from_str(line) {
+            Ok(version) => Some(version),
+            Err(err) => {
+                tracing::debug!(%err, line, "Skipping a version in the crates.io index");
+                None
+            }
+        })
+        .collect()
+}
+
+/// The newest version in `versions` matching `req` which isn't yanked
+pub(crate) fn newest_matching<'a>(
+    versions: &'a [IndexVersion],
+    req: &VersionReq,
+) -> Option<&'a IndexVersion> {
+    versions
+        .iter()
+        .filter(|version| !version.yanked && req.matches(&version.vers))
+        .max_by(|a, b| a.vers.cmp(&b.vers))
+}
+
+/// The HTTP client the index is fetched with
+pub(crate) fn http_client() -> color_eyre::Result<reqwest::Client> {
+    reqwest::Client::builder()
+        .user_agent(concat!("riff/", env!("CARGO_PKG_VERSION")))
+        .timeout(INDEX_TIMEOUT)
+        .build()
+        .wrap_err("Unable to create an HTTP client for crates.io")
+}
+
+/// Fetch every published version of the crate `name` from the index
+pub(crate) async fn fetch_index(
+    http_client: &reqwest::Client,
+    name: &str,
+) -> color_eyre::Result<Vec<IndexVersion>> {
+    let url = format!("{CRATES_IO_INDEX_URL}/{}", index_path(name));
+    tracing::trace!(%url, "Fetching a crate from the crates.io index");
+    let res = http_client
+        .get(&url)
+        .send()
+        .await
+        .wrap_err_with(|| format!("Unable to fetch `{url}`"))?;
+    if res.status() == reqwest::StatusCode::NOT_FOUND {
+        return Err(eyre!("`{name}` is not published on crates.io"));
+    }
+    let content = res
+        .error_for_status()
+        .wrap_err_with(|| format!("Unable to fetch `{url}`"))?
+        .text()
+        .await
+        .wrap_err_with(|| format!("Unable to fetch `{url}`"))?;
+    Ok(parse_index(&content))
+}
+
+#[cfg(test)]
+mod tests {
+    use semver::VersionReq;
+
+    use super::{index_path, newest_matching, parse_index};
+
+    #[test]
+    fn index_paths() {
+        assert_eq!(index_path("a"), "1/a");
+        assert_eq!(index_path("cc"), "2/cc");
+        assert_eq!(index_path("syn"), "3/s/syn");
+        assert_eq!(index_path("Tokio"), "to/ki/tokio");
+    }
+
+    #[test]
+    fn index_newest_matching() -> eyre::Result<()> {
+        let versions = parse_index(
+            r#"{"name":"foo","vers":"1.0.0","deps":[],"cksum":"","features":{}}
+{"name":"foo","vers":"1.2.0","deps":[],"cksum":"","features":{},"yanked":true}
+{"name":"foo","vers":"1.1.0","deps":[{"name":"bar","req":"^0.1","features":[],"optional":false,"default_features":true,"target":null,"kind":"normal"},{"name":"winapi","req":"^0.3","features":[],"optional":false,"default_features":true,"target":"cfg(windows)","kind":"normal"}],"cksum":"","features":{}}
+{"name":"foo","vers":"2.0.0-rc.1","deps":[],"cksum":"","features":{}}
+not json
+"#,
+        );
+        assert_eq!(versions.len(), 4);
+        let newest = newest_matching(&versions, &VersionReq::STAR).unwrap();
+        assert_eq!(newest.vers.to_string(), "1.1.0");
+        assert_eq!(newest.deps[0].crate_name(), "bar");
+        assert!(newest.deps[0].is_for("x86_64-unknown-linux-gnu"));
+        assert!(!newest.deps[1].is_for("x86_64-unknown-linux-gnu"));
+        assert!(newest.deps[1].is_for("x86_64-pc-windows-gnu"));
+        assert!(newest_matching(&versions, &"^3".parse()?).is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,248 @@
+//! Cross compilation targets.
+
+use std::str::FromStr;
+
+use eyre::eyre;
+use itertools::Itertools;
+use target_lexicon::{Architecture, Environment, OperatingSystem, Triple};
+
+/// Rust target triples riff can cross compile to, and the Nixpkgs `pkgsCross` set providing
+/// their toolchain
+const CROSS_TARGETS: &[(&str, &str)] = &[
+    ("aarch64-unknown-linux-gnu", "aarch64-multiplatform"),
+    ("aarch64-unknown-linux-musl", "aarch64-multiplatform-musl"),
+    ("arm-unknown-linux-gnueabihf", "raspberryPi"),
+    ("armv7-unknown-linux-gnueabihf", "armv7l-hf-multiplatform"),
+    ("i686-pc-windows-gnu", "mingw32"),
+    ("riscv64gc-unknown-linux-gnu", "riscv64"),
+    ("x86_64-pc-windows-gnu", "mingwW64"),
+    ("x86_64-unknown-linux-gnu", "gnu64"),
+    ("x86_64-unknown-linux-musl", "musl64"),
+];
+
+/// A Rust target triple to cross compile to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CrossTarget {
+    /// The Rust target triple, Eg `aarch64-unknown-linux-gnu`
+    pub(crate) triple: String,
+    /// The attribute of `pkgsCross` for the triple, Eg `aarch64-multiplatform`
+    pub(crate) nixpkgs_cross: &'static str,
+}
+
+impl CrossTarget {
+    /// The suffix Cargo uses for target specific environment variables, Eg `AARCH64_UNKNOWN_LINUX_GNU`
+    pub(crate) fn env_suffix(&self) -> String {
+        self.triple.to_uppercase().replace(['-', '.'], "_")
+    }
+}
+
+impl FromStr for CrossTarget {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match CROSS_TARGETS.iter().find(|(triple, _)| *triple == s) {
+            Some((triple, nixpkgs_cross)) => Ok(Self {
+                triple: triple.to_string(),
+                nixpkgs_cross,
+            }),
+            None => Err(eyre!(
+                "`{s}` is not a supported cross compilation target, supported targets are: {}",
+                CROSS_TARGETS.iter().map(|(triple, _)| triple).join(", ")
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for CrossTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.triple)
+    }
+}
+
+/// If a dependency for `platform`, a target triple or `cfg(..)` expression like in
+/// `[target.'cfg(unix)'.dependencies]`, is built for `triple`
+///
+/// Configuration which doesn't come from the triple, like `feature` or `target_feature`, is
+/// unset. Expressions which can't be parsed match, so the dependency is checked.
+pub(crate) fn platform_matches(platform: &str, triple: &str) -> bool {
+    let expression = match platform
+        .strip_prefix("cfg(")
+        .and_then(|expression| expression.strip_suffix(')'))
+    {
+        Some(expression) => expression,
+        None => return platform == triple,
+    };
+    let triple = match triple.parse::<Triple>() {
+        Ok(triple) => triple,
+        Err(err) => {
+            tracing::debug!(%err, %triple, "Unable to parse a target triple");
+            return true;
+        }
+    };
+    let mut tokens = CfgTokens::new(expression);
+    match tokens.predicate(&triple) {
+        Some(matches) if tokens.next().is_none() => matches,
+        _ => {
+            tracing::debug!(%platform, "Unable to parse a target specific dependency");
+            true
+        }
+    }
+}
+
+/// The value of the `cfg` option `key` for `triple`, Eg `linux` for `target_os`
+fn cfg_value(triple: &Triple, key: &str) -> Option<String> {
+    let value = match key {
+        "target_arch" => match triple.architecture {
+            Architecture::Arm(_) => "arm".to_string(),
+            Architecture::X86_32(_) => "x86".to_string(),
+            Architecture::Riscv32(_) => "riscv32".to_string(),
+            Architecture::Riscv64(_) => "riscv64".to_string(),
+            architecture => architecture.to_string(),
+        },
+        "target_os" => match triple.operating_system {
+            OperatingSystem::Darwin | OperatingSystem::MacOSX { .. } => "macos".to_string(),
+            OperatingSystem::Unknown | OperatingSystem::None_ => "none".to_string(),
+            operating_system => operating_system.to_string(),
+        },
+        "target_family" => match triple.operating_system {
+            OperatingSystem::Windows => "windows".to_string(),
+            OperatingSystem::Unknown | OperatingSystem::None_ => return None,
+            _ => "unix".to_string(),
+        },
+        "target_env" => match triple.environment {
+            Environment::Msvc => "msvc".to_string(),
+            environment => {
+                let environment = environment.to_string();
+                match ["gnu", "musl"]
+                    .into_iter()
+                    .find(|env| environment.starts_with(env))
+                {
+                    Some(env) => env.to_string(),
+                    None => String::new(),
+                }
+            }
+        },
+        "target_vendor" => triple.vendor.to_string(),
+        "target_pointer_width" => triple.pointer_width().ok()?.bits().to_string(),
+        "target_endian" => match triple.endianness().ok()? {
+            target_lexicon::Endianness::Little => "little".to_string(),
+            target_lexicon::Endianness::Big => "big".to_string(),
+        },
+        _ => return None,
+    };
+    Some(value)
+}
+
+/// The tokens of a `cfg(..)` expression, parsed as they're read
+struct CfgTokens<'a> {
+    rest: &'a str,
+}
+
+impl<'a> CfgTokens<'a> {
+    fn new(expression: &'a str) -> Self {
+        Self { rest: expression }
+    }
+
+    /// The next token: an identifier, a string without its quotes, or one of `(),=`
+    fn next(&mut self) -> Option<&'a str> {
+        self.rest = self.rest.trim_start();
+        let mut chars = self.rest.char_indices();
+        let len = match chars.next()? {
+            (_, '(' | ')' | ',' | '=') => 1,
+            (_, '"') => self.rest[1..].find('"')? + 2,
+            _ => chars
+                .find(|(_, c)| !(c.is_alphanumeric() || *c == '_'))
+                .map_or(self.rest.len(), |(index, _)| index),
+        };
+        let (token, rest) = self.rest.split_at(len);
+        self.rest = rest;
+        Some(token)
+    }
+
+    fn peek(&self) -> Option<&'a str> {
+        Self { rest: self.rest }.next()
+    }
+
+    /// Parse and evaluate a predicate for `triple`, `None` if it isn't valid
+    fn predicate(&mut self, triple: &Triple) -> Option<bool> {
+        let key = self.next()?;
+        match key {
+            "all" | "any" | "not" => {
+                (self.next()? == "(").then_some(())?;
+                let mut values = Vec::new();
+                while self.peek()? != ")" {
+                    values.push(self.predicate(triple)?);
+                    if self.peek()? == "," {
+                        self.next();
+                    }
+                }
+                self.next();
+                match key {
+                    "all" => Some(values.into_iter().all(|value| value)),
+                    "any" => Some(values.into_iter().any(|value| value)),
+                    _ => match values[..] {
+                        [value] => Some(!value),
+                        _ => None,
+                    },
+                }
+            }
+            _ if key.starts_with(|c: char| c.is_alphabetic() || c == '_') => {
+                if self.peek() == Some("=") {
+                    self.next();
+                    let value = self.next()?.strip_prefix('"')?.strip_suffix('"')?;
+                    Some(cfg_value(triple, key).as_deref() == Some(value))
+                } else {
+                    Some(cfg_value(triple, "target_family").as_deref() == Some(key))
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{platform_matches, CrossTarget};
+
+    #[test]
+    fn cross_target_parse() -> eyre::Result<()> {
+        let target: CrossTarget = "aarch64-unknown-linux-gnu".parse()?;
+        assert_eq!(target.nixpkgs_cross, "aarch64-multiplatform");
+        assert_eq!(target.env_suffix(), "AARCH64_UNKNOWN_LINUX_GNU");
+
+        let err = "sparc-sun-solaris".parse::<CrossTarget>().unwrap_err();
+        assert!(err.to_string().contains("x86_64-pc-windows-gnu"), "{err}");
+        Ok(())
+    }
+
+    #[test]
+    fn platforms_matching() {
+        let linux = "x86_64-unknown-linux-gnu";
+        let windows = "x86_64-pc-windows-gnu";
+        assert!(platform_matches(linux, linux));
+        assert!(!platform_matches(linux, windows));
+        assert!(platform_matches("cfg(unix)", linux));
+        assert!(!platform_matches("cfg(unix)", windows));
+        assert!(platform_matches("cfg(windows)", windows));
+        assert!(platform_matches(r#"cfg(target_os = "linux")"#, linux));
+        assert!(platform_matches(
+            r#"cfg(all(target_arch = "aarch64", target_env = "musl"))"#,
+            "aarch64-unknown-linux-musl"
+        ));
+        assert!(platform_matches(
+            r#"cfg(any(target_os = "macos", target_os = "ios"))"#,
+            "aarch64-apple-darwin"
+        ));
+        assert!(!platform_matches(
+            r#"cfg(not(target_pointer_width = "64"))"#,
+            linux
+        ));
+        assert!(platform_matches(
+            r#"cfg(target_arch = "x86")"#,
+            "i686-pc-windows-gnu"
+        ));
+        assert!(!platform_matches(r#"cfg(feature = "std")"#, linux));
+        // Unparseable expressions are checked rather than dropped
+        assert!(platform_matches("cfg(all(unix)", linux));
+    }
+}
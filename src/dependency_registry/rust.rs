@@ -1,19 +1,128 @@
-use std::collections::{HashMap, HashSet};
+//! The Rust specific parts of the registry, mapping crates to their settings.
 
-use serde::Deserialize;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap, HashSet};
 
-use crate::dev_env::{DevEnvironment, DevEnvironmentAppliable};
+use semver::{Version, VersionReq};
+use serde::{Deserialize, Deserializer};
+
+use itertools::Itertools;
+
+use crate::dev_env::{
+    check_environment_variable_name, escape_nix_string, DevEnvironment, DevEnvironmentAppliable,
+    SHELL_HOOK,
+};
 
 /// A language specific registry of dependencies to riff settings
 #[derive(Deserialize, Default, Clone, Debug)]
 pub struct RustDependencyRegistryData {
     /// Settings which are needed for every instance of this language (Eg `cargo` for Rust)
+    #[serde(default)]
     pub(crate) default: RustDependencyTargetData,
     /// A mapping of dependencies (by crate name) to configuration
+    ///
+    /// A name may contain `*` wildcards to cover a family of crates, Eg `openssl-*`, see
+    /// [`RustDependencyRegistryData::find_dependency`].
     // TODO(@hoverbear): How do we handle crates with conflicting names? eg a `rocksdb-sys` crate from one repo and another from another having different requirements?
+    #[serde(default, deserialize_with = "deserialize_dependencies")]
     pub(crate) dependencies: HashMap<String, RustDependencyData>,
 }
 
+impl RustDependencyRegistryData {
+    /// The settings every Rust project is provided, Eg `cargo`
+    pub fn defaults(&self) -> &RustDependencyTargetData {
+        &self.default
+    }
+
+    /// The settings of every crate in the registry, by name
+    pub fn dependencies(&self) -> &HashMap<String, RustDependencyData> {
+        &self.dependencies
+    }
+
+    /// The settings of the crate `name`, if the registry has any
+    pub fn dependency(&self, name: &str) -> Option<&RustDependencyData> {
+        self.dependencies.get(name)
+    }
+
+    /// The registry key and settings of the crate `name`
+    ///
+    /// In order of precedence:
+    /// 1. The key which is exactly `name`
+    /// 2. A key differing only in case, or also in `-` and `_` if `ignore_separators` is set, so
+    ///    a near miss in the registry still matches
+    /// 3. The wildcard key matching `name` (normalized the same way) with the most characters
+    ///    besides `*`, Eg `openssl-s*` over `openssl-*`
+    ///
+    /// Ties are broken by the order of the keys, so the result doesn't depend on the registry's.
+    pub fn find_dependency(
+        &self,
+        name: &str,
+        ignore_separators: bool,
+    ) -> Option<(&str, &RustDependencyData)> {
+        if let Some((key, dep_config)) = self.dependencies.get_key_value(name) {
+            return Some((key, dep_config));
+        }
+        let normalize = |name: &str| {
+            let name = name.to_lowercase();
+            match ignore_separators {
+                true => name.replace('_', "-"),
+                false => name,
+            }
+        };
+        let normalized = normalize(name);
+        let (exact, wildcards): (Vec<_>, Vec<_>) = self
+            .dependencies
+            .iter()
+            .partition(|(key, _)| !key.contains('*'));
+        if let Some((key, dep_config)) = exact
+            .into_iter()
+            .filter(|(key, _)| normalize(key) == normalized)
+            .min_by_key(|(key, _)| *key)
+        {
+            tracing::info!(
+                crate_name = %name,
+                registry_key = %key,
+                "Using a registry entry whose name only nearly matches the crate, the registry's key should be `{name}`"
+            );
+            return Some((key, dep_config));
+        }
+        let (key, dep_config) = wildcards
+            .into_iter()
+            .filter(|(key, _)| glob_matches(&normalize(key), &normalized))
+            .min_by_key(|(key, _)| (Reverse(key.len() - key.matches('*').count()), *key))?;
+        tracing::debug!(crate_name = %name, registry_key = %key, "Using a wildcard registry entry");
+        Some((key, dep_config))
+    }
+
+    /// Merge a registry layered over this one, its mappings replace these per crate
+    pub(crate) fn merge(&mut self, other: RustDependencyRegistryData) {
+        self.default.merge(other.default);
+        self.dependencies.extend(other.dependencies);
+    }
+}
+
+/// If `name` matches `pattern`, where each `*` matches any number of characters
+fn glob_matches(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let mut rest = match parts.next().and_then(|first| name.strip_prefix(first)) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    let parts = parts.collect::<Vec<_>>();
+    let (last, middle) = match parts.split_last() {
+        Some(split) => split,
+        None => return rest.is_empty(),
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+/// The settings of a crate, Eg `openssl-sys`
 #[derive(Deserialize, Default, Clone, Debug)]
 pub struct RustDependencyData {
     #[serde(flatten)]
@@ -21,11 +130,62 @@ pub struct RustDependencyData {
     // Keep the key a `String` since users can make custom targets.
     #[serde(default)]
     pub(crate) targets: HashMap<String, RustDependencyTargetData>,
+    /// Settings only needed when a feature of the crate is enabled (Eg `cmake-build`)
+    #[serde(default)]
+    pub(crate) features: HashMap<String, RustDependencyTargetData>,
+    /// Settings for ranges of the crate's versions, which replace the rest when one matches
+    #[serde(default)]
+    pub(crate) versions: Vec<RustDependencyVersionRule>,
+}
+
+/// The settings for the versions of a crate matching `version`, Eg `>=1.0`
+#[derive(Deserialize, Clone, Debug)]
+pub struct RustDependencyVersionRule {
+    pub(crate) version: VersionReq,
+    #[serde(flatten)]
+    pub(crate) data: RustDependencyData,
+}
+
+/// A project's settings for a crate from `package.metadata.riff.overrides.<crate>`, which replace
+/// the registry's
+#[derive(Deserialize, Clone, Debug)]
+pub struct RustDependencyOverride {
+    /// Add to the registry's settings for the crate instead of replacing them
+    #[serde(default)]
+    pub(crate) augment: bool,
+    #[serde(flatten)]
+    pub(crate) data: RustDependencyData,
 }
 
 impl RustDependencyData {
+    /// The settings for `version` of the crate: the first rule matching it, or the rest if none do
+    #[tracing::instrument(skip_all, fields(%version))]
+    pub fn for_version(&self, version: &Version) -> &RustDependencyData {
+        if self.versions.is_empty() {
+            return self;
+        }
+        match self
+            .versions
+            .iter()
+            .find(|rule| rule.version.matches(version))
+        {
+            Some(rule) => {
+                tracing::trace!(rule = %rule.version, "Using the settings for a version range");
+                &rule.data
+            }
+            None => {
+                tracing::warn!("No version range matches, using the default settings");
+                self
+            }
+        }
+    }
+    /// The settings only needed when `feature` of the crate is enabled
+    pub fn feature(&self, feature: &str) -> Option<&RustDependencyTargetData> {
+        self.features.get(feature)
+    }
+    /// The `buildInputs` needed on the host platform
     #[tracing::instrument(skip_all)]
-    pub(crate) fn build_inputs(&self) -> HashSet<String> {
+    pub fn build_inputs(&self) -> HashSet<String> {
         let target = format!("{}", target_lexicon::HOST);
         let mut build_inputs = self.default.build_inputs.clone();
         // Importantly: These come after, they are more specific.
@@ -37,8 +197,19 @@ impl RustDependencyData {
         }
         build_inputs
     }
+    /// The `nativeBuildInputs` needed on the host platform
     #[tracing::instrument(skip_all)]
-    pub(crate) fn environment_variables(&self) -> HashMap<String, String> {
+    pub fn native_build_inputs(&self) -> HashSet<String> {
+        let target = format!("{}", target_lexicon::HOST);
+        let mut native_build_inputs = self.default.native_build_inputs.clone();
+        if let Some(target_config) = self.targets.get(&target) {
+            native_build_inputs.extend(target_config.native_build_inputs.iter().cloned());
+        }
+        native_build_inputs
+    }
+    /// The environment variables set on the host platform
+    #[tracing::instrument(skip_all)]
+    pub fn environment_variables(&self) -> HashMap<String, String> {
         let target = format!("{}", target_lexicon::HOST);
         let mut environment_variables = self.default.environment_variables.clone();
         // Importantly: These come after, they are more specific.
@@ -49,8 +220,9 @@ impl RustDependencyData {
         }
         environment_variables
     }
+    /// The runtime inputs needed on the host platform
     #[tracing::instrument(skip_all)]
-    pub(crate) fn runtime_inputs(&self) -> HashSet<String> {
+    pub fn runtime_inputs(&self) -> HashSet<String> {
         let target = format!("{}", target_lexicon::HOST);
         let mut runtime_inputs = self.default.runtime_inputs.clone();
         // Importantly: These come after, they are more specific.
@@ -62,6 +234,36 @@ impl RustDependencyData {
         }
         runtime_inputs
     }
+    /// Expand the `${NAME}` references to the current environment in the environment variables,
+    /// see [`expand_environment_value`]
+    pub(crate) fn expand_environment_variables(&mut self) {
+        let lookup = |name: &str| std::env::var(name).ok();
+        for data in std::iter::once(&mut self.default)
+            .chain(self.targets.values_mut())
+            .chain(self.features.values_mut())
+        {
+            for value in data.environment_variables.values_mut() {
+                *value = expand_environment_value(value, lookup);
+            }
+        }
+        for rule in &mut self.versions {
+            rule.data.expand_environment_variables();
+        }
+    }
+    /// Apply the settings of the features in `enabled_features`, these come after the rest
+    #[tracing::instrument(skip_all)]
+    pub(crate) fn apply_features(
+        &self,
+        enabled_features: &HashSet<String>,
+        dev_env: &mut DevEnvironment,
+    ) {
+        for (feature, feature_config) in &self.features {
+            if enabled_features.contains(feature) {
+                tracing::debug!(%feature, "Applying feature specific settings");
+                feature_config.apply(dev_env);
+            }
+        }
+    }
 }
 
 impl DevEnvironmentAppliable for RustDependencyData {
@@ -80,24 +282,305 @@ impl DevEnvironmentAppliable for RustDependencyData {
 #[derive(Deserialize, Default, Clone, Debug)]
 pub struct RustDependencyTargetData {
     /// The Nix `buildInputs` needed
-    #[serde(default, rename = "build-inputs")]
+    #[serde(
+        default,
+        rename = "build-inputs",
+        deserialize_with = "deserialize_attribute_paths"
+    )]
     pub(crate) build_inputs: HashSet<String>,
+    /// The Nix `nativeBuildInputs` needed, tools run while building (Eg `pkg-config`) rather
+    /// than libraries linked against, which come from the host when cross compiling
+    #[serde(
+        default,
+        rename = "native-build-inputs",
+        deserialize_with = "deserialize_attribute_paths"
+    )]
+    pub(crate) native_build_inputs: HashSet<String>,
     /// Any packaging specific environment variables that need to be set
-    #[serde(default, rename = "environment-variables")]
+    ///
+    /// Those given as lists of paths are exported from the `shellHook` instead, see
+    /// [`deserialize_environment_variables`].
+    #[serde(
+        default,
+        rename = "environment-variables",
+        deserialize_with = "deserialize_environment_variables"
+    )]
     pub(crate) environment_variables: HashMap<String, String>,
     /// The Nix packages which should have the result of `lib.getLib` run on them placed on the `LD_LIBRARY_PATH`
-    #[serde(default, rename = "runtime-inputs")]
+    #[serde(
+        default,
+        rename = "runtime-inputs",
+        deserialize_with = "deserialize_attribute_paths"
+    )]
     pub(crate) runtime_inputs: HashSet<String>,
+    /// The Apple SDK frameworks needed on macOS (Eg `Security`), from `darwin.apple_sdk.frameworks`
+    #[serde(
+        default,
+        rename = "darwin-frameworks",
+        deserialize_with = "deserialize_darwin_frameworks"
+    )]
+    pub(crate) darwin_frameworks: HashSet<String>,
+    /// If `libclang` is needed (Eg by `bindgen`), which adds it and sets `LIBCLANG_PATH`
+    #[serde(default)]
+    pub(crate) libclang: bool,
+    /// If `PKG_CONFIG_PATH` is set from the build inputs by the `shellHook`, for libraries which
+    /// Nix's `pkg-config` setup hook doesn't find
+    #[serde(default, rename = "pkg-config-path")]
+    pub(crate) pkg_config_path: bool,
+}
+
+impl RustDependencyTargetData {
+    /// The Nixpkgs attribute paths needed in `buildInputs`
+    pub fn build_inputs(&self) -> &HashSet<String> {
+        &self.build_inputs
+    }
+
+    /// The Nixpkgs attribute paths needed in `nativeBuildInputs`
+    pub fn native_build_inputs(&self) -> &HashSet<String> {
+        &self.native_build_inputs
+    }
+
+    /// The environment variables to set
+    pub fn environment_variables(&self) -> &HashMap<String, String> {
+        &self.environment_variables
+    }
+
+    /// The Nixpkgs attribute paths whose libraries are put on the `LD_LIBRARY_PATH`
+    pub fn runtime_inputs(&self) -> &HashSet<String> {
+        &self.runtime_inputs
+    }
+
+    /// The Apple SDK frameworks needed on macOS
+    pub fn darwin_frameworks(&self) -> &HashSet<String> {
+        &self.darwin_frameworks
+    }
+
+    /// If `libclang` is needed, which also sets `LIBCLANG_PATH`
+    pub fn libclang(&self) -> bool {
+        self.libclang
+    }
+
+    /// If `PKG_CONFIG_PATH` is set from the build inputs
+    pub fn pkg_config_path(&self) -> bool {
+        self.pkg_config_path
+    }
+
+    /// Add the settings of `other`, its environment variables take precedence
+    pub(crate) fn merge(&mut self, other: RustDependencyTargetData) {
+        self.build_inputs.extend(other.build_inputs);
+        self.native_build_inputs.extend(other.native_build_inputs);
+        self.environment_variables
+            .extend(other.environment_variables);
+        self.runtime_inputs.extend(other.runtime_inputs);
+        self.darwin_frameworks.extend(other.darwin_frameworks);
+        self.libclang |= other.libclang;
+        self.pkg_config_path |= other.pkg_config_path;
+    }
+}
+
+/// Expand the references to environment variables in `value`, which is put in a Nix string
+///
+/// Only `${NAME}` where `NAME` is made of uppercase letters, digits and underscores is expanded,
+/// with `lookup`, when the flake is generated. An undefined variable expands to nothing. Other
+/// `${...}`, like `${lib.getLib openssl}`, are left for Nix to interpolate, and `$NAME` without
+/// braces is left as written. `$$` is a literal `$`, so `$${HOME}` is the text `${HOME}`.
+pub(crate) fn expand_environment_value(
+    value: &str,
+    lookup: impl Fn(&str) -> Option<String>,
+) -> String {
+    let mut expanded = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(index) = rest.find('$') {
+        expanded.push_str(&rest[..index]);
+        rest = &rest[index..];
+        if let Some(after) = rest.strip_prefix("$$") {
+            // Escaped so Nix doesn't read `${` as the start of an interpolation
+            expanded.push_str("\\$");
+            rest = after;
+            continue;
+        }
+        let name = rest
+            .strip_prefix("${")
+            .and_then(|after| after.split_once('}'))
+            .map(|(name, _)| name)
+            .filter(|name| {
+                name.starts_with(|c: char| c.is_ascii_uppercase() || c == '_')
+                    && name
+                        .chars()
+                        .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_')
+            });
+        match name {
+            Some(name) => {
+                match lookup(name) {
+                    Some(var) => expanded.push_str(&escape_nix_string(&var)),
+                    None => tracing::warn!(
+                        %name,
+                        "Undefined environment variable referenced in `package.metadata.riff.environment-variables`, expanding it to nothing"
+                    ),
+                }
+                rest = &rest[name.len() + 3..];
+            }
+            None => {
+                expanded.push('$');
+                rest = &rest[1..];
+            }
+        }
+    }
+    expanded.push_str(rest);
+    expanded
+}
+
+/// The build inputs provided for [`RustDependencyTargetData::libclang`]
+const LIBCLANG_BUILD_INPUTS: [&str; 2] = ["clang", "llvmPackages.libclang"];
+/// Where `clang-sys` looks for `libclang`, see its README
+const LIBCLANG_PATH: (&str, &str) = ("LIBCLANG_PATH", "${llvmPackages.libclang.lib}/lib");
+
+/// The value of a variable in `environment-variables`
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum EnvironmentValue {
+    Value(String),
+    /// Paths joined with `:`, Eg `PATH = [ "${foo}/bin", "$PATH" ]`
+    Paths(Vec<String>),
+}
+
+/// Deserialize `environment-variables`, where a variable may be a list of paths
+///
+/// A list is exported from the `shellHook`, before any `shellHook` given with it, so the value
+/// the variable already has when the environment is entered can be kept: it's added after the
+/// paths, unless the list places it with `$NAME`.
+fn deserialize_environment_variables<'de, D>(
+    deserializer: D,
+) -> Result<HashMap<String, String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let values = BTreeMap::<String, EnvironmentValue>::deserialize(deserializer)?;
+    let mut environment_variables = HashMap::new();
+    let mut exports = Vec::new();
+    for (key, value) in values {
+        match value {
+            EnvironmentValue::Value(value) => {
+                environment_variables.insert(key, value);
+            }
+            EnvironmentValue::Paths(paths) => {
+                exports.push(path_list_export(&key, &paths).map_err(serde::de::Error::custom)?)
+            }
+        }
+    }
+    if !exports.is_empty() {
+        let shell_hook = environment_variables.remove(SHELL_HOOK);
+        environment_variables.insert(
+            SHELL_HOOK.to_string(),
+            exports.into_iter().chain(shell_hook).join("\n"),
+        );
+    }
+    Ok(environment_variables)
+}
+
+/// The `shellHook` line exporting `key` as `paths` joined with `:`
+fn path_list_export(key: &str, paths: &[String]) -> color_eyre::Result<String> {
+    if key == SHELL_HOOK {
+        return Err(eyre::eyre!("`{SHELL_HOOK}` can't be a list of paths"));
+    }
+    check_environment_variable_name(key)?;
+    if paths.is_empty() {
+        return Err(eyre::eyre!("`{key}` is an empty list of paths"));
+    }
+    if let Some(path) = paths
+        .iter()
+        .find(|path| path.is_empty() || path.contains([':', '"']))
+    {
+        return Err(eyre::eyre!(
+            "`{path}` in `{key}` is not a path, it can't be empty or contain `:` or `\"`"
+        ));
+    }
+    let mut value = paths.join(":");
+    let existing = format!("${key}");
+    if !paths.contains(&existing) {
+        value.push_str(&format!("\\${{{key}:+:{existing}}}"));
+    }
+    Ok(format!("export {key}=\\\"{value}\\\""))
+}
+
+/// Deserialize the registry's crates, skipping any whose settings are invalid
+///
+/// One bad entry in a remote or cached registry shouldn't stop riff from using the rest of it.
+fn deserialize_dependencies<'de, D>(
+    deserializer: D,
+) -> Result<HashMap<String, RustDependencyData>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let dependencies = HashMap::<String, serde_json::Value>::deserialize(deserializer)?;
+    Ok(dependencies
+        .into_iter()
+        .filter_map(
+            |(name, value)| match RustDependencyData::deserialize(value) {
+                Ok(data) => Some((name, data)),
+                Err(err) => {
+                    tracing::warn!(%err, name, "Skipping an invalid registry entry");
+                    None
+                }
+            },
+        )
+        .collect())
+}
+
+/// Deserialize a list of Apple SDK framework names (Eg `CoreFoundation`)
+fn deserialize_darwin_frameworks<'de, D>(deserializer: D) -> Result<HashSet<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let frameworks = HashSet::<String>::deserialize(deserializer)?;
+    for framework in &frameworks {
+        if framework.contains('.') || !is_attribute_path(framework) {
+            return Err(serde::de::Error::custom(format!(
+                "`{framework}` is not an Apple SDK framework name like `Security`"
+            )));
+        }
+    }
+    Ok(frameworks)
+}
+
+/// Deserialize a list of Nixpkgs attribute paths (Eg `openssl` or `darwin.apple_sdk.frameworks.Security`)
+///
+/// These are spliced into the generated flake, so anything else is rejected.
+fn deserialize_attribute_paths<'de, D>(deserializer: D) -> Result<HashSet<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let attribute_paths = HashSet::<String>::deserialize(deserializer)?;
+    for attribute_path in &attribute_paths {
+        if !is_attribute_path(attribute_path) {
+            return Err(serde::de::Error::custom(format!(
+                "`{attribute_path}` is not a Nixpkgs attribute path like `openssl` or `darwin.apple_sdk.frameworks.Security`"
+            )));
+        }
+    }
+    Ok(attribute_paths)
+}
+
+pub(crate) fn is_attribute_path(attribute_path: &str) -> bool {
+    attribute_path.split('.').all(|segment| {
+        let mut chars = segment.chars();
+        chars
+            .next()
+            .map(|c| c.is_ascii_alphabetic() || c == '_')
+            .unwrap_or(false)
+            && chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '\''))
+    })
 }
 
 impl DevEnvironmentAppliable for RustDependencyTargetData {
     #[tracing::instrument(skip_all)]
     fn apply(&self, dev_env: &mut DevEnvironment) {
-        dev_env.build_inputs = dev_env
+        dev_env
             .build_inputs
-            .union(&self.build_inputs)
-            .cloned()
-            .collect();
+            .extend(self.build_inputs.iter().cloned());
+        dev_env
+            .native_build_inputs
+            .extend(self.native_build_inputs.iter().cloned());
         for (ref env_key, ref env_val) in &self.environment_variables {
             if let Some(existing_value) = dev_env
                 .environment_variables
@@ -111,25 +594,64 @@ impl DevEnvironmentAppliable for RustDependencyTargetData {
                 )
             }
         }
-        dev_env.runtime_inputs = dev_env
+        dev_env
             .runtime_inputs
-            .union(&self.runtime_inputs)
-            .cloned()
-            .collect();
+            .extend(self.runtime_inputs.iter().cloned());
+        dev_env
+            .darwin_frameworks
+            .extend(self.darwin_frameworks.iter().cloned());
+        if self.libclang {
+            dev_env
+                .build_inputs
+                .extend(LIBCLANG_BUILD_INPUTS.map(str::to_owned));
+            let (key, value) = LIBCLANG_PATH;
+            dev_env
+                .environment_variables
+                .insert(key.to_owned(), value.to_owned());
+        }
+        dev_env.pkg_config_path |= self.pkg_config_path;
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::dependency_registry::DependencyRegistry;
+    use crate::dependency_registry::{DependencyRegistry, DependencyRegistryOptions};
     use tempfile::TempDir;
 
+    #[test]
+    fn expand_environment_value_references() {
+        let lookup = |name: &str| match name {
+            "HOME" => Some("/home/me".to_string()),
+            "QUOTED" => Some(r#"a"b\c${d}"#.to_string()),
+            _ => None,
+        };
+        assert_eq!(
+            expand_environment_value("${HOME}/bin:$PATH", lookup),
+            "/home/me/bin:$PATH"
+        );
+        assert_eq!(expand_environment_value("${UNDEFINED}x", lookup), "x");
+        assert_eq!(
+            expand_environment_value("$${HOME} $$5", lookup),
+            r"\${HOME} \$5"
+        );
+        assert_eq!(
+            expand_environment_value("${lib.getLib openssl}/lib:${openssl}", lookup),
+            "${lib.getLib openssl}/lib:${openssl}"
+        );
+        assert_eq!(
+            expand_environment_value("${QUOTED}", lookup),
+            r#"a\"b\\c\${d}"#
+        );
+        assert_eq!(expand_environment_value("${HOME", lookup), "${HOME");
+    }
+
     #[tokio::test]
     async fn try_apply() -> eyre::Result<()> {
         let cache_dir = TempDir::new()?;
         std::env::set_var("XDG_CACHE_HOME", cache_dir.path());
-        let registry = DependencyRegistry::new(true).await?;
+        let registry =
+            DependencyRegistry::new(&DependencyRegistryOptions::new().offline(true)).await?;
         let mut dev_env = DevEnvironment::new(&registry);
 
         let target = format!("{}", target_lexicon::HOST);
@@ -143,6 +665,10 @@ mod test {
                 .into_iter()
                 .collect(),
                 runtime_inputs: vec!["default".into()].into_iter().collect(),
+                native_build_inputs: vec!["pkg-config".into()].into_iter().collect(),
+                darwin_frameworks: Default::default(),
+                libclang: false,
+                pkg_config_path: false,
             },
             targets: {
                 let mut map = HashMap::default();
@@ -157,14 +683,24 @@ mod test {
                         .into_iter()
                         .collect(),
                         runtime_inputs: vec!["target_specific".into()].into_iter().collect(),
+                        native_build_inputs: Default::default(),
+                        darwin_frameworks: Default::default(),
+                        libclang: false,
+                        pkg_config_path: false,
                     },
                 );
                 map
             },
+            features: Default::default(),
+            versions: Default::default(),
         };
 
         data.apply(&mut dev_env);
 
+        assert_eq!(
+            dev_env.native_build_inputs,
+            vec!["pkg-config".into()].into_iter().collect()
+        );
         assert_eq!(
             dev_env.build_inputs,
             vec!["default".into(), "target_specific".into()]
@@ -191,6 +727,209 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn build_inputs_validated() {
+        let data: RustDependencyData = serde_json::from_str(
+            r#"{ "build-inputs": [ "cmake", "protobuf", "darwin.apple_sdk.frameworks.Security" ] }"#,
+        )
+        .unwrap();
+        assert_eq!(data.default.build_inputs.len(), 3);
+
+        let err =
+            serde_json::from_str::<RustDependencyData>(r#"{ "build-inputs": [ 3 ] }"#).unwrap_err();
+        assert!(err.to_string().contains("expected a string"), "{err}");
+
+        for invalid in ["", "pkgs.", "(import <nixpkgs> {})", "foo bar", "1password"] {
+            let json = serde_json::json!({ "runtime-inputs": [invalid] }).to_string();
+            let err = serde_json::from_str::<RustDependencyData>(&json).unwrap_err();
+            assert!(
+                err.to_string().contains("is not a Nixpkgs attribute path"),
+                "{invalid}: {err}"
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_registry_entries_skipped() {
+        let data: RustDependencyRegistryData = serde_json::from_str(
+            r#"{ "dependencies": { "openssl-sys": { "build-inputs": [ "openssl" ] }, "bad-sys": { "build-inputs": [ "foo bar" ] } } }"#,
+        )
+        .unwrap();
+        assert!(data.dependency("openssl-sys").is_some());
+        assert!(data.dependency("bad-sys").is_none());
+    }
+
+    #[tokio::test]
+    async fn darwin_frameworks_apply_on_every_host() -> eyre::Result<()> {
+        let cache_dir = TempDir::new()?;
+        std::env::set_var("XDG_CACHE_HOME", cache_dir.path());
+        let registry =
+            DependencyRegistry::new(&DependencyRegistryOptions::new().offline(true)).await?;
+        let mut dev_env = DevEnvironment::new(&registry);
+
+        let data: RustDependencyData =
+            serde_json::from_str(r#"{ "darwin-frameworks": [ "Security" ] }"#)?;
+        data.apply(&mut dev_env);
+        assert!(dev_env.darwin_frameworks.contains("Security"));
+
+        let flake = dev_env.to_flake();
+        assert!(flake.contains("lib.optionals (stdenv.isDarwin) ["));
+        assert!(flake.contains("darwin.apple_sdk.frameworks.Security"));
+
+        // A framework also listed in the older form is only added once
+        dev_env
+            .build_inputs
+            .insert("darwin.apple_sdk.frameworks.Security".into());
+        assert_eq!(
+            dev_env
+                .to_flake()
+                .matches("darwin.apple_sdk.frameworks.Security")
+                .count(),
+            1
+        );
+
+        assert!(serde_json::from_str::<RustDependencyData>(
+            r#"{ "darwin-frameworks": [ "darwin.apple_sdk.frameworks.Security" ] }"#
+        )
+        .is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn libclang_sets_libclang_path() -> eyre::Result<()> {
+        let cache_dir = TempDir::new()?;
+        std::env::set_var("XDG_CACHE_HOME", cache_dir.path());
+        let registry =
+            DependencyRegistry::new(&DependencyRegistryOptions::new().offline(true)).await?;
+        let mut dev_env = DevEnvironment::new(&registry);
+
+        let data: RustDependencyData = serde_json::from_str(r#"{ "libclang": true }"#)?;
+        data.apply(&mut dev_env);
+        assert!(dev_env.build_inputs.contains("clang"));
+        assert!(dev_env.build_inputs.contains("llvmPackages.libclang"));
+        assert_eq!(
+            dev_env.environment_variables.get("LIBCLANG_PATH"),
+            Some(&"${llvmPackages.libclang.lib}/lib".to_string())
+        );
+
+        let language = registry.language().await;
+        assert!(language.rust.dependencies["bindgen"].default.libclang);
+        assert!(language.rust.dependencies["clang-sys"].default.libclang);
+        Ok(())
+    }
+
+    #[test]
+    fn find_dependency_normalized() -> eyre::Result<()> {
+        let data: RustDependencyRegistryData = serde_json::from_str(
+            r#"{ "dependencies": {
+                "foo-sys": { "build-inputs": [ "hyphen" ] },
+                "foo_sys": { "build-inputs": [ "underscore" ] },
+                "Bar-Sys": { "build-inputs": [ "bar" ] }
+            } }"#,
+        )?;
+        let key = |name: &str, ignore_separators: bool| {
+            data.find_dependency(name, ignore_separators)
+                .map(|(key, _)| key.to_string())
+        };
+        assert_eq!(key("foo_sys", true).as_deref(), Some("foo_sys"));
+        assert_eq!(key("FOO_SYS", false).as_deref(), Some("foo_sys"));
+        assert_eq!(key("bar-sys", false).as_deref(), Some("Bar-Sys"));
+        assert_eq!(key("bar_sys", false), None);
+        assert_eq!(key("bar_sys", true).as_deref(), Some("Bar-Sys"));
+        assert_eq!(key("baz-sys", true), None);
+        Ok(())
+    }
+
+    #[test]
+    fn find_dependency_wildcards() -> eyre::Result<()> {
+        let data: RustDependencyRegistryData = serde_json::from_str(
+            r#"{ "dependencies": {
+                "openssl-*": { "build-inputs": [ "openssl" ] },
+                "openssl-s*": { "build-inputs": [ "openssl-sys-family" ] },
+                "openssl-sys": { "build-inputs": [ "exact" ] },
+                "*-probe-*": { "build-inputs": [ "probe" ] }
+            } }"#,
+        )?;
+        let key = |name: &str| {
+            data.find_dependency(name, false)
+                .map(|(key, _)| key.to_string())
+        };
+        assert_eq!(key("openssl-sys").as_deref(), Some("openssl-sys"));
+        assert_eq!(key("openssl-src").as_deref(), Some("openssl-s*"));
+        assert_eq!(key("OpenSSL-macros").as_deref(), Some("openssl-*"));
+        assert_eq!(key("openssl"), None);
+        assert_eq!(key("lib-probe-sys").as_deref(), Some("*-probe-*"));
+        assert_eq!(key("probe-sys"), None);
+
+        assert!(glob_matches("a*a", "aa"));
+        assert!(!glob_matches("a*a", "a"));
+        assert!(glob_matches("*", ""));
+        assert!(glob_matches("a*b*c", "abbbc"));
+        assert!(!glob_matches("a*b*c", "acb"));
+        Ok(())
+    }
+
+    #[test]
+    fn environment_variables_path_lists() -> eyre::Result<()> {
+        let data: RustDependencyTargetData = serde_json::from_str(
+            r#"{ "environment-variables": {
+                "PATH": [ "${foo}/bin", "$PATH" ],
+                "LD_LIBRARY_PATH": [ "${bar}/lib", "${baz}/lib" ],
+                "FOO": "1",
+                "shellHook": "echo hi"
+            } }"#,
+        )?;
+        assert_eq!(data.environment_variables["FOO"], "1");
+        assert!(!data.environment_variables.contains_key("PATH"));
+        assert_eq!(
+            data.environment_variables[SHELL_HOOK],
+            [
+                r#"export LD_LIBRARY_PATH=\"${bar}/lib:${baz}/lib\${LD_LIBRARY_PATH:+:$LD_LIBRARY_PATH}\""#,
+                r#"export PATH=\"${foo}/bin:$PATH\""#,
+                "echo hi",
+            ]
+            .join("\n")
+        );
+
+        for invalid in [
+            r#"{ "shellHook": [ "/bin" ] }"#,
+            r#"{ "NOT-A-NAME": [ "/bin" ] }"#,
+            r#"{ "PATH": [] }"#,
+            r#"{ "PATH": [ "/bin:/usr/bin" ] }"#,
+            r#"{ "PATH": [ "" ] }"#,
+        ] {
+            let json = format!(r#"{{ "environment-variables": {invalid} }}"#);
+            assert!(
+                serde_json::from_str::<RustDependencyTargetData>(&json).is_err(),
+                "{invalid}"
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn version_rules() -> eyre::Result<()> {
+        let data: RustDependencyData = serde_json::from_str(
+            r#"{
+                "build-inputs": [ "openssl" ],
+                "versions": [
+                    { "version": ">=1.0", "build-inputs": [ "openssl_3" ] },
+                    { "version": ">=0.9, <1.0", "build-inputs": [ "openssl_1_1" ] }
+                ]
+            }"#,
+        )?;
+        let build_inputs = |version: &str| -> eyre::Result<_> {
+            Ok(data.for_version(&version.parse()?).build_inputs())
+        };
+        assert!(build_inputs("1.2.0")?.contains("openssl_3"));
+        assert!(build_inputs("0.9.80")?.contains("openssl_1_1"));
+        assert_eq!(
+            build_inputs("0.8.0")?,
+            ["openssl".to_string()].into_iter().collect()
+        );
+        Ok(())
+    }
+
     #[test]
     fn build_input_merge() -> eyre::Result<()> {
         let target = format!("{}", target_lexicon::HOST);
@@ -210,6 +949,8 @@ mod test {
                 );
                 map
             },
+            features: Default::default(),
+            versions: Default::default(),
         };
         let merged = data.build_inputs();
         assert_eq!(
@@ -250,6 +991,8 @@ mod test {
                 );
                 map
             },
+            features: Default::default(),
+            versions: Default::default(),
         };
         let merged = data.environment_variables();
         assert_eq!(
@@ -284,6 +1027,8 @@ mod test {
                 );
                 map
             },
+            features: Default::default(),
+            versions: Default::default(),
         };
         let merged = data.runtime_inputs();
         assert_eq!(
@@ -1,15 +1,22 @@
 //! The developer environment setup.
 
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::path::Path;
 
 use eyre::{eyre, WrapErr};
 use itertools::Itertools;
-use owo_colors::OwoColorize;
 use tokio::process::Command;
 
-use crate::cargo_metadata::CargoMetadata;
+use crate::cargo_metadata::{CargoFeatures, CargoMetadata, ProjectError};
+use crate::color::Colorize;
+use crate::cross_target::CrossTarget;
+use crate::dependency_registry::rust::{
+    is_attribute_path, RustDependencyData, RustDependencyOverride, RustDependencyRegistryData,
+    RustDependencyTargetData,
+};
 use crate::dependency_registry::DependencyRegistry;
+use crate::rust_toolchain::{RustChannel, RustToolchain};
+use crate::spawn_error::{SpawnError, Tool};
 use crate::spinner::SimpleSpinner;
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, serde::Serialize)]
@@ -17,13 +24,249 @@ pub enum DetectedLanguage {
     Rust,
 }
 
+/// Where the settings a crate contributed came from
+///
+/// Ordered by which is applied last when settings are combined, like `shellHook`s.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CrateSettingsSource {
+    /// The dependency registry
+    Registry,
+    /// The crate's own `package.metadata.riff`
+    PackageMetadata,
+    /// The project's `package.metadata.riff.overrides`, which take precedence over the others
+    Override,
+}
+
+/// What a crate added to the environment
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct CrateContribution {
+    pub(crate) name: String,
+    pub(crate) version: semver::Version,
+    pub(crate) source: CrateSettingsSource,
+    pub(crate) build_inputs: BTreeSet<String>,
+    pub(crate) native_build_inputs: BTreeSet<String>,
+    pub(crate) environment_variables: BTreeMap<String, String>,
+    pub(crate) runtime_inputs: BTreeSet<String>,
+    pub(crate) darwin_frameworks: BTreeSet<String>,
+}
+
+/// What a workspace member's own shell provides, see [`DevEnvironment::member_shells`]
+///
+/// Everything else, like the toolchain and Nixpkgs, is shared with the workspace's shell.
+#[derive(Debug, Clone)]
+pub(crate) struct MemberShell {
+    build_inputs: HashSet<String>,
+    native_build_inputs: HashSet<String>,
+    environment_variables: HashMap<String, String>,
+    runtime_inputs: HashSet<String>,
+    darwin_frameworks: HashSet<String>,
+    pkg_config_path: bool,
+}
+
+impl MemberShell {
+    fn of(dev_env: &DevEnvironment) -> Self {
+        Self {
+            build_inputs: dev_env.build_inputs.clone(),
+            native_build_inputs: dev_env.native_build_inputs.clone(),
+            environment_variables: dev_env.environment_variables.clone(),
+            runtime_inputs: dev_env.runtime_inputs.clone(),
+            darwin_frameworks: dev_env.darwin_frameworks.clone(),
+            pkg_config_path: dev_env.pkg_config_path,
+        }
+    }
+}
+
+/// The attributes of the shell derivation, as Nix
+struct ShellAttributes {
+    build_inputs: String,
+    native_build_inputs: String,
+    darwin_frameworks: String,
+    environment_variables: String,
+    ld_library_path: String,
+}
+
 #[derive(Debug, Clone)]
 pub struct DevEnvironment<'a> {
     pub(crate) registry: &'a DependencyRegistry,
     pub(crate) build_inputs: HashSet<String>,
+    /// Tools run while building, which are never taken from `pkgsCross` when cross compiling
+    pub(crate) native_build_inputs: HashSet<String>,
     pub(crate) environment_variables: HashMap<String, String>,
     pub(crate) runtime_inputs: HashSet<String>,
+    /// Apple SDK frameworks, only added to the environment on macOS
+    pub(crate) darwin_frameworks: HashSet<String>,
     pub(crate) detected_languages: HashSet<DetectedLanguage>,
+    /// The target to cross compile to, if not the host
+    pub(crate) target: Option<CrossTarget>,
+    /// The build inputs of the language toolchain itself, which always run on the host
+    pub(crate) toolchain_inputs: HashSet<String>,
+    /// The Nixpkgs git revision or branch to use, instead of [`DEFAULT_NIXPKGS_REV`]
+    pub(crate) nixpkgs_rev: Option<String>,
+    /// A flake reference to use for Nixpkgs, instead of GitHub, overriding `nixpkgs_rev`
+    pub(crate) nixpkgs_flake: Option<String>,
+    /// A toolchain pinned by the project, provided with `rust-overlay` instead of Nixpkgs
+    pub(crate) rust_toolchain: Option<RustToolchain>,
+    /// The channel from `package.metadata.riff.rust-channel`
+    pub(crate) rust_channel: Option<RustChannel>,
+    /// Do not provide a Rust toolchain, use the one on the host
+    pub(crate) host_toolchain: bool,
+    /// Also provide the dependencies of the workspace members' `[dev-dependencies]`
+    pub(crate) dev_dependencies: bool,
+    /// What each crate with settings for riff added, in the order they were applied
+    pub(crate) contributions: Vec<CrateContribution>,
+    /// Set `PKG_CONFIG_PATH` from the build inputs in the `shellHook`, see
+    /// [`RustDependencyTargetData::pkg_config_path`]
+    pub(crate) pkg_config_path: bool,
+    /// Every `shellHook` contributed, which are combined rather than overriding each other
+    pub(crate) shell_hooks: Vec<(CrateSettingsSource, String)>,
+    /// The `-sys` and `-src` crates with no settings in the registry or their own `Cargo.toml`
+    pub(crate) unmapped_crates: BTreeSet<String>,
+    /// Crates left out of the environment, in lowercase, from `--exclude` and
+    /// `package.metadata.riff.exclude`
+    pub(crate) excluded_crates: BTreeSet<String>,
+    /// The settings from `package.metadata.riff.overrides`, by the lowercase name of the crate
+    pub(crate) crate_overrides: BTreeMap<String, RustDependencyOverride>,
+    /// Don't print progress or the detected dependencies
+    pub(crate) quiet: bool,
+    /// Read `Cargo.lock` instead of running `cargo metadata`
+    pub(crate) no_cargo_metadata: bool,
+    /// Treat `-` and `_` in crate names as the same when looking them up in the registry
+    pub(crate) ignore_name_separators: bool,
+    /// Also detect a shell for each workspace member, so the flake has a `devShell` per member
+    pub(crate) dev_shell_per_member: bool,
+    /// The shells of the workspace members, by name, when [`DevEnvironment::dev_shell_per_member`]
+    /// is set and there is more than one member or a member was chosen
+    pub(crate) member_shells: BTreeMap<String, MemberShell>,
+}
+
+/// The environment variable run by the shell when the environment is entered
+pub(crate) const SHELL_HOOK: &str = "shellHook";
+
+/// The Nixpkgs branch used when no revision is pinned
+pub(crate) const DEFAULT_NIXPKGS_REV: &str = "nixos-unstable";
+
+/// Check a Nixpkgs git revision or branch (Eg `nixos-23.11`) can be used in a flake reference
+pub(crate) fn parse_nixpkgs_rev(rev: &str) -> color_eyre::Result<String> {
+    if rev.is_empty()
+        || !rev
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+    {
+        return Err(eyre!(
+            "`{rev}` is not a Nixpkgs git revision or branch like `nixos-23.11`"
+        ));
+    }
+    Ok(rev.to_string())
+}
+
+/// Check a build input passed on the command line is a Nixpkgs attribute path, Eg `protobuf`
+pub(crate) fn parse_build_input(build_input: &str) -> color_eyre::Result<String> {
+    if !is_attribute_path(build_input) {
+        return Err(eyre!(
+            "`{build_input}` is not a Nixpkgs attribute path like `openssl` or `llvmPackages.libclang`"
+        ));
+    }
+    Ok(build_input.to_string())
+}
+
+/// Parse an environment variable passed on the command line as `KEY=VALUE`
+pub(crate) fn parse_environment_variable(
+    environment_variable: &str,
+) -> color_eyre::Result<(String, String)> {
+    let (key, value) = environment_variable.split_once('=').ok_or_else(|| {
+        eyre!("`{environment_variable}` is not an environment variable like `KEY=VALUE`")
+    })?;
+    check_environment_variable_name(key)?;
+    Ok((key.to_string(), value.to_string()))
+}
+
+/// Check `key` can be used as the name of an environment variable in the generated flake
+pub(crate) fn check_environment_variable_name(key: &str) -> color_eyre::Result<()> {
+    if !key.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+    {
+        return Err(eyre!(
+            "`{key}` is not an environment variable name, it must be made of letters, digits and `_`"
+        ));
+    }
+    Ok(())
+}
+
+/// How to add crates to the registry
+const REGISTRY_CONTRIBUTING_URL: &str =
+    "https://github.com/DeterminateSystems/riff/blob/main/CONTRIBUTING.md";
+
+/// If the crate `name` is likely to build or link a system library, by the `-sys` and `-src`
+/// naming conventions
+fn is_system_crate(name: &str) -> bool {
+    name.ends_with("-sys") || name.ends_with("-src")
+}
+
+/// Escape `value` so it is placed in a Nix string as is, without interpolating `${...}`
+pub(crate) fn escape_nix_string(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('$', "\\$")
+}
+
+/// The flake reference types understood by Nix, see `nix help flake`
+const FLAKE_REF_TYPES: &[&str] = &[
+    "flake",
+    "path",
+    "git",
+    "git+http",
+    "git+https",
+    "git+ssh",
+    "git+file",
+    "mercurial",
+    "hg+http",
+    "hg+https",
+    "hg+ssh",
+    "hg+file",
+    "tarball",
+    "tarball+http",
+    "tarball+https",
+    "tarball+file",
+    "file",
+    "file+http",
+    "file+https",
+    "file+file",
+    "http",
+    "https",
+    "github",
+    "gitlab",
+    "sourcehut",
+];
+
+/// Check a flake reference (Eg `git+ssh://git@example.com/nixpkgs`) looks like something Nix
+/// understands, and can be placed in the generated flake
+pub(crate) fn parse_nixpkgs_flake(flake_ref: &str) -> color_eyre::Result<String> {
+    if flake_ref.is_empty()
+        || flake_ref
+            .chars()
+            .any(|c| c.is_whitespace() || c.is_control() || matches!(c, '"' | '\\' | '$'))
+    {
+        return Err(eyre!(
+            "`{flake_ref}` is not a flake reference, it must not be empty or contain whitespace, `\"`, `\\` or `$`"
+        ));
+    }
+    if let Some((flake_ref_type, rest)) = flake_ref.split_once(':') {
+        if !FLAKE_REF_TYPES.contains(&flake_ref_type) {
+            return Err(eyre!(
+                "`{flake_ref}` is not a flake reference, `{flake_ref_type}:` is not one of {}",
+                FLAKE_REF_TYPES.iter().map(|t| format!("`{t}:`")).join(", ")
+            ));
+        }
+        if rest.is_empty() {
+            return Err(eyre!(
+                "`{flake_ref}` is not a flake reference, it is missing everything after `{flake_ref_type}:`"
+            ));
+        }
+    }
+    Ok(flake_ref.to_string())
 }
 
 // TODO(@cole-h): should this become a trait that the various languages we may support have to implement?
@@ -32,39 +275,499 @@ impl<'a> DevEnvironment<'a> {
         Self {
             registry,
             build_inputs: Default::default(),
+            native_build_inputs: Default::default(),
             environment_variables: Default::default(),
             runtime_inputs: Default::default(),
+            darwin_frameworks: Default::default(),
             detected_languages: Default::default(),
+            target: None,
+            toolchain_inputs: Default::default(),
+            nixpkgs_rev: None,
+            nixpkgs_flake: None,
+            rust_toolchain: None,
+            rust_channel: None,
+            host_toolchain: false,
+            dev_dependencies: true,
+            contributions: Default::default(),
+            pkg_config_path: false,
+            shell_hooks: Default::default(),
+            unmapped_crates: Default::default(),
+            excluded_crates: Default::default(),
+            crate_overrides: Default::default(),
+            quiet: false,
+            no_cargo_metadata: false,
+            ignore_name_separators: false,
+            dev_shell_per_member: false,
+            member_shells: Default::default(),
+        }
+    }
+
+    /// The flake reference the generated flake uses for Nixpkgs
+    pub(crate) fn nixpkgs_url(&self) -> String {
+        match &self.nixpkgs_flake {
+            Some(nixpkgs_flake) => nixpkgs_flake.clone(),
+            None => format!(
+                "github:NixOS/nixpkgs/{}",
+                self.nixpkgs_rev.as_deref().unwrap_or(DEFAULT_NIXPKGS_REV)
+            ),
         }
     }
+    /// Generate the `flake.nix` for this environment
+    ///
+    /// Everything is emitted in a sorted order so the same environment always produces the same
+    /// flake.
+    ///
+    /// When cross compiling, the inputs from crates are also taken from the `pkgsCross` set of
+    /// the target so there are libraries to link against, and Cargo is pointed at its linker.
+    ///
+    /// The `default` shell provides the whole workspace, and each of the
+    /// [`DevEnvironment::member_shells`] is another shell named after its member.
     pub fn to_flake(&self) -> String {
+        let dev_shells = std::iter::once((
+            "default".to_string(),
+            "riff-shell".to_string(),
+            self.shell_attributes(),
+        ))
+        .chain(self.member_shells.iter().map(|(member, member_shell)| {
+            (
+                format!("\"{member}\""),
+                format!("riff-shell-{member}"),
+                self.with_member_shell(member_shell).shell_attributes(),
+            )
+        }))
+        .map(|(attribute, name, shell)| {
+            format!(
+                include_str!("dev-shell-template.inc"),
+                attribute = attribute,
+                name = name,
+                build_inputs = shell.build_inputs,
+                native_build_inputs = shell.native_build_inputs,
+                darwin_frameworks = shell.darwin_frameworks,
+                environment_variables = shell.environment_variables,
+                ld_library_path = shell.ld_library_path,
+            )
+        })
+        .collect::<String>();
         // TODO: use rnix for generating Nix?
         format!(
             include_str!("flake-template.inc"),
-            build_inputs = self.build_inputs.iter().join(" "),
-            environment_variables = self
+            inputs = self.flake_inputs(),
+            extra_outputs_args = if self.rust_toolchain.is_some() {
+                ", rust-overlay"
+            } else {
+                ""
+            },
+            overlays = if self.rust_toolchain.is_some() {
+                " overlays = [ rust-overlay.overlays.default ];"
+            } else {
+                ""
+            },
+            dev_shells = dev_shells.trim_end(),
+        )
+    }
+
+    /// The `inputs` of the `flake.nix`, which are all its `flake.lock` depends on
+    pub(crate) fn flake_inputs(&self) -> String {
+        let mut inputs = format!("  inputs.nixpkgs.url = \"{}\";", self.nixpkgs_url());
+        if self.rust_toolchain.is_some() {
+            inputs.push_str("\n  inputs.rust-overlay.url = \"github:oxalica/rust-overlay\";\n  inputs.rust-overlay.inputs.nixpkgs.follows = \"nixpkgs\";");
+        }
+        inputs
+    }
+
+    /// This environment with the inputs of a workspace member's shell instead of the workspace's
+    fn with_member_shell(&self, member_shell: &MemberShell) -> Self {
+        let mut dev_env = self.clone();
+        dev_env.build_inputs = member_shell.build_inputs.clone();
+        dev_env.native_build_inputs = member_shell.native_build_inputs.clone();
+        dev_env.environment_variables = member_shell.environment_variables.clone();
+        dev_env.runtime_inputs = member_shell.runtime_inputs.clone();
+        dev_env.darwin_frameworks = member_shell.darwin_frameworks.clone();
+        dev_env.pkg_config_path = member_shell.pkg_config_path;
+        dev_env
+    }
+
+    /// Add a build input to the environment, and to every workspace member's shell
+    pub(crate) fn add_build_input(&mut self, build_input: String) {
+        for member_shell in self.member_shells.values_mut() {
+            member_shell.build_inputs.insert(build_input.clone());
+        }
+        self.build_inputs.insert(build_input);
+    }
+
+    /// Set an environment variable in the environment, and in every workspace member's shell
+    pub(crate) fn set_environment_variable(&mut self, key: String, value: String) {
+        for member_shell in self.member_shells.values_mut() {
+            member_shell
                 .environment_variables
+                .insert(key.clone(), value.clone());
+        }
+        self.environment_variables.insert(key, value);
+    }
+
+    /// Generate a `shell.nix` for this environment, for `nix-shell` on Nix installations without
+    /// flakes
+    ///
+    /// It provides the same environment as [`DevEnvironment::to_flake`], but Nixpkgs is fetched
+    /// with `builtins.fetchTarball`, so it is only pinned if a git revision is. A flake reference
+    /// set with `--nixpkgs-flake` can't be used.
+    pub fn to_shell_nix(&self) -> color_eyre::Result<String> {
+        if let Some(nixpkgs_flake) = &self.nixpkgs_flake {
+            return Err(eyre!(
+                "The Nixpkgs flake reference `{nixpkgs_flake}` can't be used without flakes, pass `--nixpkgs-rev` instead"
+            ));
+        }
+        let shell = self.shell_attributes();
+        Ok(format!(
+            include_str!("shell-template.inc"),
+            nixpkgs_rev = self.nixpkgs_rev.as_deref().unwrap_or(DEFAULT_NIXPKGS_REV),
+            extra_inputs = if self.rust_toolchain.is_some() {
+                "\n  rust-overlay = import (builtins.fetchTarball \"https://github.com/oxalica/rust-overlay/archive/master.tar.gz\");"
+            } else {
+                ""
+            },
+            overlays = if self.rust_toolchain.is_some() {
+                " overlays = [ rust-overlay ];"
+            } else {
+                ""
+            },
+            build_inputs = shell.build_inputs,
+            native_build_inputs = shell.native_build_inputs,
+            darwin_frameworks = shell.darwin_frameworks,
+            environment_variables = shell.environment_variables,
+            ld_library_path = shell.ld_library_path,
+        ))
+    }
+
+    /// The attributes of the shell derivation, shared by the `flake.nix` and `shell.nix`
+    fn shell_attributes(&self) -> ShellAttributes {
+        let mut build_inputs = match &self.rust_toolchain {
+            // The pinned toolchain replaces the one from Nixpkgs.
+            Some(rust_toolchain) => self
+                .build_inputs
+                .difference(&self.toolchain_inputs)
+                .sorted()
+                .cloned()
+                .chain([rust_toolchain.to_nix(
+                    &self
+                        .target
+                        .iter()
+                        .map(|target| target.triple.as_str())
+                        .collect::<Vec<_>>(),
+                )])
+                .collect::<Vec<_>>(),
+            None if self.host_toolchain => self
+                .build_inputs
+                .difference(&self.toolchain_inputs)
+                .sorted()
+                .cloned()
+                .collect::<Vec<_>>(),
+            None => self
+                .build_inputs
+                .iter()
+                .sorted()
+                .cloned()
+                .collect::<Vec<_>>(),
+        };
+        // The registry lists frameworks in both forms, for riff releases without `darwin-frameworks`
+        build_inputs.retain(|input| {
+            !self
+                .darwin_frameworks
+                .iter()
+                .any(|framework| *input == format!("darwin.apple_sdk.frameworks.{framework}"))
+        });
+        let mut environment_variables = self.environment_variables.clone();
+        if self.pkg_config_path {
+            // First, so the `shellHook`s of crates and the project can change it
+            let hook = self.pkg_config_path_hook();
+            let shell_hook = match environment_variables.remove(SHELL_HOOK) {
+                Some(shell_hook) => format!("{hook}\n{shell_hook}"),
+                None => hook,
+            };
+            environment_variables.insert(SHELL_HOOK.to_string(), shell_hook);
+        }
+        if let Some(target) = &self.target {
+            let cross_pkgs = format!("pkgsCross.{}", target.nixpkgs_cross);
+            build_inputs.extend(
+                self.build_inputs
+                    .difference(&self.toolchain_inputs)
+                    .sorted()
+                    .map(|input| format!("{cross_pkgs}.{input}")),
+            );
+            let cross_cc = format!(
+                "${{{cross_pkgs}.stdenv.cc}}/bin/${{{cross_pkgs}.stdenv.cc.targetPrefix}}cc"
+            );
+            environment_variables.insert("CARGO_BUILD_TARGET".into(), target.triple.clone());
+            environment_variables.insert(
+                format!("CARGO_TARGET_{}_LINKER", target.env_suffix()),
+                cross_cc.clone(),
+            );
+            environment_variables
+                .insert(format!("CC_{}", target.triple.replace('-', "_")), cross_cc);
+            environment_variables.insert("PKG_CONFIG_ALLOW_CROSS".into(), "1".into());
+        }
+
+        ShellAttributes {
+            build_inputs: build_inputs.join(" "),
+            native_build_inputs: self.native_build_inputs.iter().sorted().join(" "),
+            darwin_frameworks: self
+                .darwin_frameworks
                 .iter()
+                .sorted()
+                .map(|framework| format!("darwin.apple_sdk.frameworks.{framework}"))
+                .join(" "),
+            // Sorted by name, wherever they were set, so the same environment is the same flake
+            environment_variables: environment_variables
+                .iter()
+                .sorted_by_key(|(name, _)| *name)
                 .map(|(name, value)| format!("\"{name}\" = \"{value}\";"))
                 .join("\n"),
-            ld_library_path = if !self.runtime_inputs.is_empty() {
+            ld_library_path: if !self.runtime_inputs.is_empty() {
                 format!(
                     "\"LD_LIBRARY_PATH\" = \"{}\";",
                     self.runtime_inputs
                         .iter()
+                        .sorted()
                         .map(|v| format!("${{lib.getLib {v}}}/lib"))
                         .join(":")
                 )
             } else {
                 "".to_string()
-            }
+            },
+        }
+    }
+
+    /// A `shellHook` line adding the `pkg-config` directories of the build inputs (those for the
+    /// target, when cross compiling) to `PKG_CONFIG_PATH`, keeping anything already on it
+    fn pkg_config_path_hook(&self) -> String {
+        let inputs = self
+            .build_inputs
+            .difference(&self.toolchain_inputs)
+            .sorted()
+            .map(|input| match &self.target {
+                Some(target) => format!("pkgsCross.{}.{input}", target.nixpkgs_cross),
+                None => input.clone(),
+            })
+            .join(" ");
+        let search_path =
+            |dir: &str| format!("${{lib.makeSearchPathOutput \"dev\" \"{dir}\" [ {inputs} ]}}");
+        format!(
+            "export PKG_CONFIG_PATH=\\\"{lib}:{share}\\${{PKG_CONFIG_PATH:+:$PKG_CONFIG_PATH}}\\\"",
+            lib = search_path("lib/pkgconfig"),
+            share = search_path("share/pkgconfig"),
         )
     }
 
-    pub async fn detect(&mut self, project_dir: &Path) -> color_eyre::Result<()> {
+    /// Apply the registry's settings for `version` of the crate `name`, if it has any, with
+    /// `features` of it enabled
+    ///
+    /// Nothing is applied if the project overrides the crate without `augment`, see
+    /// [`DevEnvironment::apply_crate_override`].
+    pub(crate) fn apply_crate(
+        &mut self,
+        rust_registry: &RustDependencyRegistryData,
+        name: &str,
+        version: &semver::Version,
+        features: &HashSet<String>,
+    ) {
+        if let Some(crate_override) = self.crate_overrides.get(&name.to_lowercase()) {
+            if !crate_override.augment {
+                tracing::trace!(package_name = %name, "Replacing the registry's settings with the override");
+                return;
+            }
+        }
+        if let Some((_, dep_config)) =
+            rust_registry.find_dependency(name, self.ignore_name_separators)
+        {
+            let dep_config = dep_config.for_version(version);
+            tracing::debug!(
+                package_name = %name,
+                "build-inputs" = %dep_config.build_inputs().iter().join(", "),
+                "native-build-inputs" = %dep_config.native_build_inputs().iter().join(", "),
+                "environment-variables" = %dep_config.environment_variables().iter().map(|(k, v)| format!("{k}={v}")).join(", "),
+                "runtime-inputs" = %dep_config.runtime_inputs().iter().join(", "),
+                "Detected known crate information"
+            );
+            self.apply_dependency(
+                dep_config,
+                name,
+                version,
+                CrateSettingsSource::Registry,
+                features,
+            );
+        }
+    }
+
+    /// Apply the project's override for `version` of the crate `name`, if it has one, with
+    /// `features` of it enabled
+    ///
+    /// This is done after every crate's other settings are applied, so the override wins.
+    pub(crate) fn apply_crate_override(
+        &mut self,
+        name: &str,
+        version: &semver::Version,
+        features: &HashSet<String>,
+    ) {
+        let crate_override = match self.crate_overrides.get(&name.to_lowercase()) {
+            Some(crate_override) => crate_override.clone(),
+            None => return,
+        };
+        let dep_config = crate_override.data.for_version(version);
+        tracing::debug!(
+            package_name = %name,
+            augment = crate_override.augment,
+            "build-inputs" = %dep_config.build_inputs().iter().join(", "),
+            "native-build-inputs" = %dep_config.native_build_inputs().iter().join(", "),
+            "environment-variables" = %dep_config.environment_variables().iter().map(|(k, v)| format!("{k}={v}")).join(", "),
+            "runtime-inputs" = %dep_config.runtime_inputs().iter().join(", "),
+            "Detected `package.metadata.riff.overrides` for the crate"
+        );
+        self.apply_dependency(
+            dep_config,
+            name,
+            version,
+            CrateSettingsSource::Override,
+            features,
+        );
+    }
+
+    /// Apply `dep_config`, with `features` enabled, recording what it added for the crate `name`
+    fn apply_dependency(
+        &mut self,
+        dep_config: &RustDependencyData,
+        name: &str,
+        version: &semver::Version,
+        source: CrateSettingsSource,
+        features: &HashSet<String>,
+    ) {
+        let mut contributed = DevEnvironment::new(self.registry);
+        dep_config.apply(&mut contributed);
+        dep_config.apply_features(features, &mut contributed);
+        // Keep a `shellHook` set before any crate's, like the language default's
+        if let (true, Some(shell_hook)) = (
+            self.shell_hooks.is_empty(),
+            self.environment_variables.get(SHELL_HOOK),
+        ) {
+            self.shell_hooks
+                .push((CrateSettingsSource::Registry, shell_hook.clone()));
+        }
+        dep_config.apply(self);
+        dep_config.apply_features(features, self);
+        if let Some(shell_hook) = contributed.environment_variables.get(SHELL_HOOK) {
+            self.add_shell_hook(source, shell_hook);
+        }
+        if source == CrateSettingsSource::Registry {
+            self.keep_package_metadata_variables(contributed.environment_variables.keys());
+        }
+        self.contributions.push(CrateContribution {
+            name: name.to_string(),
+            version: version.clone(),
+            source,
+            build_inputs: contributed.build_inputs.into_iter().collect(),
+            native_build_inputs: contributed.native_build_inputs.into_iter().collect(),
+            environment_variables: contributed.environment_variables.into_iter().collect(),
+            runtime_inputs: contributed.runtime_inputs.into_iter().collect(),
+            darwin_frameworks: contributed.darwin_frameworks.into_iter().collect(),
+        });
+    }
+
+    /// Set the variables in `keys` back to the value a `package.metadata.riff` gave them, if one
+    /// did, so the registry's settings for a crate found later don't override a project's own
+    fn keep_package_metadata_variables<'k>(&mut self, keys: impl IntoIterator<Item = &'k String>) {
+        for key in keys {
+            if key == SHELL_HOOK {
+                continue;
+            }
+            let metadata_value = self
+                .contributions
+                .iter()
+                .rev()
+                .filter(|contribution| contribution.source == CrateSettingsSource::PackageMetadata)
+                .find_map(|contribution| contribution.environment_variables.get(key));
+            if let Some(metadata_value) = metadata_value {
+                tracing::debug!(
+                    %key,
+                    value = %metadata_value,
+                    "Keeping the value from `package.metadata.riff` over the registry's"
+                );
+                self.environment_variables
+                    .insert(key.clone(), metadata_value.clone());
+            }
+        }
+    }
+
+    /// Add `shell_hook` to those run when the environment is entered
+    ///
+    /// The hooks are joined with newlines, those from the registry first and those from
+    /// `package.metadata.riff` last so a project can override what the registry sets up. A hook
+    /// contributed more than once, Eg by two versions of a crate, is only run once.
+    fn add_shell_hook(&mut self, source: CrateSettingsSource, shell_hook: &str) {
+        if !self.shell_hooks.iter().any(|(_, hook)| hook == shell_hook) {
+            self.shell_hooks.push((source, shell_hook.to_string()));
+        }
+        let shell_hook = self
+            .shell_hooks
+            .iter()
+            .sorted_by_key(|(source, _)| *source)
+            .map(|(_, hook)| hook)
+            .join("\n");
+        self.environment_variables
+            .insert(SHELL_HOOK.to_string(), shell_hook);
+    }
+
+    /// Leave the crate `name` out of the environment, matched case-insensitively
+    pub(crate) fn exclude_crate(&mut self, name: &str) {
+        self.excluded_crates.insert(name.to_lowercase());
+    }
+
+    /// Read a workspace member's `package.metadata.riff.overrides`, erroring if another member
+    /// already overrides one of the same crates
+    fn add_member_overrides(
+        &mut self,
+        riff_object: &serde_json::Value,
+        package: &str,
+    ) -> color_eyre::Result<()> {
+        let overrides = match riff_object.get("overrides") {
+            Some(overrides) => overrides,
+            None => return Ok(()),
+        };
+        let overrides: BTreeMap<String, RustDependencyOverride> =
+            serde_json::from_value(overrides.clone()).wrap_err_with(|| {
+                format!(
+                    "Invalid `package.metadata.riff.overrides` in the `Cargo.toml` of `{package}`"
+                )
+            })?;
+        for (name, crate_override) in overrides {
+            tracing::trace!(%package, %name, augment = crate_override.augment, "Overriding a crate from `package.metadata.riff.overrides`");
+            if self
+                .crate_overrides
+                .insert(name.to_lowercase(), crate_override)
+                .is_some()
+            {
+                return Err(eyre!(
+                    "`{name}` is overridden more than once in `package.metadata.riff.overrides`"
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Detect the dependencies of the project in `project_dir`
+    ///
+    /// In a workspace, the dependencies of every member are detected, unless `package` names a
+    /// single member to restrict detection to. With [`DevEnvironment::dev_shell_per_member`],
+    /// every member is detected instead and `package` is only checked to be one, and each
+    /// member's own dependencies are also kept in [`DevEnvironment::member_shells`].
+    pub async fn detect(
+        &mut self,
+        project_dir: &Path,
+        features: &CargoFeatures,
+        package: Option<&str>,
+    ) -> color_eyre::Result<()> {
         if project_dir.join("Cargo.toml").exists() {
             self.detected_languages.insert(DetectedLanguage::Rust);
-            self.add_deps_from_cargo(project_dir).await?;
+            self.add_deps_from_cargo(project_dir, features, package)
+                .await?;
             Ok(())
         } else {
             Err(eyre!(
@@ -74,80 +777,135 @@ impl<'a> DevEnvironment<'a> {
         }
     }
 
+    /// Detect the dependencies in `metadata`, Eg of a crate read from the crates.io index rather
+    /// than a project on disk
+    pub(crate) async fn detect_metadata(
+        &mut self,
+        metadata: CargoMetadata,
+    ) -> color_eyre::Result<()> {
+        self.detected_languages.insert(DetectedLanguage::Rust);
+        self.apply_cargo_metadata(metadata, None).await
+    }
+
     #[tracing::instrument(skip_all, fields(project_dir = %project_dir.display()))]
-    async fn add_deps_from_cargo(&mut self, project_dir: &Path) -> color_eyre::Result<()> {
+    async fn add_deps_from_cargo(
+        &mut self,
+        project_dir: &Path,
+        features: &CargoFeatures,
+        package: Option<&str>,
+    ) -> color_eyre::Result<()> {
         tracing::debug!("Adding Cargo dependencies...");
 
-        let mut cargo_metadata_command = Command::new("cargo");
-        cargo_metadata_command.args(["metadata", "--format-version", "1"]);
-        cargo_metadata_command.arg("--manifest-path");
-        cargo_metadata_command.arg(project_dir.join("Cargo.toml"));
+        let metadata = if self.no_cargo_metadata {
+            tracing::debug!("Reading `Cargo.lock` instead of running `cargo metadata`");
+            CargoMetadata::from_lockfile(project_dir, features).await?
+        } else {
+            match self.cargo_metadata(project_dir, features).await? {
+                Some(metadata) => metadata,
+                None => {
+                    if !self.quiet {
+                        eprintln!(
+                            "{warning} `{cargo}` was not found, reading `{cargo_lock}` instead. Dependencies only needed by some features may be missed.",
+                            warning = "!".yellow(),
+                            cargo = "cargo".cyan(),
+                            cargo_lock = "Cargo.lock".cyan(),
+                        );
+                    }
+                    CargoMetadata::from_lockfile(project_dir, features).await?
+                }
+            }
+        };
 
-        // Infer offline-ness from our stored registry
-        if self.registry.offline() {
-            cargo_metadata_command.arg("--offline");
+        let members = metadata
+            .packages
+            .iter()
+            .filter(|package| metadata.workspace_members.contains(&package.id))
+            .map(|package| package.name.clone())
+            .sorted()
+            .collect::<Vec<_>>();
+        if !self.dev_shell_per_member || (package.is_none() && members.len() < 2) {
+            return self.apply_cargo_metadata(metadata, package).await;
         }
-
-        tracing::trace!(command = ?cargo_metadata_command.as_std(), "Running");
-        let spinner = SimpleSpinner::new_with_message(Some(&format!(
-            "Running `{cargo_metadata}`",
-            cargo_metadata = "cargo metadata".cyan()
-        )))
-        .context("Failed to construct progress spinner")?;
-
-        let cargo_metadata_output = match cargo_metadata_command.output().await {
-            Ok(output) => output,
-            Err(err) => {
-                let err_msg = format!(
-                    "\
-                    Could not execute `{cargo_metadata}`. Is `{cargo}` installed?\n\n\
-                    Get instructions for installing Cargo: {rust_install_url}\
-                    ",
-                    cargo_metadata = "cargo metadata".cyan(),
-                    cargo = "cargo".cyan(),
-                    rust_install_url = "https://www.rust-lang.org/tools/install".blue().underline()
-                );
-                eprintln!("{err_msg}\n\nUnderlying error:\n{err}", err = err.red());
-                std::process::exit(1);
+        // Check `package` is a member before detecting anything
+        metadata.clone().restrict(package, self.dev_dependencies)?;
+        for member in members {
+            if member == "default" {
+                tracing::debug!("Not generating a shell for the member `default`, whose name is taken by the workspace's shell");
+                continue;
             }
-        };
+            let mut member_env = self.clone();
+            // Only the chosen member's dependencies are printed
+            member_env.quiet = self.quiet || package != Some(member.as_str());
+            member_env
+                .apply_cargo_metadata(metadata.clone(), Some(&member))
+                .await?;
+            self.member_shells
+                .insert(member, MemberShell::of(&member_env));
+        }
+        let quiet = self.quiet;
+        self.quiet = quiet || package.is_some();
+        let result = self.apply_cargo_metadata(metadata, None).await;
+        self.quiet = quiet;
+        result
+    }
 
-        spinner.finish_and_clear();
+    /// Add the dependencies in `metadata` of the workspace, or just its `package` member
+    async fn apply_cargo_metadata(
+        &mut self,
+        mut metadata: CargoMetadata,
+        package: Option<&str>,
+    ) -> color_eyre::Result<()> {
+        metadata.restrict(package, self.dev_dependencies)?;
 
-        if !cargo_metadata_output.status.success() {
-            return Err(eyre!(
-                "`cargo metadata` exited with code {}:\n{}",
-                cargo_metadata_output
-                    .status
-                    .code()
-                    .map(|x| x.to_string())
-                    .unwrap_or_else(|| "unknown".to_string()),
-                std::str::from_utf8(&cargo_metadata_output.stderr)?,
-            ));
+        // Read every member's exclusions first, so they apply to crates seen before the member.
+        for package in &metadata.packages {
+            if !metadata.workspace_members.contains(&package.id) {
+                continue;
+            }
+            if let Some(riff_object) = package.metadata.as_ref().and_then(|m| m.riff.as_ref()) {
+                for name in member_excluded_crates(riff_object, &package.name)? {
+                    tracing::trace!(package = %package.name, %name, "Excluding a crate from `package.metadata.riff.exclude`");
+                    self.exclude_crate(&name);
+                }
+                self.add_member_overrides(riff_object, &package.name)?;
+            }
         }
 
-        let cargo_metadata_output = std::str::from_utf8(&cargo_metadata_output.stdout)
-            .wrap_err("Output produced by `cargo metadata` was not valid UTF8")?;
-        let metadata: CargoMetadata = serde_json::from_str(cargo_metadata_output).wrap_err(
-            "Unable to parse output produced by `cargo metadata` into our desired structure",
-        )?;
-
-        tracing::debug!(fresh = %self.registry.fresh(), "Cache freshness");
-        let language_registry = self.registry.language().await.clone();
+        tracing::debug!(refresh_state = %self.registry.refresh_state(), "Registry refresh");
+        let registry = self.registry;
+        let language_registry = registry.language().await;
         language_registry.rust.default.apply(self);
+        self.toolchain_inputs = language_registry.rust.default.build_inputs.clone();
 
+        let enabled_features = metadata.enabled_features();
+        let no_features = HashSet::new();
+        let mut overridden = Vec::new();
         for package in metadata.packages {
             let name = package.name;
+            if self.excluded_crates.contains(&name.to_lowercase()) {
+                tracing::trace!(package = %name, "Skipping an excluded crate");
+                continue;
+            }
+            let package_features = enabled_features.get(&package.id).unwrap_or(&no_features);
+            if self.crate_overrides.contains_key(&name.to_lowercase()) {
+                overridden.push((name.clone(), package.version.clone(), package_features));
+            }
 
-            if let Some(dep_config) = language_registry.rust.dependencies.get(name.as_str()) {
-                tracing::debug!(
-                    package_name = %name,
-                    "build-inputs" = %dep_config.build_inputs().iter().join(", "),
-                    "environment-variables" = %dep_config.environment_variables().iter().map(|(k, v)| format!("{k}={v}")).join(", "),
-                    "runtime-inputs" = %dep_config.runtime_inputs().iter().join(", "),
-                    "Detected known crate information"
-                );
-                dep_config.clone().apply(self);
+            self.apply_crate(
+                &language_registry.rust,
+                &name,
+                &package.version,
+                package_features,
+            );
+            if is_system_crate(&name)
+                && language_registry
+                    .rust
+                    .find_dependency(&name, self.ignore_name_separators)
+                    .is_none()
+                && !self.crate_overrides.contains_key(&name.to_lowercase())
+                && !matches!(&package.metadata, Some(metadata) if metadata.riff.is_some())
+            {
+                self.unmapped_crates.insert(name.clone());
             }
 
             let metadata_object = match package.metadata {
@@ -155,21 +913,70 @@ impl<'a> DevEnvironment<'a> {
                 None => continue,
             };
 
-            let dep_config = match metadata_object.riff {
-                Some(riff_object) => riff_object,
+            // Settings for the whole environment are only read from workspace members.
+            let workspace_member = metadata.workspace_members.contains(&package.id);
+            if let (true, Some(riff_object)) = (workspace_member, &metadata_object.riff) {
+                merge_member_setting(
+                    &mut self.nixpkgs_rev,
+                    riff_object,
+                    "nixpkgs",
+                    &name,
+                    parse_nixpkgs_rev,
+                )?;
+                merge_member_setting(
+                    &mut self.rust_channel,
+                    riff_object,
+                    "rust-channel",
+                    &name,
+                    str::parse,
+                )?;
+            }
+
+            let mut dep_config: RustDependencyData = match metadata_object.riff {
+                Some(riff_object) => match serde_json::from_value(riff_object) {
+                    Ok(dep_config) => dep_config,
+                    Err(err) => {
+                        // Parse the manifest itself, to point at where in it the problem is
+                        if let Some(manifest_path) = &package.manifest_path {
+                            check_manifest_metadata(&name, manifest_path).await?;
+                        }
+                        return Err(err).wrap_err_with(|| {
+                            format!(
+                                "Invalid `package.metadata.riff` in the `Cargo.toml` of `{name}`"
+                            )
+                        });
+                    }
+                },
                 None => continue,
             };
+            // Only the project's own settings may read the environment riff runs in.
+            if workspace_member {
+                dep_config.expand_environment_variables();
+            }
 
             tracing::debug!(
                 package = %name,
                 "build-inputs" = %dep_config.build_inputs().iter().join(", "),
+                "native-build-inputs" = %dep_config.native_build_inputs().iter().join(", "),
                 "environment-variables" = %dep_config.environment_variables().iter().map(|(k, v)| format!("{k}={v}")).join(", "),
                 "runtime-inputs" = %dep_config.runtime_inputs().iter().join(", "),
                 "Detected `package.metadata.riff` in `Crate.toml`"
             );
-            dep_config.apply(self);
+            self.apply_dependency(
+                &dep_config,
+                &name,
+                &package.version,
+                CrateSettingsSource::PackageMetadata,
+                package_features,
+            );
+        }
+        for (name, version, features) in overridden {
+            self.apply_crate_override(&name, &version, features);
         }
 
+        if self.quiet {
+            return Ok(());
+        }
         eprintln!(
             "{check} {lang}: {colored_inputs}{maybe_colored_envs}",
             check = "✓".green(),
@@ -177,7 +984,10 @@ impl<'a> DevEnvironment<'a> {
             colored_inputs = {
                 let mut sorted_build_inputs = self
                     .build_inputs
-                    .union(&self.runtime_inputs)
+                    .iter()
+                    .chain(&self.native_build_inputs)
+                    .chain(&self.runtime_inputs)
+                    .unique()
                     .collect::<Vec<_>>();
                 sorted_build_inputs.sort();
                 sorted_build_inputs.iter().map(|v| v.cyan()).join(", ")
@@ -199,9 +1009,206 @@ impl<'a> DevEnvironment<'a> {
                 }
             }
         );
+        self.warn_unmapped_crates();
 
         Ok(())
     }
+
+    /// Warn about the crates in [`DevEnvironment::unmapped_crates`], whose system libraries
+    /// are likely missing from the environment
+    fn warn_unmapped_crates(&self) {
+        if self.unmapped_crates.is_empty() {
+            return;
+        }
+        eprintln!(
+            "{warning} riff doesn't know what these crates need, their system libraries may be missing: {crates}",
+            warning = "!".yellow(),
+            crates = self.unmapped_crates.iter().map(|name| name.cyan()).join(", "),
+        );
+        eprintln!(
+            "  Declare what they need in `{metadata}`, and please contribute it to the registry: {url}",
+            metadata = "package.metadata.riff".cyan(),
+            url = REGISTRY_CONTRIBUTING_URL.cyan(),
+        );
+    }
+
+    /// Run `cargo metadata` on the project in `project_dir`, or `None` if `cargo` isn't installed
+    async fn cargo_metadata(
+        &self,
+        project_dir: &Path,
+        features: &CargoFeatures,
+    ) -> color_eyre::Result<Option<CargoMetadata>> {
+        let mut cargo_metadata_command = Command::new("cargo");
+        cargo_metadata_command.args(["metadata", "--format-version", "1"]);
+        cargo_metadata_command.arg("--manifest-path");
+        cargo_metadata_command.arg(project_dir.join("Cargo.toml"));
+        features.apply(&mut cargo_metadata_command);
+        // Skip dependencies for other platforms, Eg `[target.'cfg(windows)'.dependencies]` on
+        // Linux. Build scripts and proc macros run on the host, so the host's dependencies are
+        // needed when cross compiling too.
+        cargo_metadata_command
+            .arg("--filter-platform")
+            .arg(target_lexicon::HOST.to_string());
+        if let Some(target) = &self.target {
+            cargo_metadata_command
+                .arg("--filter-platform")
+                .arg(&target.triple);
+        }
+
+        // Infer offline-ness from our stored registry
+        if self.registry.offline() {
+            cargo_metadata_command.arg("--offline");
+        }
+
+        // The versions registry rules match are those `cargo metadata` reports as locked
+        if !project_dir.join("Cargo.lock").exists() {
+            tracing::trace!(
+                "No `Cargo.lock`, so dependency versions are the newest matching the requirements in `Cargo.toml` and may differ from those later built"
+            );
+        }
+
+        tracing::trace!(command = ?cargo_metadata_command.as_std(), "Running");
+        let spinner = if self.quiet {
+            indicatif::ProgressBar::hidden()
+        } else {
+            SimpleSpinner::new_with_message(Some(&format!(
+                "Running `{cargo_metadata}`",
+                cargo_metadata = "cargo metadata".cyan()
+            )))
+            .context("Failed to construct progress spinner")?
+        };
+
+        let cargo_metadata_output = match cargo_metadata_command.output().await {
+            Ok(output) => output,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                spinner.finish_and_clear();
+                tracing::debug!(%err, "Could not run `cargo metadata`");
+                return Ok(None);
+            }
+            Err(err) => {
+                spinner.finish_and_clear();
+                return Err(SpawnError::new("cargo metadata", Tool::Cargo, err).into());
+            }
+        };
+
+        spinner.finish_and_clear();
+
+        if !cargo_metadata_output.status.success() {
+            return Err(eyre!(
+                "`cargo metadata` exited with code {}:\n{}",
+                cargo_metadata_output
+                    .status
+                    .code()
+                    .map(|x| x.to_string())
+                    .unwrap_or_else(|| "unknown".to_string()),
+                std::str::from_utf8(&cargo_metadata_output.stderr)?,
+            ));
+        }
+
+        let cargo_metadata_output = std::str::from_utf8(&cargo_metadata_output.stdout)
+            .wrap_err("Output produced by `cargo metadata` was not valid UTF8")?;
+        let metadata = serde_json::from_str(cargo_metadata_output).wrap_err(
+            "Unable to parse output produced by `cargo metadata` into our desired structure",
+        )?;
+        Ok(Some(metadata))
+    }
+}
+
+/// Check the `package.metadata.riff` of `package`'s `Cargo.toml` at `manifest_path` can be read,
+/// with an error pointing at the problem in the file if it can't
+async fn check_manifest_metadata(package: &str, manifest_path: &Path) -> color_eyre::Result<()> {
+    #[derive(serde::Deserialize)]
+    struct Manifest<T> {
+        #[serde(rename = "package")]
+        _package: Package<T>,
+    }
+    #[derive(serde::Deserialize)]
+    struct Package<T> {
+        #[serde(rename = "metadata")]
+        _metadata: Metadata<T>,
+    }
+    #[derive(serde::Deserialize)]
+    struct Metadata<T> {
+        #[serde(rename = "riff")]
+        _riff: T,
+    }
+
+    let manifest = tokio::fs::read_to_string(manifest_path)
+        .await
+        .wrap_err_with(|| format!("Unable to read `{}`", manifest_path.display()))?;
+    // The settings for every target are flattened into `RustDependencyData`, which loses where
+    // in the file a problem with them is, so they are read on their own first.
+    let result = toml_edit::de::from_str::<Manifest<RustDependencyTargetData>>(&manifest)
+        .and_then(|_| toml_edit::de::from_str::<Manifest<RustDependencyData>>(&manifest));
+    match result {
+        Ok(_) => Ok(()),
+        Err(err) => Err(ProjectError::InvalidMetadata(
+            package.to_string(),
+            manifest_path.to_path_buf(),
+            err,
+        )
+        .into()),
+    }
+}
+
+/// The crates a workspace member's `package.metadata.riff.exclude` leaves out of the environment
+fn member_excluded_crates(
+    riff_object: &serde_json::Value,
+    package: &str,
+) -> color_eyre::Result<Vec<String>> {
+    let value = match riff_object.get("exclude") {
+        Some(value) => value,
+        None => return Ok(Vec::new()),
+    };
+    value
+        .as_array()
+        .and_then(|names| {
+            names
+                .iter()
+                .map(|name| name.as_str().filter(|name| !name.is_empty()))
+                .map(|name| name.map(str::to_string))
+                .collect()
+        })
+        .ok_or_else(|| {
+            eyre!(
+                "`package.metadata.riff.exclude` in the `Cargo.toml` of `{package}` must be a list of crate names"
+            )
+        })
+}
+
+/// Read a setting from the `package.metadata.riff` of a workspace member into `existing`,
+/// erroring if it differs from another member's
+fn merge_member_setting<T: PartialEq + std::fmt::Debug>(
+    existing: &mut Option<T>,
+    riff_object: &serde_json::Value,
+    key: &str,
+    package: &str,
+    parse: impl Fn(&str) -> color_eyre::Result<T>,
+) -> color_eyre::Result<()> {
+    let value = match riff_object.get(key) {
+        Some(value) => value,
+        None => return Ok(()),
+    };
+    let value: T = value
+        .as_str()
+        .ok_or_else(|| {
+            eyre!(
+                "`package.metadata.riff.{key}` in the `Cargo.toml` of `{package}` must be a string"
+            )
+        })
+        .and_then(parse)
+        .wrap_err_with(|| {
+            format!("Invalid `package.metadata.riff.{key}` in the `Cargo.toml` of `{package}`")
+        })?;
+    match existing {
+        Some(existing) if *existing != value => Err(eyre!(
+            "Workspace members set different values for `package.metadata.riff.{key}`: {existing:?} and {value:?}"
+        )),
+        _ => {
+            *existing = Some(value);
+            Ok(())
+        }
+    }
 }
 
 pub(crate) trait DevEnvironmentAppliable {
@@ -211,6 +1218,7 @@ pub(crate) trait DevEnvironmentAppliable {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::dependency_registry::DependencyRegistryOptions;
     use tempfile::TempDir;
     use tokio::fs::write;
 
@@ -218,21 +1226,46 @@ mod tests {
     async fn dev_env_to_flake() -> eyre::Result<()> {
         let cache_dir = TempDir::new()?;
         std::env::set_var("XDG_CACHE_HOME", cache_dir.path());
-        let registry = DependencyRegistry::new(true).await?;
+        let registry =
+            DependencyRegistry::new(&DependencyRegistryOptions::new().offline(true)).await?;
         let dev_env = DevEnvironment {
             build_inputs: ["cargo", "hello"]
                 .into_iter()
                 .map(ToString::to_string)
                 .collect(),
-            environment_variables: [("HELLO", "WORLD"), ("GOODBYE", "WORLD")]
+            native_build_inputs: ["pkg-config"]
                 .into_iter()
-                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .map(ToString::to_string)
+                .collect(),
+            environment_variables: [("HELLO", "WORLD"), ("GOODBYE", "WORLD")]
+                .into_iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
                 .collect(),
             runtime_inputs: ["nix", "libGL"]
                 .into_iter()
                 .map(ToString::to_string)
                 .collect(),
+            darwin_frameworks: Default::default(),
             detected_languages: vec![DetectedLanguage::Rust].into_iter().collect(),
+            target: None,
+            toolchain_inputs: ["cargo"].into_iter().map(ToString::to_string).collect(),
+            nixpkgs_rev: None,
+            nixpkgs_flake: None,
+            rust_toolchain: None,
+            rust_channel: None,
+            host_toolchain: false,
+            dev_dependencies: true,
+            contributions: Default::default(),
+            pkg_config_path: false,
+            shell_hooks: Default::default(),
+            unmapped_crates: Default::default(),
+            excluded_crates: Default::default(),
+            crate_overrides: Default::default(),
+            quiet: false,
+            no_cargo_metadata: false,
+            ignore_name_separators: false,
+            dev_shell_per_member: false,
+            member_shells: Default::default(),
             registry: &registry,
         };
 
@@ -243,11 +1276,260 @@ mod tests {
         );
         assert!(flake.contains(r#""GOODBYE" = "WORLD""#));
         assert!(flake.contains(r#""HELLO" = "WORLD""#));
+        assert!(flake
+            .contains(r#""LD_LIBRARY_PATH" = "${lib.getLib libGL}/lib:${lib.getLib nix}/lib";"#));
+        assert!(flake.find(r#""GOODBYE""#) < flake.find(r#""HELLO""#));
+        assert_eq!(flake, dev_env.clone().to_flake());
+        assert!(!flake.contains("pkgsCross"));
+        let host_toolchain_flake = DevEnvironment {
+            host_toolchain: true,
+            ..dev_env.clone()
+        }
+        .to_flake();
+        assert!(!host_toolchain_flake.contains("cargo"));
+        assert!(flake.contains(r#"inputs.nixpkgs.url = "github:NixOS/nixpkgs/nixos-unstable";"#));
+
+        let dev_env = DevEnvironment {
+            target: Some("aarch64-unknown-linux-gnu".parse()?),
+            ..dev_env
+        };
+        let flake = dev_env.to_flake();
+        assert!(flake.contains("cargo hello pkgsCross.aarch64-multiplatform.hello\n"));
+        // Tools run while building come from the host
+        assert!(flake.contains("nativeBuildInputs = [\n              pkg-config\n"));
+        assert!(!flake.contains("pkgsCross.aarch64-multiplatform.pkg-config"));
+        assert!(flake.contains(r#""CARGO_BUILD_TARGET" = "aarch64-unknown-linux-gnu";"#));
+        assert!(!flake.contains("rust-overlay"));
+
+        let dev_env = DevEnvironment {
+            rust_toolchain: Some(RustToolchain::parse("nightly")?),
+            ..dev_env
+        };
+        let flake = dev_env.to_flake();
+        assert!(flake.contains("outputs = { self, nixpkgs, rust-overlay }:"));
+        assert!(flake.contains("overlays = [ rust-overlay.overlays.default ];"));
+        assert!(flake.contains(r#"hello (rust-bin.fromRustupToolchain { channel = "nightly"; components = [ ]; targets = [ "aarch64-unknown-linux-gnu" ]; }) pkgsCross.aarch64-multiplatform.hello"#));
+        assert!(flake.contains(r#""CARGO_TARGET_AARCH64_UNKNOWN_LINUX_GNU_LINKER" = "${pkgsCross.aarch64-multiplatform.stdenv.cc}/bin/${pkgsCross.aarch64-multiplatform.stdenv.cc.targetPrefix}cc";"#));
+
+        let shell_nix = DevEnvironment {
+            nixpkgs_rev: Some("nixos-23.11".into()),
+            ..dev_env.clone()
+        }
+        .to_shell_nix()?;
+        assert!(shell_nix.contains(
+            r#"builtins.fetchTarball "https://github.com/NixOS/nixpkgs/archive/nixos-23.11.tar.gz";"#
+        ));
+        assert!(shell_nix.contains("overlays = [ rust-overlay ];"));
+        assert!(shell_nix.contains(r#"hello (rust-bin.fromRustupToolchain { channel = "nightly"; components = [ ]; targets = [ "aarch64-unknown-linux-gnu" ]; }) pkgsCross.aarch64-multiplatform.hello"#));
+        assert!(shell_nix.contains(r#""CARGO_BUILD_TARGET" = "aarch64-unknown-linux-gnu";"#));
+        assert!(DevEnvironment {
+            nixpkgs_flake: Some("path:/srv/nixpkgs".into()),
+            ..dev_env
+        }
+        .to_shell_nix()
+        .is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dev_env_to_flake_deduplicates_build_inputs() -> eyre::Result<()> {
+        let cache_dir = TempDir::new()?;
+        std::env::set_var("XDG_CACHE_HOME", cache_dir.path());
+        let registry =
+            DependencyRegistry::new(&DependencyRegistryOptions::new().offline(true)).await?;
+        let mut dev_env = DevEnvironment::new(&registry);
+        {
+            let language = registry.language().await;
+            // Both map to `protobuf`
+            for name in ["prost-build", "protoc-rust"] {
+                language.rust.dependencies[name].apply(&mut dev_env);
+            }
+        }
+
+        let flake = dev_env.to_flake();
+        assert_eq!(flake.matches(" protobuf").count(), 1, "{flake}");
+        assert_eq!(flake, dev_env.to_flake());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dev_env_to_flake_is_stable() -> eyre::Result<()> {
+        let cache_dir = TempDir::new()?;
+        std::env::set_var("XDG_CACHE_HOME", cache_dir.path());
+        let registry =
+            DependencyRegistry::new(&DependencyRegistryOptions::new().offline(true)).await?;
+        let names = (0..64).map(|i| format!("VAR_{i:02}")).collect::<Vec<_>>();
+        let flake_with = |names: &mut dyn Iterator<Item = &String>| {
+            let mut dev_env = DevEnvironment::new(&registry);
+            for name in names {
+                dev_env
+                    .environment_variables
+                    .insert(name.clone(), name.to_lowercase());
+                dev_env.build_inputs.insert(name.to_lowercase());
+                dev_env.runtime_inputs.insert(name.to_lowercase());
+            }
+            dev_env.to_flake()
+        };
+
+        // The same environment, built up in a different order
+        let flake = flake_with(&mut names.iter());
+        assert_eq!(flake, flake_with(&mut names.iter().rev()));
+        let positions = names
+            .iter()
+            .map(|name| flake.find(&format!("\"{name}\" = ")))
+            .collect::<Option<Vec<_>>>()
+            .expect("every variable should be in the flake");
+        assert!(
+            positions.windows(2).all(|pair| pair[0] < pair[1]),
+            "{flake}"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dev_env_to_flake_combines_shell_hooks() -> eyre::Result<()> {
+        let cache_dir = TempDir::new()?;
+        std::env::set_var("XDG_CACHE_HOME", cache_dir.path());
+        let temp_dir = TempDir::new()?;
+        let registry_file = temp_dir.path().join("registry.json");
+        write(
+            &registry_file,
+            r#"{ "version": 1, "language": { "rust": { "dependencies": {
+                "foo-sys": { "environment-variables": { "shellHook": "echo foo" } },
+                "bar-sys": { "environment-variables": { "shellHook": "echo bar" } }
+            } } } }"#,
+        )
+        .await?;
+        let registry = DependencyRegistry::new(
+            &DependencyRegistryOptions::new()
+                .offline(true)
+                .registry(registry_file.into()),
+        )
+        .await?;
+        let mut dev_env = DevEnvironment::new(&registry);
+        let version = semver::Version::new(1, 0, 0);
+        let metadata: RustDependencyData = serde_json::from_str(
+            r#"{ "environment-variables": { "shellHook": "echo project" } }"#,
+        )?;
+        dev_env.apply_dependency(
+            &metadata,
+            "riff-test",
+            &version,
+            CrateSettingsSource::PackageMetadata,
+            &Default::default(),
+        );
+        {
+            let language = registry.language().await;
+            for name in ["foo-sys", "bar-sys", "foo-sys"] {
+                dev_env.apply_crate(&language.rust, name, &version, &Default::default());
+            }
+        }
+
+        let flake = dev_env.to_flake();
         assert!(
-            flake.contains(r#""LD_LIBRARY_PATH" = "#)
-                && flake.contains("${lib.getLib nix}/lib")
-                && flake.contains("${lib.getLib libGL}/lib")
+            flake.contains("\"shellHook\" = \"echo foo\necho bar\necho project\";"),
+            "{flake}"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dev_env_to_flake_pkg_config_path() -> eyre::Result<()> {
+        let cache_dir = TempDir::new()?;
+        std::env::set_var("XDG_CACHE_HOME", cache_dir.path());
+        let temp_dir = TempDir::new()?;
+        let registry_file = temp_dir.path().join("registry.json");
+        write(
+            &registry_file,
+            r#"{ "version": 1, "language": { "rust": { "dependencies": {
+                "openssl-sys": { "build-inputs": [ "openssl" ], "pkg-config-path": true },
+                "libz-sys": { "build-inputs": [ "zlib" ] }
+            } } } }"#,
+        )
+        .await?;
+        let registry = DependencyRegistry::new(
+            &DependencyRegistryOptions::new()
+                .offline(true)
+                .registry(registry_file.into()),
+        )
+        .await?;
+        let mut dev_env = DevEnvironment::new(&registry);
+        let version = semver::Version::new(1, 0, 0);
+        {
+            let language = registry.language().await;
+            dev_env.apply_crate(&language.rust, "libz-sys", &version, &Default::default());
+            assert!(!dev_env.to_flake().contains("PKG_CONFIG_PATH"));
+            dev_env.apply_crate(&language.rust, "openssl-sys", &version, &Default::default());
+        }
+        let metadata: RustDependencyData = serde_json::from_str(
+            r#"{ "environment-variables": { "shellHook": "echo project" } }"#,
+        )?;
+        dev_env.apply_dependency(
+            &metadata,
+            "riff-test",
+            &version,
+            CrateSettingsSource::PackageMetadata,
+            &Default::default(),
+        );
+
+        let flake = dev_env.to_flake();
+        let inputs = dev_env
+            .build_inputs
+            .difference(&dev_env.toolchain_inputs)
+            .sorted()
+            .join(" ");
+        assert!(inputs.contains("openssl zlib"), "{inputs}");
+        let expected = format!(
+            r#""shellHook" = "export PKG_CONFIG_PATH=\"${{lib.makeSearchPathOutput "dev" "lib/pkgconfig" [ {inputs} ]}}:${{lib.makeSearchPathOutput "dev" "share/pkgconfig" [ {inputs} ]}}\${{PKG_CONFIG_PATH:+:$PKG_CONFIG_PATH}}\"
+echo project";"#
         );
+        assert!(flake.contains(&expected), "{flake}");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dev_env_package_metadata_overrides_registry_variables() -> eyre::Result<()> {
+        let cache_dir = TempDir::new()?;
+        std::env::set_var("XDG_CACHE_HOME", cache_dir.path());
+        let temp_dir = TempDir::new()?;
+        let registry_file = temp_dir.path().join("registry.json");
+        write(
+            &registry_file,
+            r#"{ "version": 1, "language": { "rust": { "dependencies": {
+                "protobuf-src": { "environment-variables": { "PROTOC": "registry", "PROTOC_INCLUDE": "registry" } }
+            } } } }"#,
+        )
+        .await?;
+        let registry = DependencyRegistry::new(
+            &DependencyRegistryOptions::new()
+                .offline(true)
+                .registry(registry_file.into()),
+        )
+        .await?;
+        let mut dev_env = DevEnvironment::new(&registry);
+        let version = semver::Version::new(1, 0, 0);
+        let metadata: RustDependencyData =
+            serde_json::from_str(r#"{ "environment-variables": { "PROTOC": "project" } }"#)?;
+        // The project's settings are applied before those of a crate found after it
+        dev_env.apply_dependency(
+            &metadata,
+            "riff-test",
+            &version,
+            CrateSettingsSource::PackageMetadata,
+            &Default::default(),
+        );
+        {
+            let language = registry.language().await;
+            dev_env.apply_crate(
+                &language.rust,
+                "protobuf-src",
+                &version,
+                &Default::default(),
+            );
+        }
+
+        assert_eq!(dev_env.environment_variables["PROTOC"], "project");
+        assert_eq!(dev_env.environment_variables["PROTOC_INCLUDE"], "registry");
         Ok(())
     }
 
@@ -278,23 +1560,41 @@ runtime-inputs = [ "libGL" ]
 
 [package.metadata.riff.environment-variables]
 HI = "BYE"
+BIN = "${HOME}/bin:$PATH"
 
 [dependencies]
         "#,
         )
         .await?;
 
-        let registry = DependencyRegistry::new(true).await?;
+        let registry =
+            DependencyRegistry::new(&DependencyRegistryOptions::new().offline(true)).await?;
         let mut dev_env = DevEnvironment::new(&registry);
-        let detect = dev_env.detect(temp_dir.path()).await;
+        let detect = dev_env
+            .detect(temp_dir.path(), &Default::default(), None)
+            .await;
         assert!(detect.is_ok(), "{detect:?}");
 
-        assert!(dev_env.build_inputs.get("hello").is_some());
+        assert!(dev_env.build_inputs.contains("hello"));
         assert_eq!(
             dev_env.environment_variables.get("HI"),
             Some(&String::from("BYE"))
         );
-        assert!(dev_env.runtime_inputs.get("libGL").is_some());
+        assert_eq!(
+            dev_env.environment_variables.get("BIN"),
+            Some(&format!("{}/bin:$PATH", std::env::var("HOME")?))
+        );
+        assert!(dev_env.runtime_inputs.contains("libGL"));
+        let contribution = dev_env
+            .contributions
+            .iter()
+            .find(|contribution| contribution.name == "riff-test")
+            .expect("riff-test should contribute");
+        assert_eq!(contribution.source, CrateSettingsSource::PackageMetadata);
+        assert_eq!(
+            contribution.build_inputs,
+            BTreeSet::from(["hello".to_string()])
+        );
         Ok(())
     }
 
@@ -303,10 +1603,815 @@ HI = "BYE"
         let cache_dir = TempDir::new()?;
         std::env::set_var("XDG_CACHE_HOME", cache_dir.path());
         let temp_dir = TempDir::new()?;
-        let registry = DependencyRegistry::new(true).await?;
+        let registry =
+            DependencyRegistry::new(&DependencyRegistryOptions::new().offline(true)).await?;
+        let mut dev_env = DevEnvironment::new(&registry);
+        let detect = dev_env
+            .detect(temp_dir.path(), &Default::default(), None)
+            .await;
+        assert!(detect.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dev_env_detect_target_specific() -> eyre::Result<()> {
+        let cache_dir = TempDir::new()?;
+        std::env::set_var("XDG_CACHE_HOME", cache_dir.path());
+        let temp_dir = TempDir::new()?;
+        for (name, build_input) in [("unix-sys", "unix-lib"), ("windows-sys", "windows-lib")] {
+            let sys_dir = temp_dir.path().join(name);
+            tokio::fs::create_dir_all(&sys_dir).await?;
+            write(sys_dir.join("lib.rs"), "").await?;
+            write(
+                sys_dir.join("Cargo.toml"),
+                format!(
+                    r#"
+[package]
+name = "{name}"
+version = "0.1.0"
+edition = "2021"
+
+[lib]
+path = "lib.rs"
+
+[package.metadata.riff]
+build-inputs = [ "{build_input}" ]
+                    "#
+                ),
+            )
+            .await?;
+        }
+        let project_dir = temp_dir.path().join("project");
+        tokio::fs::create_dir_all(&project_dir).await?;
+        write(project_dir.join("lib.rs"), "").await?;
+        write(
+            project_dir.join("Cargo.toml"),
+            r#"
+[package]
+name = "riff-test"
+version = "0.1.0"
+edition = "2021"
+
+[lib]
+path = "lib.rs"
+
+[target.'cfg(unix)'.dependencies]
+unix-sys = { path = "../unix-sys" }
+
+[target.'cfg(windows)'.dependencies]
+windows-sys = { path = "../windows-sys" }
+        "#,
+        )
+        .await?;
+
+        let registry =
+            DependencyRegistry::new(&DependencyRegistryOptions::new().offline(true)).await?;
+
+        let mut dev_env = DevEnvironment::new(&registry);
+        dev_env
+            .detect(&project_dir, &Default::default(), None)
+            .await?;
+        assert_eq!(
+            dev_env.build_inputs.contains("unix-lib"),
+            cfg!(unix),
+            "{:?}",
+            dev_env.build_inputs
+        );
+        assert_eq!(dev_env.build_inputs.contains("windows-lib"), cfg!(windows));
+
+        let mut dev_env = DevEnvironment::new(&registry);
+        dev_env.target = Some("x86_64-pc-windows-gnu".parse()?);
+        dev_env
+            .detect(&project_dir, &Default::default(), None)
+            .await?;
+        assert!(dev_env.build_inputs.contains("windows-lib"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dev_env_detect_no_default_features() -> eyre::Result<()> {
+        let cache_dir = TempDir::new()?;
+        std::env::set_var("XDG_CACHE_HOME", cache_dir.path());
+        let temp_dir = TempDir::new()?;
+        let sys_dir = temp_dir.path().join("heavy-sys");
+        let project_dir = temp_dir.path().join("project");
+        tokio::fs::create_dir_all(&sys_dir).await?;
+        tokio::fs::create_dir_all(&project_dir).await?;
+        write(sys_dir.join("lib.rs"), "").await?;
+        write(
+            sys_dir.join("Cargo.toml"),
+            r#"
+[package]
+name = "heavy-sys"
+version = "0.1.0"
+edition = "2021"
+
+[lib]
+path = "lib.rs"
+
+[package.metadata.riff]
+build-inputs = [ "heavy" ]
+        "#,
+        )
+        .await?;
+        write(project_dir.join("lib.rs"), "").await?;
+        write(
+            project_dir.join("Cargo.toml"),
+            r#"
+[package]
+name = "riff-test"
+version = "0.1.0"
+edition = "2021"
+
+[lib]
+path = "lib.rs"
+
+[features]
+default = [ "heavy" ]
+heavy = [ "dep:heavy-sys" ]
+
+[dependencies]
+heavy-sys = { path = "../heavy-sys", optional = true }
+        "#,
+        )
+        .await?;
+
+        let registry =
+            DependencyRegistry::new(&DependencyRegistryOptions::new().offline(true)).await?;
+
+        let mut dev_env = DevEnvironment::new(&registry);
+        dev_env
+            .detect(&project_dir, &Default::default(), None)
+            .await?;
+        assert!(dev_env.build_inputs.contains("heavy"));
+
+        let mut dev_env = DevEnvironment::new(&registry);
+        let features = CargoFeatures {
+            no_default_features: true,
+            ..Default::default()
+        };
+        dev_env.detect(&project_dir, &features, None).await?;
+        assert!(!dev_env.build_inputs.contains("heavy"));
+
+        let mut dev_env = DevEnvironment::new(&registry);
+        let features = CargoFeatures {
+            features: vec!["heavy".into()],
+            no_default_features: true,
+            ..Default::default()
+        };
+        dev_env.detect(&project_dir, &features, None).await?;
+        assert!(dev_env.build_inputs.contains("heavy"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dev_env_detect_unmapped_crates() -> eyre::Result<()> {
+        let cache_dir = TempDir::new()?;
+        std::env::set_var("XDG_CACHE_HOME", cache_dir.path());
+        let temp_dir = TempDir::new()?;
+        let project_dir = temp_dir.path().join("project");
+        tokio::fs::create_dir_all(&project_dir).await?;
+        for (name, metadata) in [
+            ("mystery-sys", ""),
+            ("mystery-src", ""),
+            (
+                "known-sys",
+                "[package.metadata.riff]\nbuild-inputs = [ \"known\" ]",
+            ),
+        ] {
+            let dir = temp_dir.path().join(name);
+            tokio::fs::create_dir_all(&dir).await?;
+            write(dir.join("lib.rs"), "").await?;
+            write(
+                dir.join("Cargo.toml"),
+                format!(
+                    r#"
+[package]
+name = "{name}"
+version = "0.1.0"
+edition = "2021"
+
+[lib]
+path = "lib.rs"
+
+{metadata}
+"#
+                ),
+            )
+            .await?;
+        }
+        write(project_dir.join("lib.rs"), "").await?;
+        write(
+            project_dir.join("Cargo.toml"),
+            r#"
+[package]
+name = "riff-test"
+version = "0.1.0"
+edition = "2021"
+
+[lib]
+path = "lib.rs"
+
+[dependencies]
+known-sys = { path = "../known-sys" }
+mystery-src = { path = "../mystery-src" }
+mystery-sys = { path = "../mystery-sys" }
+        "#,
+        )
+        .await?;
+
+        let registry =
+            DependencyRegistry::new(&DependencyRegistryOptions::new().offline(true)).await?;
+        let mut dev_env = DevEnvironment::new(&registry);
+        dev_env
+            .detect(&project_dir, &Default::default(), None)
+            .await?;
+        assert_eq!(
+            dev_env.unmapped_crates.iter().collect::<Vec<_>>(),
+            ["mystery-src", "mystery-sys"]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dev_env_detect_member_shells() -> eyre::Result<()> {
+        let cache_dir = TempDir::new()?;
+        std::env::set_var("XDG_CACHE_HOME", cache_dir.path());
+        let temp_dir = TempDir::new()?;
+        let project_dir = temp_dir.path().join("project");
+        for (dir, name, dependencies) in [
+            (
+                temp_dir.path().join("known-sys"),
+                "known-sys",
+                "[package.metadata.riff]\nbuild-inputs = [ \"known\" ]",
+            ),
+            (
+                temp_dir.path().join("other-sys"),
+                "other-sys",
+                "[package.metadata.riff]\nbuild-inputs = [ \"other\" ]",
+            ),
+            (
+                project_dir.join("app"),
+                "app",
+                "[dependencies]\nknown-sys = { path = \"../../known-sys\" }",
+            ),
+            (
+                project_dir.join("tool"),
+                "tool",
+                "[dependencies]\nother-sys = { path = \"../../other-sys\" }",
+            ),
+        ] {
+            tokio::fs::create_dir_all(&dir).await?;
+            write(dir.join("lib.rs"), "").await?;
+            write(
+                dir.join("Cargo.toml"),
+                format!(
+                    r#"
+[package]
+name = "{name}"
+version = "0.1.0"
+edition = "2021"
+
+[lib]
+path = "lib.rs"
+
+{dependencies}
+"#
+                ),
+            )
+            .await?;
+        }
+        write(
+            project_dir.join("Cargo.toml"),
+            "[workspace]\nmembers = [ \"app\", \"tool\" ]\n",
+        )
+        .await?;
+
+        let registry =
+            DependencyRegistry::new(&DependencyRegistryOptions::new().offline(true)).await?;
+        let mut dev_env = DevEnvironment::new(&registry);
+        dev_env.dev_shell_per_member = true;
+        dev_env
+            .detect(&project_dir, &Default::default(), Some("app"))
+            .await?;
+        // The default shell is still the whole workspace
+        assert!(dev_env.build_inputs.contains("known"));
+        assert!(dev_env.build_inputs.contains("other"));
+        assert_eq!(
+            dev_env.member_shells.keys().collect::<Vec<_>>(),
+            ["app", "tool"]
+        );
+        assert!(dev_env.member_shells["app"].build_inputs.contains("known"));
+        assert!(!dev_env.member_shells["app"].build_inputs.contains("other"));
+        assert!(dev_env.member_shells["tool"].build_inputs.contains("other"));
+
+        dev_env.add_build_input("extra".into());
+        let flake = dev_env.to_flake();
+        assert!(flake.contains("default = with pkgs;"));
+        assert!(flake.contains("\"app\" = with pkgs;"));
+        assert!(flake.contains("name = \"riff-shell-tool\";"));
+        assert!(dev_env.member_shells["tool"].build_inputs.contains("extra"));
+
+        let mut dev_env = DevEnvironment::new(&registry);
+        dev_env.dev_shell_per_member = true;
+        assert!(dev_env
+            .detect(&project_dir, &Default::default(), Some("missing"))
+            .await
+            .is_err());
+
+        // Without a shell per member, choosing one restricts the environment to it
+        let mut dev_env = DevEnvironment::new(&registry);
+        dev_env
+            .detect(&project_dir, &Default::default(), Some("app"))
+            .await?;
+        assert!(dev_env.build_inputs.contains("known"));
+        assert!(!dev_env.build_inputs.contains("other"));
+        assert!(dev_env.member_shells.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dev_env_detect_excluded_crates() -> eyre::Result<()> {
+        let cache_dir = TempDir::new()?;
+        std::env::set_var("XDG_CACHE_HOME", cache_dir.path());
+        let temp_dir = TempDir::new()?;
+        let project_dir = temp_dir.path().join("project");
+        tokio::fs::create_dir_all(&project_dir).await?;
+        for (name, metadata) in [
+            ("mystery-sys", ""),
+            (
+                "known-sys",
+                "[package.metadata.riff]\nbuild-inputs = [ \"known\" ]",
+            ),
+            (
+                "other-sys",
+                "[package.metadata.riff]\nbuild-inputs = [ \"other\" ]",
+            ),
+        ] {
+            let dir = temp_dir.path().join(name);
+            tokio::fs::create_dir_all(&dir).await?;
+            write(dir.join("lib.rs"), "").await?;
+            write(
+                dir.join("Cargo.toml"),
+                format!(
+                    r#"
+[package]
+name = "{name}"
+version = "0.1.0"
+edition = "2021"
+
+[lib]
+path = "lib.rs"
+
+{metadata}
+"#
+                ),
+            )
+            .await?;
+        }
+        write(project_dir.join("lib.rs"), "").await?;
+        write(
+            project_dir.join("Cargo.toml"),
+            r#"
+[package]
+name = "riff-test"
+version = "0.1.0"
+edition = "2021"
+
+[lib]
+path = "lib.rs"
+
+[dependencies]
+known-sys = { path = "../known-sys" }
+mystery-sys = { path = "../mystery-sys" }
+other-sys = { path = "../other-sys" }
+
+[package.metadata.riff]
+exclude = [ "Known-Sys" ]
+        "#,
+        )
+        .await?;
+
+        let registry =
+            DependencyRegistry::new(&DependencyRegistryOptions::new().offline(true)).await?;
+        let mut dev_env = DevEnvironment::new(&registry);
+        dev_env.exclude_crate("MYSTERY-SYS");
+        dev_env
+            .detect(&project_dir, &Default::default(), None)
+            .await?;
+        assert!(!dev_env.build_inputs.contains("known"));
+        assert!(dev_env.build_inputs.contains("other"));
+        assert!(dev_env.unmapped_crates.is_empty());
+        assert_eq!(
+            dev_env.excluded_crates.iter().collect::<Vec<_>>(),
+            ["known-sys", "mystery-sys"]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dev_env_detect_crate_overrides() -> eyre::Result<()> {
+        let cache_dir = TempDir::new()?;
+        std::env::set_var("XDG_CACHE_HOME", cache_dir.path());
+        let temp_dir = TempDir::new()?;
+        let registry_file = temp_dir.path().join("registry.json");
+        write(
+            &registry_file,
+            r#"{ "version": 1, "language": { "rust": { "dependencies": {
+                "replaced-sys": { "build-inputs": [ "stale" ], "environment-variables": { "FOO": "registry" } },
+                "augmented-sys": { "build-inputs": [ "kept" ] }
+            } } } }"#,
+        )
+        .await?;
+        let project_dir = temp_dir.path().join("project");
+        tokio::fs::create_dir_all(&project_dir).await?;
+        for name in ["replaced-sys", "augmented-sys"] {
+            let dir = temp_dir.path().join(name);
+            tokio::fs::create_dir_all(&dir).await?;
+            write(dir.join("lib.rs"), "").await?;
+            write(
+                dir.join("Cargo.toml"),
+                format!(
+                    r#"
+[package]
+name = "{name}"
+version = "0.1.0"
+edition = "2021"
+
+[lib]
+path = "lib.rs"
+"#
+                ),
+            )
+            .await?;
+        }
+        write(project_dir.join("lib.rs"), "").await?;
+        write(
+            project_dir.join("Cargo.toml"),
+            r#"
+[package]
+name = "riff-test"
+version = "0.1.0"
+edition = "2021"
+
+[lib]
+path = "lib.rs"
+
+[dependencies]
+augmented-sys = { path = "../augmented-sys" }
+replaced-sys = { path = "../replaced-sys" }
+
+[package.metadata.riff]
+build-inputs = [ "project" ]
+environment-variables = { FOO = "project" }
+
+[package.metadata.riff.overrides.replaced-sys]
+build-inputs = [ "fixed" ]
+environment-variables = { FOO = "override" }
+
+[package.metadata.riff.overrides.Augmented-Sys]
+augment = true
+build-inputs = [ "extra" ]
+        "#,
+        )
+        .await?;
+
+        let registry = DependencyRegistry::new(
+            &DependencyRegistryOptions::new()
+                .offline(true)
+                .registry(registry_file.into()),
+        )
+        .await?;
+        let mut dev_env = DevEnvironment::new(&registry);
+        dev_env
+            .detect(&project_dir, &Default::default(), None)
+            .await?;
+        for input in ["fixed", "project", "kept", "extra"] {
+            assert!(dev_env.build_inputs.contains(input), "{input}");
+        }
+        assert!(!dev_env.build_inputs.contains("stale"));
+        assert_eq!(
+            dev_env.environment_variables.get("FOO").map(String::as_str),
+            Some("override")
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dev_env_detect_renamed_dependency() -> eyre::Result<()> {
+        let cache_dir = TempDir::new()?;
+        std::env::set_var("XDG_CACHE_HOME", cache_dir.path());
+        let temp_dir = TempDir::new()?;
+        let registry_file = temp_dir.path().join("registry.json");
+        write(
+            &registry_file,
+            r#"{ "version": 1, "language": { "rust": { "dependencies": {
+                "riff-openssl-sys": { "build-inputs": [ "openssl" ] }
+            } } } }"#,
+        )
+        .await?;
+        let dependency_dir = temp_dir.path().join("riff-openssl-sys");
+        tokio::fs::create_dir_all(&dependency_dir).await?;
+        write(dependency_dir.join("lib.rs"), "").await?;
+        write(
+            dependency_dir.join("Cargo.toml"),
+            r#"
+[package]
+name = "riff-openssl-sys"
+version = "0.9.0"
+edition = "2021"
+
+[lib]
+path = "lib.rs"
+        "#,
+        )
+        .await?;
+        let project_dir = temp_dir.path().join("project");
+        tokio::fs::create_dir_all(&project_dir).await?;
+        write(project_dir.join("lib.rs"), "").await?;
+        write(
+            project_dir.join("Cargo.toml"),
+            r#"
+[package]
+name = "riff-test"
+version = "0.1.0"
+edition = "2021"
+
+[lib]
+path = "lib.rs"
+
+[features]
+default = ["tls"]
+tls = ["dep:openssl"]
+
+[dependencies]
+openssl = { package = "riff-openssl-sys", path = "../riff-openssl-sys", optional = true }
+        "#,
+        )
+        .await?;
+
+        let registry = DependencyRegistry::new(
+            &DependencyRegistryOptions::new()
+                .offline(true)
+                .registry(registry_file.into()),
+        )
+        .await?;
+        // `cargo metadata` writes the `Cargo.lock` read the second time
+        for no_cargo_metadata in [false, true] {
+            let mut dev_env = DevEnvironment::new(&registry);
+            dev_env.no_cargo_metadata = no_cargo_metadata;
+            dev_env
+                .detect(&project_dir, &Default::default(), None)
+                .await?;
+            assert!(
+                dev_env.build_inputs.contains("openssl"),
+                "no_cargo_metadata: {no_cargo_metadata}"
+            );
+            assert!(dev_env.unmapped_crates.is_empty());
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dev_env_detect_feature_gated() -> eyre::Result<()> {
+        let cache_dir = TempDir::new()?;
+        std::env::set_var("XDG_CACHE_HOME", cache_dir.path());
+        let temp_dir = TempDir::new()?;
+        write(temp_dir.path().join("lib.rs"), "").await?;
+        write(
+            temp_dir.path().join("Cargo.toml"),
+            r#"
+[package]
+name = "riff-test"
+version = "0.1.0"
+edition = "2021"
+
+[lib]
+path = "lib.rs"
+
+[features]
+default = [ "native-tls" ]
+native-tls = []
+rustls = []
+
+[package.metadata.riff]
+build-inputs = [ "hello" ]
+
+[package.metadata.riff.features.native-tls]
+build-inputs = [ "openssl" ]
+        "#,
+        )
+        .await?;
+
+        let registry =
+            DependencyRegistry::new(&DependencyRegistryOptions::new().offline(true)).await?;
+
+        let mut dev_env = DevEnvironment::new(&registry);
+        dev_env
+            .detect(temp_dir.path(), &Default::default(), None)
+            .await?;
+        assert!(dev_env.build_inputs.contains("hello"));
+        assert!(dev_env.build_inputs.contains("openssl"));
+
+        let mut dev_env = DevEnvironment::new(&registry);
+        let features = CargoFeatures {
+            features: vec!["rustls".into()],
+            no_default_features: true,
+            ..Default::default()
+        };
+        dev_env.detect(temp_dir.path(), &features, None).await?;
+        assert!(dev_env.build_inputs.contains("hello"));
+        assert!(!dev_env.build_inputs.contains("openssl"));
+
+        let mut dev_env = DevEnvironment::new(&registry);
+        let features = CargoFeatures {
+            all_features: true,
+            ..Default::default()
+        };
+        dev_env.detect(temp_dir.path(), &features, None).await?;
+        assert!(dev_env.build_inputs.contains("openssl"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dev_env_detect_workspace() -> eyre::Result<()> {
+        let cache_dir = TempDir::new()?;
+        std::env::set_var("XDG_CACHE_HOME", cache_dir.path());
+        let temp_dir = TempDir::new()?;
+        write(
+            temp_dir.path().join("Cargo.toml"),
+            r#"
+[workspace]
+members = [ "a", "b" ]
+        "#,
+        )
+        .await?;
+        for (name, build_input) in [("a", "aye"), ("b", "bee")] {
+            let member_dir = temp_dir.path().join(name);
+            tokio::fs::create_dir_all(&member_dir).await?;
+            write(member_dir.join("lib.rs"), "").await?;
+            write(
+                member_dir.join("Cargo.toml"),
+                format!(
+                    r#"
+[package]
+name = "{name}"
+version = "0.1.0"
+edition = "2021"
+
+[lib]
+path = "lib.rs"
+
+[package.metadata.riff]
+build-inputs = [ "{build_input}" ]
+        "#
+                ),
+            )
+            .await?;
+        }
+
+        let registry =
+            DependencyRegistry::new(&DependencyRegistryOptions::new().offline(true)).await?;
+
+        let mut dev_env = DevEnvironment::new(&registry);
+        dev_env
+            .detect(temp_dir.path(), &Default::default(), None)
+            .await?;
+        assert!(dev_env.build_inputs.contains("aye"));
+        assert!(dev_env.build_inputs.contains("bee"));
+
+        let mut dev_env = DevEnvironment::new(&registry);
+        dev_env
+            .detect(temp_dir.path(), &Default::default(), Some("b"))
+            .await?;
+        assert!(!dev_env.build_inputs.contains("aye"));
+        assert!(dev_env.build_inputs.contains("bee"));
+
         let mut dev_env = DevEnvironment::new(&registry);
-        let detect = dev_env.detect(temp_dir.path()).await;
+        let detect = dev_env
+            .detect(temp_dir.path(), &Default::default(), Some("c"))
+            .await;
         assert!(detect.is_err());
         Ok(())
     }
+
+    #[tokio::test]
+    async fn dev_env_detect_invalid_metadata() -> eyre::Result<()> {
+        let cache_dir = TempDir::new()?;
+        std::env::set_var("XDG_CACHE_HOME", cache_dir.path());
+        let temp_dir = TempDir::new()?;
+        write(temp_dir.path().join("lib.rs"), "").await?;
+        write(
+            temp_dir.path().join("Cargo.toml"),
+            r#"
+[package]
+name = "riff-test"
+version = "0.1.0"
+edition = "2021"
+
+[lib]
+path = "lib.rs"
+
+[package.metadata.riff]
+build-inputs = [ "cmake", 3 ]
+        "#,
+        )
+        .await?;
+
+        let registry =
+            DependencyRegistry::new(&DependencyRegistryOptions::new().offline(true)).await?;
+        let mut dev_env = DevEnvironment::new(&registry);
+        let err = dev_env
+            .detect(temp_dir.path(), &Default::default(), None)
+            .await
+            .unwrap_err();
+        assert!(format!("{err:?}").contains("`riff-test`"), "{err:?}");
+        match err.downcast_ref() {
+            Some(ProjectError::InvalidMetadata(_, manifest_path, de_err)) => {
+                assert_eq!(manifest_path, &temp_dir.path().join("Cargo.toml"));
+                let de_err = de_err.to_string();
+                assert!(de_err.contains("line 11"), "{de_err}");
+                assert!(
+                    de_err.contains(r#"build-inputs = [ "cmake", 3 ]"#),
+                    "{de_err}"
+                );
+            }
+            _ => panic!("Expected `ProjectError::InvalidMetadata`, got {err:?}"),
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dev_env_detect_nixpkgs_rev() -> eyre::Result<()> {
+        let cache_dir = TempDir::new()?;
+        std::env::set_var("XDG_CACHE_HOME", cache_dir.path());
+        let temp_dir = TempDir::new()?;
+        write(temp_dir.path().join("lib.rs"), "").await?;
+        write(
+            temp_dir.path().join("Cargo.toml"),
+            r#"
+[package]
+name = "riff-test"
+version = "0.1.0"
+edition = "2021"
+
+[lib]
+path = "lib.rs"
+
+[package.metadata.riff]
+nixpkgs = "nixos-23.11"
+rust-channel = "beta"
+        "#,
+        )
+        .await?;
+
+        let registry =
+            DependencyRegistry::new(&DependencyRegistryOptions::new().offline(true)).await?;
+        let mut dev_env = DevEnvironment::new(&registry);
+        dev_env
+            .detect(temp_dir.path(), &Default::default(), None)
+            .await?;
+        assert_eq!(dev_env.nixpkgs_rev.as_deref(), Some("nixos-23.11"));
+        assert_eq!(
+            dev_env.rust_channel,
+            Some(RustChannel::Channel("beta".into()))
+        );
+        assert!(dev_env
+            .to_flake()
+            .contains(r#"inputs.nixpkgs.url = "github:NixOS/nixpkgs/nixos-23.11";"#));
+
+        assert!(parse_nixpkgs_rev("2c7f3c0fb7c08a0814627611d9d7d45ab6d75335").is_ok());
+        assert!(parse_nixpkgs_rev("nixos-unstable\"; evil = \"").is_err());
+
+        dev_env.nixpkgs_flake = Some(parse_nixpkgs_flake(
+            "git+ssh://git@example.com/mirrors/nixpkgs?ref=nixos-23.11",
+        )?);
+        assert!(dev_env.to_flake().contains(
+            r#"inputs.nixpkgs.url = "git+ssh://git@example.com/mirrors/nixpkgs?ref=nixos-23.11";"#
+        ));
+        assert!(parse_nixpkgs_flake("path:/srv/nixpkgs").is_ok());
+        assert!(parse_nixpkgs_flake("nixpkgs").is_ok());
+        assert!(parse_nixpkgs_flake("").is_err());
+        assert!(parse_nixpkgs_flake("github:").is_err());
+        assert!(parse_nixpkgs_flake("gopher://example.com/nixpkgs").is_err());
+        assert!(parse_nixpkgs_flake("github:NixOS/nixpkgs\"; x = \"").is_err());
+
+        assert!(parse_build_input("llvmPackages.libclang").is_ok());
+        assert!(parse_build_input("").is_err());
+        assert!(parse_build_input("protobuf ]; x = [").is_err());
+
+        assert_eq!(
+            parse_environment_variable("RUSTFLAGS=-C target-cpu=native")?,
+            ("RUSTFLAGS".into(), "-C target-cpu=native".into())
+        );
+        assert_eq!(
+            parse_environment_variable("EMPTY=")?,
+            ("EMPTY".into(), "".into())
+        );
+        assert!(parse_environment_variable("RUSTFLAGS").is_err());
+        assert!(parse_environment_variable("=value").is_err());
+        assert!(parse_environment_variable("A\"=value").is_err());
+        assert_eq!(escape_nix_string(r#"a"b\c${d}"#), r#"a\"b\\c\${d}"#);
+        Ok(())
+    }
 }
@@ -1,46 +1,561 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use eyre::{eyre, WrapErr};
-use owo_colors::OwoColorize;
+use sha2::{Digest, Sha256};
 use tempfile::TempDir;
 use tokio::process::Command;
 
-use crate::dependency_registry::DependencyRegistry;
+use crate::cargo_metadata::CargoFeatures;
+use crate::color::Colorize;
+use crate::config::Config;
+use crate::cross_target::CrossTarget;
+use crate::dependency_registry::{
+    self, DependencyRegistry, DependencyRegistryOptions, RegistryLocation,
+};
 use crate::dev_env::DevEnvironment;
+use crate::rust_toolchain::{RustChannel, RustToolchain};
+use crate::spawn_error::{SpawnError, Tool};
 use crate::spinner::SimpleSpinner;
 use crate::telemetry::Telemetry;
 
-/// Generates a `flake.nix` by inspecting the specified `project_dir` for supported project types.
-#[tracing::instrument(skip(disable_telemetry))]
-pub async fn generate_flake_from_project_dir(
-    project_dir: Option<PathBuf>,
-    offline: bool,
-    disable_telemetry: bool,
-) -> color_eyre::Result<TempDir> {
-    let project_dir = match project_dir {
-        Some(dir) => dir,
+/// The directory under the cache directory generated flakes are kept in
+pub(crate) const FLAKES_CACHE_PATH: &str = "flakes";
+/// The directory under [`FLAKES_CACHE_PATH`] the locks shared by a project's flakes are kept in
+const FLAKE_LOCKS_PATH: &str = "locks";
+
+/// Options for [`generate_flake`]
+///
+/// Construct with [`GenerateOptions::new`] and the builder methods; unset options match the
+/// defaults of the `riff` command line.
+#[derive(Debug, Clone, Default)]
+pub struct GenerateOptions {
+    pub(crate) project_dir: Option<PathBuf>,
+    pub(crate) features: CargoFeatures,
+    pub(crate) offline: bool,
+    pub(crate) disable_telemetry: bool,
+    pub(crate) telemetry_url: Option<String>,
+    pub(crate) flake_dir: Option<PathBuf>,
+    pub(crate) package: Option<String>,
+    pub(crate) target: Option<CrossTarget>,
+    pub(crate) nixpkgs_rev: Option<String>,
+    pub(crate) nixpkgs_flake: Option<String>,
+    pub(crate) no_toolchain: bool,
+    pub(crate) rust_channel: Option<RustChannel>,
+    pub(crate) rust_components: Vec<String>,
+    pub(crate) registry: DependencyRegistryOptions,
+    pub(crate) no_dev: bool,
+    pub(crate) no_cargo_metadata: bool,
+    pub(crate) ignore_name_separators: bool,
+    pub(crate) build_inputs: Vec<String>,
+    pub(crate) excluded_crates: Vec<String>,
+    pub(crate) environment_variables: Vec<(String, String)>,
+    pub(crate) legacy_nix: bool,
+    pub(crate) quiet: bool,
+}
+
+impl GenerateOptions {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// The root directory of the project, defaults to the current working directory
+    pub fn project_dir(mut self, project_dir: impl Into<PathBuf>) -> Self {
+        self.project_dir = Some(project_dir.into());
+        self
+    }
+
+    /// The Cargo features to resolve dependencies with, defaults to the `default` feature
+    pub fn features(mut self, features: CargoFeatures) -> Self {
+        self.features = features;
+        self
+    }
+
+    /// Disable all network usage except what Nix needs to lock the flake
+    pub fn offline(mut self, offline: bool) -> Self {
+        self.offline = offline;
+        self
+    }
+
+    /// Turn off the user telemetry ping
+    pub fn disable_telemetry(mut self, disable_telemetry: bool) -> Self {
+        self.disable_telemetry = disable_telemetry;
+        self
+    }
+
+    /// Where to send the user telemetry ping, overriding `telemetry-url`
+    ///
+    /// `RIFF_TELEMETRY_URL` still takes precedence.
+    pub fn telemetry_url(mut self, telemetry_url: impl Into<String>) -> Self {
+        self.telemetry_url = Some(telemetry_url.into());
+        self
+    }
+
+    /// Choose this workspace member, defaults to every member
+    ///
+    /// The flake then also has a `devShell` for each member, which is selected with
+    /// [`FlakeDir::installable`]. A `shell.nix` only has the dependencies of this member.
+    pub fn package(mut self, package: impl Into<String>) -> Self {
+        self.package = Some(package.into());
+        self
+    }
+
+    /// The target to cross compile to, defaults to the host
+    pub fn target(mut self, target: CrossTarget) -> Self {
+        self.target = Some(target);
+        self
+    }
+
+    /// The Nixpkgs git revision or branch to use, overriding `package.metadata.riff.nixpkgs`
+    ///
+    /// Defaults to `nixos-unstable`.
+    pub fn nixpkgs_rev(mut self, nixpkgs_rev: impl Into<String>) -> Self {
+        self.nixpkgs_rev = Some(nixpkgs_rev.into());
+        self
+    }
+
+    /// The Nixpkgs to use, either a git revision or branch like [`GenerateOptions::nixpkgs_rev`]
+    /// (Eg `nixos-23.11`), or otherwise a flake reference like [`GenerateOptions::nixpkgs_flake`]
+    /// (Eg `git+ssh://git@example.com/nixpkgs`)
+    pub fn nixpkgs(self, nixpkgs: impl Into<String>) -> Self {
+        let nixpkgs = nixpkgs.into();
+        match crate::dev_env::parse_nixpkgs_rev(&nixpkgs) {
+            Ok(_) => Self {
+                nixpkgs_flake: None,
+                ..self
+            }
+            .nixpkgs_rev(nixpkgs),
+            Err(_) => Self {
+                nixpkgs_rev: None,
+                ..self
+            }
+            .nixpkgs_flake(nixpkgs),
+        }
+    }
+
+    /// A flake reference to use for Nixpkgs instead of GitHub, Eg an internal mirror
+    ///
+    /// Takes precedence over [`GenerateOptions::nixpkgs_rev`] and `package.metadata.riff.nixpkgs`.
+    pub fn nixpkgs_flake(mut self, nixpkgs_flake: impl Into<String>) -> Self {
+        self.nixpkgs_flake = Some(nixpkgs_flake.into());
+        self
+    }
+
+    /// Ignore any `rust-toolchain.toml`, using the Rust toolchain from Nixpkgs
+    pub fn no_toolchain(mut self, no_toolchain: bool) -> Self {
+        self.no_toolchain = no_toolchain;
+        self
+    }
+
+    /// The Rust channel to provide, overriding `package.metadata.riff.rust-channel` and any
+    /// `rust-toolchain.toml`
+    pub fn rust_channel(mut self, rust_channel: RustChannel) -> Self {
+        self.rust_channel = Some(rust_channel);
+        self
+    }
+
+    /// Add a component (Eg `rust-analyzer`) to the Rust toolchain, which may be called repeatedly
+    ///
+    /// The toolchain must come from `rust-overlay`, chosen with [`GenerateOptions::rust_channel`],
+    /// `package.metadata.riff.rust-channel` or a `rust-toolchain.toml`.
+    pub fn rust_component(mut self, component: impl Into<String>) -> Self {
+        self.rust_components.push(component.into());
+        self
+    }
+
+    /// Leave out the external dependencies only needed by the workspace members'
+    /// `[dev-dependencies]`
+    ///
+    /// Those of `[dependencies]` and `[build-dependencies]` are always provided. Without
+    /// `cargo metadata`, dev-dependencies can't be told apart, so they're always provided.
+    pub fn no_dev(mut self, no_dev: bool) -> Self {
+        self.no_dev = no_dev;
+        self
+    }
+
+    /// Read the project's dependencies from `Cargo.lock` instead of running `cargo metadata`
+    ///
+    /// `cargo metadata` is more accurate, but needs Cargo. Riff falls back to `Cargo.lock` if
+    /// Cargo isn't installed.
+    pub fn no_cargo_metadata(mut self, no_cargo_metadata: bool) -> Self {
+        self.no_cargo_metadata = no_cargo_metadata;
+        self
+    }
+
+    /// Treat `-` and `_` in crate names as the same when looking them up in the registry
+    ///
+    /// Names are always matched case-insensitively when there's no exact match.
+    pub fn ignore_name_separators(mut self, ignore_name_separators: bool) -> Self {
+        self.ignore_name_separators = ignore_name_separators;
+        self
+    }
+
+    /// Don't print the detected dependencies, or warn about `-sys` and `-src` crates which have no
+    /// settings in the registry
+    pub fn quiet(mut self, quiet: bool) -> Self {
+        self.quiet = quiet;
+        self
+    }
+
+    /// A Nixpkgs attribute path to add to `buildInputs`, Eg `protobuf`, along with those the
+    /// registry and `package.metadata.riff` provide
+    pub fn build_input(mut self, build_input: impl Into<String>) -> Self {
+        self.build_inputs.push(build_input.into());
+        self
+    }
+
+    /// A crate to leave out of the environment, Eg `openssl-sys` when it's only built for a
+    /// feature the project doesn't use, along with any in `package.metadata.riff.exclude`
+    ///
+    /// Matched case-insensitively.
+    pub fn exclude(mut self, name: impl Into<String>) -> Self {
+        self.excluded_crates.push(name.into());
+        self
+    }
+
+    /// An environment variable to set, overriding any the registry or `package.metadata.riff`
+    /// sets with the same name
+    ///
+    /// The value is set as is, Nix doesn't interpolate `${...}` in it.
+    pub fn environment_variable(
+        mut self,
+        key: impl Into<String>,
+        value: impl Into<String>,
+    ) -> Self {
+        self.environment_variables.push((key.into(), value.into()));
+        self
+    }
+
+    /// Write a `shell.nix` for `nix-shell` instead of a flake, for Nix installations without
+    /// flakes
+    ///
+    /// The `shell.nix` is written to the same directory as the flake would be, and isn't locked.
+    pub fn legacy_nix(mut self, legacy_nix: bool) -> Self {
+        self.legacy_nix = legacy_nix;
+        self
+    }
+
+    /// A registry to layer over the public one, after any in the configuration file
+    ///
+    /// Registries added later override the mappings of earlier ones per crate.
+    pub fn registry(mut self, registry: RegistryLocation) -> Self {
+        self.registry = self.registry.registry(registry);
+        self
+    }
+
+    /// Don't verify the signature of the refreshed registry, even if `registry-public-key` is set
+    pub fn no_verify(mut self, no_verify: bool) -> Self {
+        self.registry = self.registry.no_verify(no_verify);
+        self
+    }
+
+    /// How long a cached registry is used before it is refreshed, overriding `registry-ttl`
+    pub fn registry_ttl(mut self, registry_ttl: Duration) -> Self {
+        self.registry = self.registry.ttl(registry_ttl);
+        self
+    }
+
+    /// The proxy to fetch the registry through, instead of `HTTPS_PROXY` or `ALL_PROXY`
+    pub fn registry_proxy(mut self, registry_proxy: impl Into<String>) -> Self {
+        self.registry = self.registry.proxy(registry_proxy);
+        self
+    }
+
+    /// How long fetching the registry may take before the cached registry is used instead
+    pub fn registry_timeout(mut self, registry_timeout: Duration) -> Self {
+        self.registry = self.registry.timeout(registry_timeout);
+        self
+    }
+
+    /// The directory to cache the registry and generated flakes in, instead of
+    /// `$XDG_CACHE_HOME/riff`
+    pub fn cache_dir(mut self, cache_dir: impl Into<PathBuf>) -> Self {
+        self.registry = self.registry.cache_dir(cache_dir);
+        self
+    }
+
+    /// Refresh the registry before resolving dependencies, failing if it can't be refreshed
+    pub fn refresh_registry(mut self, refresh_registry: bool) -> Self {
+        self.registry = self.registry.refresh(refresh_registry);
+        self
+    }
+
+    /// The options to load the registry with, from the configuration file and these options
+    pub(crate) async fn registry_options(&self) -> color_eyre::Result<DependencyRegistryOptions> {
+        Ok(self.registry_options_with(&Config::load().await?))
+    }
+
+    /// Apply the options which change how dependencies are detected to `dev_env`, before
+    /// [`DevEnvironment::detect`]
+    pub(crate) fn configure(&self, dev_env: &mut DevEnvironment) {
+        dev_env.target = self.target.clone();
+        dev_env.dev_dependencies = !self.no_dev;
+        dev_env.no_cargo_metadata = self.no_cargo_metadata;
+        dev_env.quiet = self.quiet;
+        dev_env.ignore_name_separators = self.ignore_name_separators;
+        for name in &self.excluded_crates {
+            tracing::trace!(%name, "Excluding a crate from the command line");
+            dev_env.exclude_crate(name);
+        }
+    }
+
+    /// Add the options which extend what was detected to `dev_env`, after
+    /// [`DevEnvironment::detect`]
+    pub(crate) fn extend(&self, dev_env: &mut DevEnvironment) -> color_eyre::Result<()> {
+        for build_input in &self.build_inputs {
+            let build_input = crate::dev_env::parse_build_input(build_input)?;
+            tracing::trace!(%build_input, "Adding a build input from the command line");
+            dev_env.add_build_input(build_input);
+        }
+        for (key, value) in &self.environment_variables {
+            crate::dev_env::check_environment_variable_name(key)?;
+            tracing::trace!(%key, %value, "Setting an environment variable from the command line");
+            dev_env.set_environment_variable(key.clone(), crate::dev_env::escape_nix_string(value));
+        }
+        Ok(())
+    }
+
+    /// Choose the Rust toolchain for `dev_env`, after [`DevEnvironment::detect`], reading
+    /// `rust-toolchain.toml` from `project_dir` if there is one
+    pub(crate) async fn select_rust_toolchain(
+        &self,
+        dev_env: &mut DevEnvironment<'_>,
+        project_dir: Option<&Path>,
+    ) -> color_eyre::Result<()> {
+        // In order of precedence: `--rust`, `package.metadata.riff.rust-channel`, `rust-toolchain.toml`
+        match self
+            .rust_channel
+            .clone()
+            .or_else(|| dev_env.rust_channel.clone())
+        {
+            Some(RustChannel::None) => {
+                tracing::debug!("Not providing a Rust toolchain, the host's will be used");
+                dev_env.host_toolchain = true;
+            }
+            Some(RustChannel::Channel(channel)) => {
+                eprintln!(
+                    "{check} Using the `{channel}` Rust toolchain",
+                    check = "✓".green(),
+                    channel = channel.cyan(),
+                );
+                dev_env.rust_toolchain = RustChannel::Channel(channel).into();
+            }
+            None if !self.no_toolchain => {
+                let found = match project_dir {
+                    Some(project_dir) => RustToolchain::find(project_dir).await?,
+                    None => None,
+                };
+                if let Some((path, rust_toolchain)) = found {
+                    eprintln!(
+                        "{check} Using the `{channel}` Rust toolchain from `{path}`",
+                        check = "✓".green(),
+                        channel = rust_toolchain.channel.cyan(),
+                        path = path.display(),
+                    );
+                    dev_env.rust_toolchain = Some(rust_toolchain);
+                }
+            }
+            None => (),
+        }
+        if !self.rust_components.is_empty() {
+            match &mut dev_env.rust_toolchain {
+                Some(rust_toolchain) => {
+                    for component in &self.rust_components {
+                        let component = crate::rust_toolchain::parse_component(component)?;
+                        tracing::trace!(%component, "Adding a Rust component from the command line");
+                        rust_toolchain.components.push(component);
+                    }
+                }
+                None => {
+                    return Err(eyre!(
+                        "Rust components can only be added to a toolchain from rust-overlay, choose one with `--rust`, like `--rust stable`"
+                    ))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The options to load the registry with, from `config` and these options
+    fn registry_options_with(&self, config: &Config) -> DependencyRegistryOptions {
+        config
+            .registry_options()
+            .overridden_by(self.registry.clone().offline(self.offline))
+    }
+
+    /// The directory to write the flake to, reusing any `flake.lock` already there
+    ///
+    /// Defaults to a directory under `$XDG_CACHE_HOME/riff/flakes` specific to the project.
+    pub fn flake_dir(mut self, flake_dir: impl Into<PathBuf>) -> Self {
+        self.flake_dir = Some(flake_dir.into());
+        self
+    }
+}
+
+/// Percent-encode the characters of `path` which aren't allowed in the path of a URL as is, like
+/// spaces, or which would end it, like `#` and `?`
+fn percent_encode_path(path: &str) -> String {
+    let mut encoded = String::with_capacity(path.len());
+    for byte in path.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'/' | b'-' | b'.' | b'_' | b'~' | b'+' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    encoded
+}
+
+/// A directory containing a generated `flake.nix` and its `flake.lock`
+#[derive(Debug)]
+pub enum FlakeDir {
+    /// A directory which is removed when dropped
+    Temporary(TempDir),
+    /// A directory which is kept, so later runs can reuse its `flake.lock`
+    Persistent(PathBuf),
+}
+
+impl FlakeDir {
+    pub fn path(&self) -> &Path {
+        match self {
+            FlakeDir::Temporary(dir) => dir.path(),
+            FlakeDir::Persistent(path) => path,
+        }
+    }
+
+    /// The `path://` flake reference to the flake, for passing to `nix`
+    ///
+    /// Fails if the path isn't UTF-8, which Nix flake references must be.
+    pub fn flake_ref(&self) -> color_eyre::Result<String> {
+        let path = self.path().to_str().ok_or_else(|| {
+            eyre!(
+                "The flake directory `{}` is not valid UTF-8, which Nix requires. Try passing another directory with `--flake-dir`.",
+                self.path().display()
+            )
+        })?;
+        Ok(format!("path://{}", percent_encode_path(path)))
+    }
+
+    /// The flake reference with the `devShell` of the workspace member `package` selected, or
+    /// the `default` shell of the whole workspace if `None`
+    pub fn installable(&self, package: Option<&str>) -> color_eyre::Result<String> {
+        let flake_ref = self.flake_ref()?;
+        Ok(match package {
+            Some(package) => format!("{flake_ref}#{package}"),
+            None => flake_ref,
+        })
+    }
+
+    /// Stop a temporary directory from being removed, returning its path
+    pub fn keep(self) -> PathBuf {
+        match self {
+            FlakeDir::Temporary(dir) => dir.into_path(),
+            FlakeDir::Persistent(path) => path,
+        }
+    }
+
+    /// The `shell.nix` written instead of the flake by [`GenerateOptions::legacy_nix`]
+    pub fn shell_nix(&self) -> PathBuf {
+        self.path().join("shell.nix")
+    }
+
+    /// If the flake has been locked
+    pub fn is_locked(&self) -> bool {
+        self.path().join("flake.lock").exists()
+    }
+
+    /// The default directory for a project's flake, in the cache directory
+    ///
+    /// The directory is keyed on the project directory, its `Cargo.lock`, and the generated
+    /// `flake` (which covers the project's metadata and the options used). A project keeps
+    /// reusing the same `flake.lock` until one of them changes, and going back to earlier options
+    /// reuses the lock made with them.
+    async fn for_project(
+        project_dir: &Path,
+        flake: &str,
+        cache_dir: Option<&Path>,
+    ) -> color_eyre::Result<Self> {
+        let mut hasher = project_hasher(project_dir).await;
+        if let Ok(cargo_lock) = tokio::fs::read(project_dir.join("Cargo.lock")).await {
+            hasher.update(cargo_lock);
+        }
+        hasher.update(flake.as_bytes());
+        let hash = format!("{:x}", hasher.finalize());
+
+        let path = dependency_registry::cache_dir(cache_dir)?
+            .join(FLAKES_CACHE_PATH)
+            .join(hash);
+        tokio::fs::create_dir_all(&path)
+            .await
+            .wrap_err_with(|| format!("Unable to create `{}`", path.display()))?;
+        Ok(FlakeDir::Persistent(path))
+    }
+}
+
+/// A hasher which has been fed the canonical path of `project_dir`
+async fn project_hasher(project_dir: &Path) -> Sha256 {
+    let mut hasher = Sha256::new();
+    hasher.update(
+        tokio::fs::canonicalize(project_dir)
+            .await
+            .unwrap_or_else(|_| project_dir.to_path_buf())
+            .to_string_lossy()
+            .as_bytes(),
+    );
+    hasher
+}
+
+/// Where the `flake.lock` shared by the flakes of `project_dir` with the same `inputs` is kept
+///
+/// Flakes which only differ in their outputs, like those of `riff shell` and `riff run cargo
+/// test`, lock the same, so a new flake starts from this lock instead of locking Nixpkgs again.
+/// That can't pick a different revision than the project already uses, and works offline.
+async fn shared_lock_path(
+    project_dir: &Path,
+    inputs: &str,
+    cache_dir: Option<&Path>,
+) -> color_eyre::Result<PathBuf> {
+    let mut hasher = project_hasher(project_dir).await;
+    hasher.update(inputs.as_bytes());
+    let hash = format!("{:x}", hasher.finalize());
+
+    let locks_dir = dependency_registry::cache_dir(cache_dir)?
+        .join(FLAKES_CACHE_PATH)
+        .join(FLAKE_LOCKS_PATH);
+    tokio::fs::create_dir_all(&locks_dir)
+        .await
+        .wrap_err_with(|| format!("Unable to create `{}`", locks_dir.display()))?;
+    Ok(locks_dir.join(format!("{hash}.lock")))
+}
+
+/// Generates a `flake.nix` by inspecting the project directory for supported project types.
+///
+/// The flake is written and locked in [`GenerateOptions::flake_dir`], or a per-project directory
+/// in the cache directory. If neither can be created, a temporary directory which is removed
+/// when the returned [`FlakeDir`] is dropped is used instead.
+#[tracing::instrument(skip_all, fields(project_dir = ?options.project_dir, offline = %options.offline))]
+pub async fn generate_flake(options: &GenerateOptions) -> color_eyre::Result<FlakeDir> {
+    let offline = options.offline;
+    let project_dir = match &options.project_dir {
+        Some(dir) => dir.clone(),
         None => std::env::current_dir().wrap_err("Current working directory was invalid")?,
     };
+    let project_dir = crate::cargo_metadata::find_project_dir(&project_dir).await?;
     tracing::debug!("Project directory is '{}'.", project_dir.display());
 
-    let registry = DependencyRegistry::new(offline).await?;
+    let config = Config::load().await?;
+    let registry = DependencyRegistry::new(&options.registry_options_with(&config)).await?;
     let mut dev_env = DevEnvironment::new(&registry);
+    options.configure(&mut dev_env);
+    // A `shell.nix` has one shell, so it only provides the chosen member
+    dev_env.dev_shell_per_member = !options.legacy_nix;
 
-    match dev_env.detect(&project_dir).await {
-        Ok(_) => {}
-        Err(err) => {
-            let err_msg = format!(
-                "\
-                `{colored_project_dir}` doesn't contain a project recognized by Riff.\n\
-                Try running `{riff_shell}` in a Rust project directory.\
-                ",
-                colored_project_dir = &project_dir.display().to_string().green(),
-                riff_shell = "riff shell".cyan(),
-            );
-            eprintln!("{err_msg}\n\nUnderlying error:\n{err}", err = err.red());
-            std::process::exit(1);
-        }
-    };
+    dev_env
+        .detect(&project_dir, &options.features, options.package.as_deref())
+        .await?;
+
+    options.extend(&mut dev_env)?;
 
     // If the user is using an old version of `riff`, we want to let them know.
     // We do it after detecting the dependencies because we'd prefer the user's first
@@ -66,9 +581,17 @@ pub async fn generate_flake_from_project_dir(
         );
     }
 
-    if !(disable_telemetry || offline) {
+    registry.warn_if_stale().await;
+
+    if !(options.disable_telemetry || offline || crate::telemetry::disabled_by_env()) {
         match Telemetry::new()
             .await
+            .with_configured_url(
+                options
+                    .telemetry_url
+                    .as_deref()
+                    .or(config.telemetry_url.as_deref()),
+            )
             .with_detected_languages(&dev_env.detected_languages)
             .send()
             .await
@@ -78,15 +601,111 @@ pub async fn generate_flake_from_project_dir(
         };
     }
 
-    let flake_nix = dev_env.to_flake();
-    tracing::trace!("Generated 'flake.nix':\n{}", flake_nix);
+    match (
+        &options.nixpkgs_rev,
+        &dev_env.nixpkgs_rev,
+        &config.nixpkgs_rev,
+    ) {
+        (Some(nixpkgs_rev), _, _) => {
+            dev_env.nixpkgs_rev = Some(crate::dev_env::parse_nixpkgs_rev(nixpkgs_rev)?)
+        }
+        (None, Some(nixpkgs_rev), _) => {
+            tracing::trace!(%nixpkgs_rev, "Using the Nixpkgs revision from `package.metadata.riff.nixpkgs`")
+        }
+        (None, None, Some(nixpkgs_rev)) => {
+            tracing::trace!(%nixpkgs_rev, "Using the Nixpkgs revision from the configuration file");
+            dev_env.nixpkgs_rev = Some(
+                crate::dev_env::parse_nixpkgs_rev(nixpkgs_rev)
+                    .wrap_err("Invalid `nixpkgs-rev` in the configuration file")?,
+            )
+        }
+        (None, None, None) => tracing::trace!(
+            nixpkgs_rev = crate::dev_env::DEFAULT_NIXPKGS_REV,
+            "No Nixpkgs revision pinned, using the default"
+        ),
+    }
+
+    if let Some(nixpkgs_flake) = &options.nixpkgs_flake {
+        dev_env.nixpkgs_flake = Some(crate::dev_env::parse_nixpkgs_flake(nixpkgs_flake)?);
+    }
+    tracing::trace!(nixpkgs_url = %dev_env.nixpkgs_url(), "Using Nixpkgs");
+
+    options
+        .select_rust_toolchain(&mut dev_env, Some(&project_dir))
+        .await?;
+
+    let (file_name, flake) = if options.legacy_nix {
+        ("shell.nix", dev_env.to_shell_nix()?)
+    } else {
+        ("flake.nix", dev_env.to_flake())
+    };
+    tracing::trace!("Generated '{}':\n{}", file_name, flake);
 
-    let flake_dir = TempDir::new()?;
-    let flake_nix_path = flake_dir.path().join("flake.nix");
+    let flake_dir = match &options.flake_dir {
+        Some(flake_dir) => {
+            tokio::fs::create_dir_all(flake_dir)
+                .await
+                .wrap_err_with(|| format!("Unable to create `{}`", flake_dir.display()))?;
+            // Nix requires an absolute path in `path://` flake references.
+            FlakeDir::Persistent(tokio::fs::canonicalize(flake_dir).await?)
+        }
+        None => {
+            match FlakeDir::for_project(&project_dir, &flake, options.registry.cache_dir.as_deref())
+                .await
+            {
+                Ok(flake_dir) => flake_dir,
+                Err(err) => {
+                    tracing::debug!(%err, "Could not create a flake directory in the cache directory, using a temporary directory");
+                    FlakeDir::Temporary(TempDir::new()?)
+                }
+            }
+        }
+    };
 
-    tokio::fs::write(&flake_nix_path, &flake_nix)
+    let flake_path = flake_dir.path().join(file_name);
+    let unchanged = tokio::fs::read_to_string(&flake_path)
         .await
-        .wrap_err("Unable to write flake.nix")?;
+        .map(|existing| existing == flake)
+        .unwrap_or(false);
+    if !unchanged {
+        tracing::debug!(path = %flake_path.display(), "Writing {}", file_name);
+        tokio::fs::write(&flake_path, &flake)
+            .await
+            .wrap_err_with(|| format!("Unable to write {file_name}"))?;
+    }
+    if options.legacy_nix {
+        return Ok(flake_dir);
+    }
+    if unchanged && flake_dir.is_locked() {
+        tracing::debug!(flake_dir = %flake_dir.path().display(), "Reusing the cached flake and its lock");
+        return Ok(flake_dir);
+    }
+
+    let flake_lock_path = flake_dir.path().join("flake.lock");
+    let shared_lock_path = match shared_lock_path(
+        &project_dir,
+        &dev_env.flake_inputs(),
+        options.registry.cache_dir.as_deref(),
+    )
+    .await
+    {
+        Ok(path) => Some(path),
+        Err(err) => {
+            tracing::debug!(%err, "Could not find where the project's shared flake lock is kept");
+            None
+        }
+    };
+    if let (Some(shared_lock_path), false) = (&shared_lock_path, flake_dir.is_locked()) {
+        match tokio::fs::copy(shared_lock_path, &flake_lock_path).await {
+            Ok(_) => {
+                tracing::debug!(path = %shared_lock_path.display(), "Starting from the project's shared flake lock")
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => (),
+            Err(err) => {
+                tracing::debug!(%err, path = %shared_lock_path.display(), "Could not copy the project's shared flake lock")
+            }
+        }
+    }
 
     let mut nix_lock_command = Command::new("nix");
     nix_lock_command
@@ -94,39 +713,37 @@ pub async fn generate_flake_from_project_dir(
         .arg("lock")
         .args(["--extra-experimental-features", "flakes nix-command"])
         .arg("-L")
-        .arg(format!("path://{}", flake_dir.path().to_str().unwrap()));
+        .arg(flake_dir.flake_ref()?);
 
     if offline {
         nix_lock_command.arg("--offline");
     }
 
     tracing::trace!(command = ?nix_lock_command.as_std(), "Running");
-    let spinner = SimpleSpinner::new_with_message(Some(&format!(
-        "Running `{nix_flake_lock}`",
-        nix_flake_lock = "nix flake lock".cyan()
-    )))
-    .context("Failed to construct progress spinner")?;
-
-    let nix_lock_exit = match nix_lock_command.output().await {
-        Ok(nix_lock_exit) => nix_lock_exit,
-        Err(err) => {
-            let err_msg = format!(
-                "\
-                Could not execute `{nix_lock}`. Is `{nix}` installed?\n\n\
-                Get instructions for installing Nix: {nix_install_url}\
-                ",
-                nix_lock = "nix flake lock".cyan(),
-                nix = "nix".cyan(),
-                nix_install_url = "https://nixos.org/download.html".blue().underline(),
-            );
-            eprintln!("{err_msg}\n\nUnderlying error:\n{err}", err = err.red());
-            std::process::exit(1);
-        }
+    let spinner = if options.quiet {
+        indicatif::ProgressBar::hidden()
+    } else {
+        SimpleSpinner::new_with_message(Some(&format!(
+            "Running `{nix_flake_lock}`",
+            nix_flake_lock = "nix flake lock".cyan()
+        )))
+        .context("Failed to construct progress spinner")?
     };
 
+    let nix_lock_exit = nix_lock_command
+        .output()
+        .await
+        .map_err(|err| SpawnError::new("nix flake lock", Tool::Nix, err))?;
+
     spinner.finish_and_clear();
 
     if !nix_lock_exit.status.success() {
+        crate::nix_dev_env::warn_if_oom_killed(
+            "nix flake lock",
+            &nix_lock_exit.status,
+            Some(&[nix_lock_exit.stdout.as_slice(), &nix_lock_exit.stderr].concat()),
+        );
+        crate::nix_dev_env::warn_if_nix_too_old("nix flake lock").await;
         return Err(eyre!(
             "`nix flake lock` exited with code {}:\n{}",
             nix_lock_exit
@@ -138,15 +755,114 @@ pub async fn generate_flake_from_project_dir(
         ));
     }
 
+    if let Some(shared_lock_path) = &shared_lock_path {
+        let result = match tokio::fs::read(&flake_lock_path).await {
+            Ok(flake_lock) => {
+                dependency_registry::write_atomically(shared_lock_path, &flake_lock).await
+            }
+            Err(err) => Err(err),
+        };
+        if let Err(err) = result {
+            tracing::debug!(%err, path = %shared_lock_path.display(), "Could not share the flake lock with the project's other flakes");
+        }
+    }
+
     Ok(flake_dir)
 }
 
 #[cfg(test)]
 mod tests {
-    use super::generate_flake_from_project_dir;
+    use super::{generate_flake, shared_lock_path, FlakeDir, GenerateOptions};
+    use crate::cargo_metadata::ProjectError;
+    use crate::dependency_registry::{DependencyRegistry, DependencyRegistryOptions};
+    use crate::dev_env::DevEnvironment;
     use tempfile::TempDir;
     use tokio::fs::{read_to_string, write};
 
+    #[tokio::test]
+    async fn flake_dir_for_project_keys_on_flake() -> eyre::Result<()> {
+        let cache_dir = TempDir::new()?;
+        let project_dir = TempDir::new()?;
+        let for_project = |flake: &'static str| {
+            let project_dir = project_dir.path().to_path_buf();
+            let cache_dir = cache_dir.path().to_path_buf();
+            async move { FlakeDir::for_project(&project_dir, flake, Some(&cache_dir)).await }
+        };
+        let a = for_project("{ a }").await?;
+        assert_eq!(a.path(), for_project("{ a }").await?.path());
+        assert_ne!(a.path(), for_project("{ b }").await?.path());
+
+        write(project_dir.path().join("Cargo.lock"), "version = 3").await?;
+        assert_ne!(a.path(), for_project("{ a }").await?.path());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn shared_lock_path_keys_on_inputs() -> eyre::Result<()> {
+        let cache_dir = TempDir::new()?;
+        let project_dir = TempDir::new()?;
+        let other_project_dir = TempDir::new()?;
+        let inputs = "  inputs.nixpkgs.url = \"github:NixOS/nixpkgs/nixos-unstable\";";
+        let path = shared_lock_path(project_dir.path(), inputs, Some(cache_dir.path())).await?;
+        assert!(path.parent().is_some_and(|dir| dir.is_dir()));
+        // Unlike the flake's directory, it doesn't change with `Cargo.lock`
+        write(project_dir.path().join("Cargo.lock"), "version = 3").await?;
+        assert_eq!(
+            path,
+            shared_lock_path(project_dir.path(), inputs, Some(cache_dir.path())).await?
+        );
+        assert_ne!(
+            path,
+            shared_lock_path(other_project_dir.path(), inputs, Some(cache_dir.path())).await?
+        );
+        assert_ne!(
+            path,
+            shared_lock_path(
+                project_dir.path(),
+                "  inputs.nixpkgs.url = \"github:NixOS/nixpkgs/nixos-23.11\";",
+                Some(cache_dir.path())
+            )
+            .await?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn flake_dir_flake_ref() -> eyre::Result<()> {
+        assert_eq!(
+            FlakeDir::Persistent("/tmp/riff flakes/a#b?c%d".into()).flake_ref()?,
+            "path:///tmp/riff%20flakes/a%23b%3Fc%25d"
+        );
+        assert_eq!(
+            FlakeDir::Persistent("/tmp/riff".into()).installable(Some("app"))?,
+            "path:///tmp/riff#app"
+        );
+        #[cfg(unix)]
+        {
+            use std::ffi::OsStr;
+            use std::os::unix::ffi::OsStrExt;
+            let err = FlakeDir::Persistent(OsStr::from_bytes(b"/tmp/\xff").into())
+                .flake_ref()
+                .unwrap_err();
+            assert!(err.to_string().contains("not valid UTF-8"), "{err}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn generate_options_nixpkgs() {
+        let options = GenerateOptions::new().nixpkgs("nixos-23.11");
+        assert_eq!(options.nixpkgs_rev.as_deref(), Some("nixos-23.11"));
+        assert_eq!(options.nixpkgs_flake, None);
+
+        let options = options.nixpkgs("git+ssh://git@example.com/nixpkgs");
+        assert_eq!(options.nixpkgs_rev, None);
+        assert_eq!(
+            options.nixpkgs_flake.as_deref(),
+            Some("git+ssh://git@example.com/nixpkgs")
+        );
+    }
+
     // We can't run this test by default because it calls Nix. Calling Nix inside Nix doesn't appear
     // to work very well (at least, for this use case).
     #[tokio::test]
@@ -173,8 +889,11 @@ path = "lib.rs"
         )
         .await?;
 
-        let flake_dir =
-            generate_flake_from_project_dir(Some(temp_dir.path().to_owned()), true, true).await?;
+        let options = GenerateOptions::new()
+            .project_dir(temp_dir.path())
+            .offline(true)
+            .disable_telemetry(true);
+        let flake_dir = generate_flake(&options).await?;
         let flake = read_to_string(flake_dir.path().join("flake.nix")).await?;
 
         assert!(
@@ -186,5 +905,184 @@ path = "lib.rs"
         Ok(())
     }
 
-    // NOTE: we can't test the failure case since it will `std::process::exit`
+    #[tokio::test]
+    async fn generate_flake_offline_makes_no_connections() -> eyre::Result<()> {
+        let cache_dir = TempDir::new()?;
+        // Anything fetched is sent here, the registry through the proxy
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        listener.set_nonblocking(true)?;
+        let url = format!("http://{}", listener.local_addr()?);
+        let temp_dir = TempDir::new()?;
+        write(temp_dir.path().join("lib.rs"), "").await?;
+        write(
+            temp_dir.path().join("Cargo.toml"),
+            r#"
+[package]
+name = "riff-test"
+version = "0.1.0"
+edition = "2021"
+
+[lib]
+path = "lib.rs"
+        "#,
+        )
+        .await?;
+        write(
+            temp_dir.path().join("Cargo.lock"),
+            "version = 3\n\n[[package]]\nname = \"riff-test\"\nversion = \"0.1.0\"\n",
+        )
+        .await?;
+
+        // Telemetry is left enabled, `--offline` alone must stop it. `shell.nix` is generated so
+        // Nix isn't run to lock a flake.
+        let options = GenerateOptions::new()
+            .project_dir(temp_dir.path())
+            .cache_dir(cache_dir.path())
+            .registry_proxy(&url)
+            .telemetry_url(&url)
+            .offline(true)
+            .no_cargo_metadata(true)
+            .legacy_nix(true)
+            .quiet(true);
+        generate_flake(&options).await?;
+
+        // Also catch anything still being sent in the background
+        let deadline = std::time::Instant::now() + std::time::Duration::from_millis(500);
+        loop {
+            match listener.accept() {
+                Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {
+                    if std::time::Instant::now() > deadline {
+                        return Ok(());
+                    }
+                    tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+                }
+                Ok((_, addr)) => return Err(eyre::eyre!("`--offline` connected out, from {addr}")),
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn generate_flake_rust_components() -> eyre::Result<()> {
+        let cache_dir = TempDir::new()?;
+        let temp_dir = TempDir::new()?;
+        write(temp_dir.path().join("lib.rs"), "").await?;
+        write(
+            temp_dir.path().join("Cargo.toml"),
+            "[package]\nname = \"riff-test\"\nversion = \"0.1.0\"\n\n[lib]\npath = \"lib.rs\"\n",
+        )
+        .await?;
+        write(
+            temp_dir.path().join("Cargo.lock"),
+            "version = 3\n\n[[package]]\nname = \"riff-test\"\nversion = \"0.1.0\"\n",
+        )
+        .await?;
+        let options = GenerateOptions::new()
+            .project_dir(temp_dir.path())
+            .cache_dir(cache_dir.path())
+            .offline(true)
+            .disable_telemetry(true)
+            .no_cargo_metadata(true)
+            .no_toolchain(true)
+            .legacy_nix(true)
+            .quiet(true)
+            .rust_component("rust-analyzer");
+
+        // The toolchain from Nixpkgs has no components
+        assert!(generate_flake(&options).await.is_err());
+
+        let options = options.rust_channel("nightly".parse()?);
+        let flake_dir = generate_flake(&options).await?;
+        let shell_nix = read_to_string(flake_dir.path().join("shell.nix")).await?;
+        assert!(
+            shell_nix.contains(r#"channel = "nightly"; components = [ "rust-analyzer" ];"#),
+            "{shell_nix}"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn generate_flake_failure() -> eyre::Result<()> {
+        let cache_dir = TempDir::new()?;
+        let temp_dir = TempDir::new()?;
+
+        let options = GenerateOptions::new()
+            .project_dir(temp_dir.path())
+            .cache_dir(cache_dir.path())
+            .offline(true)
+            .disable_telemetry(true)
+            .quiet(true);
+        let err = generate_flake(&options).await.unwrap_err();
+        assert!(
+            matches!(
+                err.downcast_ref::<ProjectError>(),
+                Some(ProjectError::NoManifest(_))
+            ),
+            "{err:?}"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn configure() -> eyre::Result<()> {
+        let cache_dir = TempDir::new()?;
+        let registry = DependencyRegistry::new(
+            &DependencyRegistryOptions::new()
+                .cache_dir(cache_dir.path())
+                .offline(true),
+        )
+        .await?;
+        let mut dev_env = DevEnvironment::new(&registry);
+
+        let options = GenerateOptions::new()
+            .target("aarch64-unknown-linux-gnu".parse()?)
+            .no_dev(true)
+            .ignore_name_separators(true)
+            .exclude("OpenSSL-sys");
+        options.configure(&mut dev_env);
+        assert_eq!(
+            dev_env.target.as_ref().map(|target| target.triple.as_str()),
+            Some("aarch64-unknown-linux-gnu")
+        );
+        assert!(!dev_env.dev_dependencies);
+        assert!(dev_env.ignore_name_separators);
+        assert!(dev_env.excluded_crates.contains("openssl-sys"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn extend() -> eyre::Result<()> {
+        let cache_dir = TempDir::new()?;
+        let registry = DependencyRegistry::new(
+            &DependencyRegistryOptions::new()
+                .cache_dir(cache_dir.path())
+                .offline(true),
+        )
+        .await?;
+        let mut dev_env = DevEnvironment::new(&registry);
+        dev_env.add_build_input("openssl".to_string());
+
+        GenerateOptions::new()
+            .build_input("cmake")
+            .build_input("openssl")
+            .environment_variable("RUSTFLAGS", "-C target-cpu=native")
+            .extend(&mut dev_env)?;
+        assert_eq!(
+            dev_env.build_inputs,
+            ["cmake".to_string(), "openssl".to_string()].into()
+        );
+        assert_eq!(
+            dev_env
+                .environment_variables
+                .get("RUSTFLAGS")
+                .map(String::as_str),
+            Some("-C target-cpu=native")
+        );
+
+        assert!(GenerateOptions::new()
+            .build_input("")
+            .extend(&mut dev_env)
+            .is_err());
+        Ok(())
+    }
 }
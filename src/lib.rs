@@ -0,0 +1,202 @@
+//! Riff automatically sets up build environments using Nix.
+//!
+//! Most users will want the `riff` binary. Tools building on top of Riff can generate the same
+//! flake it uses by constructing [`GenerateOptions`] and passing them to [`generate_flake`]:
+//!
+//! ```no_run
+//! # async fn example() -> color_eyre::Result<()> {
+//! let options = riff::GenerateOptions::new()
+//!     .project_dir("path/to/project")
+//!     .offline(true);
+//! let flake_dir = riff::generate_flake(&options).await?;
+//! println!("{}", flake_dir.path().join("flake.nix").display());
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! To find what Riff provides for a set of crates without generating a flake, see
+//! [`dependency_registry::DependencyRegistry::resolve`].
+
+mod cargo_metadata;
+#[doc(hidden)] // Used by the `riff` binary, not part of the stable API.
+pub mod cmds;
+#[doc(hidden)] // Used by the `riff` binary, not part of the stable API.
+pub mod color;
+mod config;
+mod crates_io;
+mod cross_target;
+pub mod dependency_registry;
+mod dev_env;
+mod flake_generator;
+mod nix_dev_env;
+mod rust_toolchain;
+mod spawn_error;
+#[doc(hidden)] // Used by the `riff` binary, not part of the stable API.
+pub mod spinner;
+#[doc(hidden)] // Used by the `riff` binary, not part of the stable API.
+pub mod telemetry;
+
+use std::path::PathBuf;
+
+use clap::{Parser, ValueEnum};
+
+use cmds::Commands;
+use color::ColorChoice;
+use telemetry::TelemetryLevel;
+
+pub use cargo_metadata::{CargoFeatures, ProjectError};
+pub use cross_target::CrossTarget;
+pub use dependency_registry::RegistryLocation;
+pub use flake_generator::{generate_flake, FlakeDir, GenerateOptions};
+pub use rust_toolchain::RustChannel;
+pub use spawn_error::{SpawnError, Tool};
+
+const RIFF_XDG_PREFIX: &str = "riff";
+
+#[doc(hidden)] // Used by the `riff` binary, not part of the stable API.
+#[derive(Debug, Parser)]
+#[clap(name = "riff")]
+#[clap(version, about = "Automatically set up build environments using Nix", long_about = None)]
+pub struct Cli {
+    #[clap(subcommand)]
+    pub command: Commands,
+    /// Turn off user telemetry ping, `DO_NOT_TRACK` also turns it off
+    #[clap(long, global = true, env = "RIFF_DISABLE_TELEMETRY")]
+    pub disable_telemetry: bool,
+    /// How much telemetry to send, `minimal` leaves out the installation's ID and what the
+    /// project uses
+    ///
+    /// See exactly what is sent with `riff telemetry show`.
+    #[clap(
+        long,
+        global = true,
+        value_enum,
+        env = "RIFF_TELEMETRY_LEVEL",
+        value_name = "LEVEL",
+        default_value_t = TelemetryLevel::Full
+    )]
+    pub telemetry_level: TelemetryLevel,
+    /// Disable all network usage except `nix develop`
+    // TODO(@hoverbear): Can we disable that, too?
+    #[clap(long, global = true, env = "RIFF_OFFLINE")]
+    pub offline: bool,
+    /// A registry file or URL to layer over the public dependency registry, may be repeated
+    ///
+    /// Later registries override the mappings of earlier ones per crate.
+    #[clap(
+        long,
+        global = true,
+        env = "RIFF_REGISTRY_FILE",
+        value_name = "PATH|URL"
+    )]
+    pub registry: Vec<RegistryLocation>,
+    /// A URL to refresh the public registry from instead of the default, may be repeated
+    ///
+    /// The mirrors are tried in order until one serves a registry riff understands. Can also be set
+    /// with `registry-mirrors` in the configuration file.
+    #[clap(
+        long,
+        global = true,
+        env = "RIFF_REGISTRY_MIRRORS",
+        value_delimiter = ',',
+        value_name = "URL"
+    )]
+    pub registry_mirror: Vec<String>,
+    /// Don't verify the signature of the refreshed registry, even if `registry-public-key` is set
+    #[clap(long, global = true)]
+    pub no_verify: bool,
+    /// How many seconds a cached registry is used before it is refreshed, `0` always refreshes it
+    ///
+    /// Defaults to an hour, or `registry-ttl` in the configuration file.
+    #[clap(long, global = true, value_name = "SECONDS")]
+    pub registry_ttl: Option<u64>,
+    /// The proxy to fetch the registry through, instead of `HTTPS_PROXY` or `ALL_PROXY`
+    #[clap(long, global = true, value_name = "URL")]
+    pub registry_proxy: Option<String>,
+    /// How long fetching the registry may take before the cached registry is used instead
+    #[clap(long, global = true, value_name = "SECONDS")]
+    pub registry_timeout: Option<u64>,
+    /// The directory to cache the registry and generated flakes in, instead of
+    /// `$XDG_CACHE_HOME/riff`
+    #[clap(long, global = true, env = "RIFF_CACHE_DIR", value_name = "PATH")]
+    pub cache_dir: Option<PathBuf>,
+    /// Refresh the registry before resolving dependencies, instead of in the background
+    ///
+    /// Failing to refresh it is then an error, rather than falling back to the cached registry.
+    #[clap(long, global = true, conflicts_with = "no_refresh")]
+    pub refresh: bool,
+    /// Don't refresh the registry, using the cached one, without otherwise going offline
+    ///
+    /// Unlike `--offline`, registries at URLs are still fetched and telemetry is still sent. Useful
+    /// on metered connections, or in CI which seeds the cache.
+    #[clap(long, global = true)]
+    pub no_refresh: bool,
+    /// Fail if there's no cached registry and it can't be refreshed, instead of using the registry
+    /// bundled with riff, which may be out of date
+    ///
+    /// Can also be set with `no-fallback` in the configuration file.
+    #[clap(long, global = true)]
+    pub no_fallback: bool,
+    /// Warn if the registry couldn't be refreshed and the cached registry is a week old
+    ///
+    /// This is the default when stderr is a terminal. The age can be set with
+    /// `registry-stale-days` in the configuration file.
+    #[clap(long, global = true)]
+    pub warn_stale: bool,
+    /// Print out debug logging, the same as `--log-level debug`
+    #[clap(long, global = true, conflicts_with = "log_level")]
+    pub debug: bool,
+    /// How much riff logs, overriding `RIFF_LOG`
+    ///
+    /// Defaults to `warn`. `RIFF_LOG` takes a level too, or tracing directives like
+    /// `riff::telemetry=debug` for more control.
+    #[clap(long, global = true, value_enum, value_name = "LEVEL")]
+    pub log_level: Option<LogLevel>,
+    /// Also write the log to this file, Eg to attach a full trace to a bug report
+    ///
+    /// The file is overwritten. It logs at the same level as stderr, so pass `--debug` too for
+    /// the full trace.
+    #[clap(long, global = true, value_name = "PATH")]
+    pub log_file: Option<PathBuf>,
+    /// Don't show progress spinners while riff waits for `cargo` or `nix`
+    #[clap(long, global = true)]
+    pub no_progress: bool,
+    /// When to color the output, `auto` colors it if stderr is a terminal and `NO_COLOR` isn't set
+    #[clap(long, global = true, value_enum, value_name = "WHEN", default_value_t = ColorChoice::Auto)]
+    pub color: ColorChoice,
+    /// The format of `--log-file`, `json` writes one object per line
+    #[clap(long, global = true, value_enum, default_value_t = LogFormat::Pretty)]
+    pub log_format: LogFormat,
+}
+
+#[doc(hidden)] // Used by the `riff` binary, not part of the stable API.
+/// The levels of `--log-level` and `RIFF_LOG`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LogLevel {
+    Trace,
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl std::fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let level = match self {
+            LogLevel::Trace => "trace",
+            LogLevel::Debug => "debug",
+            LogLevel::Info => "info",
+            LogLevel::Warn => "warn",
+            LogLevel::Error => "error",
+        };
+        f.write_str(level)
+    }
+}
+
+#[doc(hidden)] // Used by the `riff` binary, not part of the stable API.
+/// The formats of `--log-file`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    Pretty,
+    Json,
+}
@@ -1,80 +1,127 @@
-mod cargo_metadata;
-mod cmds;
-mod dependency_registry;
-mod dev_env;
-mod flake_generator;
-mod nix_dev_env;
-mod spinner;
-mod telemetry;
-
-use std::error::Error;
 use std::io::Write;
+use std::path::Path;
 use std::process::ExitCode;
+use std::sync::Arc;
 
-use atty::Stream;
-use clap::Parser;
+use clap::{Parser, ValueEnum};
+use color_eyre::config::{HookBuilder, Theme};
 use eyre::WrapErr;
-use owo_colors::OwoColorize;
 use tracing_error::ErrorLayer;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
 
-use cmds::Commands;
-use telemetry::Telemetry;
-
-const RIFF_XDG_PREFIX: &str = "riff";
-
-#[derive(Debug, Parser)]
-#[clap(name = "riff")]
-#[clap(version, about = "Automatically set up build environments using Nix", long_about = None)]
-struct Cli {
-    #[clap(subcommand)]
-    command: Commands,
-    /// Turn off user telemetry ping
-    #[clap(long, global = true, env = "RIFF_DISABLE_TELEMETRY")]
-    disable_telemetry: bool,
-    /// Disable all network usage except `nix develop`
-    // TODO(@hoverbear): Can we disable that, too?
-    #[clap(long, global = true, env = "RIFF_OFFLINE")]
-    offline: bool,
-    /// Print out debug logging
-    #[clap(long, global = true)]
-    debug: bool,
-}
+use riff::cmds::Commands;
+use riff::color::{ColorChoice, Colorize};
+use riff::telemetry::{self, Telemetry};
+use riff::{Cli, LogFormat, LogLevel};
+use riff::{ProjectError, SpawnError};
 
 #[tokio::main]
 async fn main() -> color_eyre::Result<std::process::ExitCode> {
-    color_eyre::config::HookBuilder::default()
-        .issue_url(concat!(env!("CARGO_PKG_REPOSITORY"), "/issues/new"))
-        .install()?;
-
-    setup_tracing().await?;
-
     let maybe_args = Cli::try_parse();
 
+    riff::color::init(
+        maybe_args
+            .as_ref()
+            .map_or(ColorChoice::Auto, |args| args.color),
+    );
+    let mut hook =
+        HookBuilder::default().issue_url(concat!(env!("CARGO_PKG_REPOSITORY"), "/issues/new"));
+    if !riff::color::enabled() {
+        hook = hook.theme(Theme::new());
+    }
+    hook.install()?;
+    if matches!(&maybe_args, Ok(args) if args.no_progress) {
+        riff::spinner::disable();
+    }
+
     let args = match maybe_args {
-        Ok(args) => args,
+        Ok(args) => {
+            let log_level = args.log_level.or(args.debug.then_some(LogLevel::Debug));
+            setup_tracing(log_level, args.log_file.as_deref(), args.log_format).await?;
+            args
+        }
         Err(e) => {
-            let telemetry_ok_via_env = match std::env::var("RIFF_DISABLE_TELEMETRY")
-                .or_else(|_| std::env::var("RIFF_OFFLINE"))
-            {
-                Ok(val) if val == "false" || val == "0" || val.is_empty() => true,
-                Err(_) => true,
-                _ => false,
-            };
-            let telemetry_ok_via_flag = !std::env::args()
+            let debug = std::env::args()
                 .take_while(|v| v != "--")
-                .any(|v| v == *"--disable-telemetry" || v == *"--offline");
+                .any(|v| v == "--debug");
+            setup_tracing(debug.then_some(LogLevel::Debug), None, LogFormat::Pretty).await?;
+            let telemetry_ok_via_env = !telemetry::disabled_by_env()
+                && match std::env::var("RIFF_OFFLINE") {
+                    Ok(val) if val == "false" || val == "0" || val.is_empty() => true,
+                    Err(_) => true,
+                    _ => false,
+                };
+            // The arguments couldn't be parsed, so any `--telemetry-level` is taken to lower it
+            let telemetry_ok_via_flag = !std::env::args().take_while(|v| v != "--").any(|v| {
+                v == *"--disable-telemetry"
+                    || v == *"--offline"
+                    || v.starts_with("--telemetry-level")
+            });
             if telemetry_ok_via_env && telemetry_ok_via_flag {
                 Telemetry::new().await.send().await.ok();
             }
             e.exit() // Dead!
         }
     };
-    match args.command {
+    match run(args.command).await {
+        Err(err) => match err.downcast_ref::<ProjectError>() {
+            Some(project_err) => {
+                let hint = match project_err {
+                    ProjectError::NoManifest(_) => format!(
+                        "Riff only supports Rust projects today. Run it in a Rust project, or pass \
+                        `{project_dir}` with the path to one.",
+                        project_dir = "--project-dir".cyan(),
+                    ),
+                    ProjectError::InvalidManifest(_, toml_err) => format!(
+                        "{toml_err}\n\nFix the `{cargo_toml}` and try again.",
+                        toml_err = toml_err.to_string().trim_end().red(),
+                        cargo_toml = "Cargo.toml".cyan(),
+                    ),
+                    ProjectError::InvalidMetadata(_, _, de_err) => format!(
+                        "{de_err}\n\nFix the `{metadata}` and try again.",
+                        de_err = de_err.to_string().trim_end().red(),
+                        metadata = "package.metadata.riff".cyan(),
+                    ),
+                };
+                eprintln!("{project_err}.\n\n{hint}");
+                Ok(project_err.exit_code().into())
+            }
+            None => match err.downcast_ref::<SpawnError>() {
+                Some(spawn_err) => {
+                    eprintln!(
+                        "{spawn_err}. {hint}\n\nUnderlying error:\n{source}",
+                        hint = spawn_err.hint(),
+                        source = std::error::Error::source(spawn_err)
+                            .map(ToString::to_string)
+                            .unwrap_or_default()
+                            .red(),
+                    );
+                    Ok(spawn_err.exit_code().into())
+                }
+                None => Err(err),
+            },
+        },
+        exit_code => exit_code,
+    }
+}
+
+async fn run(command: Commands) -> color_eyre::Result<ExitCode> {
+    match command {
         Commands::PrintDevEnv(print_dev_env) => {
             Ok(exit_status_to_exit_code(print_dev_env.cmd().await?))
         }
         Commands::Shell(shell) => Ok(exit_status_to_exit_code(shell.cmd().await?)),
+        Commands::Check(check) => Ok(exit_status_to_exit_code(check.cmd().await?)),
+        Commands::Explain(explain) => Ok(exit_status_to_exit_code(explain.cmd().await?)),
+        Commands::Init(init) => Ok(exit_status_to_exit_code(init.cmd().await?)),
+        Commands::Doctor(doctor) => Ok(exit_status_to_exit_code(doctor.cmd().await?)),
+        Commands::Cache(cache) => Ok(exit_status_to_exit_code(cache.cmd().await?)),
+        Commands::Completions(completions) => {
+            Ok(exit_status_to_exit_code(completions.cmd().await?))
+        }
+        Commands::PrintFlake(print_flake) => Ok(exit_status_to_exit_code(print_flake.cmd().await?)),
+        Commands::Direnv(direnv) => Ok(exit_status_to_exit_code(direnv.cmd().await?)),
+        Commands::Telemetry(telemetry) => Ok(exit_status_to_exit_code(telemetry.cmd().await?)),
         Commands::Run(run) => {
             let code = run.cmd().await?;
             if let Some(code) = code {
@@ -101,42 +148,72 @@ fn exit_status_to_exit_code(status: Option<i32>) -> ExitCode {
         .unwrap_or(ExitCode::SUCCESS)
 }
 
-#[tracing::instrument]
-async fn setup_tracing() -> eyre::Result<()> {
-    let debug = std::env::args()
-        .take_while(|v| v != "--")
-        .any(|v| v == "--debug");
-
-    let filter_layer = match EnvFilter::try_from_default_env() {
-        Ok(layer) => layer,
-        Err(e) => {
-            // Catch a parse error and report it, ignore a missing env.
-            if let Some(source) = e.source() {
-                match source.downcast_ref::<std::env::VarError>() {
-                    Some(std::env::VarError::NotPresent) => (),
-                    _ => return Err(e).wrap_err_with(|| "parsing RUST_LOG directives"),
-                }
-            }
-            EnvFilter::try_new(format!("{}={}", env!("CARGO_PKG_NAME"), "info"))?
-        }
-    };
+#[tracing::instrument(skip_all)]
+async fn setup_tracing(
+    log_level: Option<LogLevel>,
+    log_file: Option<&Path>,
+    log_format: LogFormat,
+) -> eyre::Result<()> {
+    // `--log-level` overrides `RIFF_LOG`, which overrides `RUST_LOG`, which overrides `warn`
+    let mut filter_layer = EnvFilter::try_new(format!("{}=warn", env!("CARGO_PKG_NAME")))?;
+    for env_var in ["RUST_LOG", "RIFF_LOG"] {
+        let directives = match std::env::var(env_var) {
+            Ok(directives) if !directives.is_empty() => directives,
+            _ => continue,
+        };
+        // A bare level is riff's own, like `--log-level`
+        let directives = match LogLevel::from_str(&directives, true) {
+            Ok(level) => format!("{}={level}", env!("CARGO_PKG_NAME")),
+            Err(_) => directives,
+        };
+        filter_layer = EnvFilter::try_new(&directives)
+            .wrap_err_with(|| format!("parsing {env_var} directives"))?;
+    }
 
-    let filter_layer = if debug {
-        let directive = format!("{}={}", env!("CARGO_PKG_NAME"), "debug").parse()?;
-        filter_layer.add_directive(directive)
-    } else {
-        filter_layer
-    };
+    if let Some(log_level) = log_level {
+        let directive = format!("{}={log_level}", env!("CARGO_PKG_NAME")).parse()?;
+        filter_layer = filter_layer.add_directive(directive);
+    }
 
     // Initialize tracing with tracing-error, and eyre
     let fmt_layer = tracing_subscriber::fmt::Layer::new()
-        .with_ansi(atty::is(Stream::Stderr))
+        .with_ansi(riff::color::enabled())
         .with_writer(std::io::stderr)
         .pretty();
 
+    // The filter applies to every layer, so the log file has the same level as stderr
+    let log_file = match log_file {
+        Some(path) => Some(Arc::new(std::fs::File::create(path).wrap_err_with(
+            || format!("Unable to create the log file `{}`", path.display()),
+        )?)),
+        None => None,
+    };
+    let (pretty_file_layer, json_file_layer) = match (log_file, log_format) {
+        (Some(file), LogFormat::Pretty) => (
+            Some(
+                tracing_subscriber::fmt::Layer::new()
+                    .with_ansi(false)
+                    .with_writer(file)
+                    .pretty(),
+            ),
+            None,
+        ),
+        (Some(file), LogFormat::Json) => (
+            None,
+            Some(
+                tracing_subscriber::fmt::Layer::new()
+                    .with_writer(file)
+                    .json(),
+            ),
+        ),
+        (None, _) => (None, None),
+    };
+
     tracing_subscriber::registry()
         .with(filter_layer)
         .with(fmt_layer)
+        .with(pretty_file_layer)
+        .with(json_file_layer)
         .with(ErrorLayer::default())
         .try_init()?;
 
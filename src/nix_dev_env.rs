@@ -1,14 +1,24 @@
 use std::collections::{HashMap, HashSet};
-use std::path::Path;
-use std::process::Stdio;
+use std::process::{ExitStatus, Stdio};
+use std::time::Duration;
 
-use eyre::WrapErr;
-use owo_colors::OwoColorize;
+use eyre::{eyre, WrapErr};
 use serde::Deserialize;
-use tokio::process::Command;
+use tokio::process::{Child, Command};
 
-pub async fn get_nix_dev_env(flake_dir: &Path) -> color_eyre::Result<NixDevEnv> {
-    let output = get_raw_nix_dev_env(flake_dir).await?;
+use crate::color::Colorize;
+use crate::flake_generator::FlakeDir;
+use crate::spawn_error::{SpawnError, Tool};
+use crate::spinner::{output_with_spinner, SimpleSpinner};
+
+pub async fn get_nix_dev_env(
+    flake_dir: &FlakeDir,
+    package: Option<&str>,
+    offline: bool,
+    nix_args: &[String],
+    quiet: bool,
+) -> color_eyre::Result<NixDevEnv> {
+    let output = get_raw_nix_dev_env(flake_dir, package, offline, nix_args, quiet).await?;
 
     serde_json::from_str(&output).wrap_err(
         "Unable to parse output produced by `nix print-dev-env` into our desired structure",
@@ -23,6 +33,7 @@ pub struct NixDevEnv {
 
 #[derive(Debug, Clone, Deserialize)]
 #[serde(tag = "type", content = "value")]
+#[allow(dead_code)] // Only `Exported` is consumed, the rest are kept to mirror the schema.
 pub enum Variable {
     #[serde(rename = "exported")]
     Exported(String),
@@ -34,64 +45,532 @@ pub enum Variable {
     Associative(HashMap<String, String>),
 }
 
-pub async fn get_raw_nix_dev_env(flake_dir: &Path) -> color_eyre::Result<String> {
+/// Run `nix print-dev-env --json` on the flake in `flake_dir`, in the shell of the workspace
+/// member `package` if set
+///
+/// `nix_args` are passed to `nix` verbatim after the flake reference. Unless `quiet`, a spinner is
+/// shown until `nix` prints anything.
+pub async fn get_raw_nix_dev_env(
+    flake_dir: &FlakeDir,
+    package: Option<&str>,
+    offline: bool,
+    nix_args: &[String],
+    quiet: bool,
+) -> color_eyre::Result<String> {
+    let mut nix_command = nix_print_dev_env_command(flake_dir, package, offline, nix_args)?;
+    tracing::trace!(command = ?nix_command.as_std(), "Running");
+    let spinner = if quiet {
+        indicatif::ProgressBar::hidden()
+    } else {
+        SimpleSpinner::new_with_message(Some(&format!(
+            "Running `{nix_print_dev_env}`",
+            nix_print_dev_env = "nix print-dev-env".cyan()
+        )))
+        .wrap_err("Failed to construct progress spinner")?
+    };
+
+    // Spawning could fail with `EWOULDBLOCK`
+    let nix_command_exit = output_with_spinner(&mut nix_command, spinner)
+        .await
+        .map_err(|err| SpawnError::new("nix print-dev-env", Tool::Nix, err))?;
+
+    if !nix_command_exit.status.success() {
+        warn_if_oom_killed(
+            "nix print-dev-env",
+            &nix_command_exit.status,
+            Some(&nix_command_exit.stdout),
+        );
+        warn_if_nix_too_old("nix print-dev-env").await;
+        warn_if_evaluation_failed(flake_dir, "flake.nix", &nix_command_exit.stderr).await;
+        return Err(eyre!(
+            "`nix print-dev-env` exited with code {}",
+            nix_command_exit
+                .status
+                .code()
+                .map(|x| x.to_string())
+                .unwrap_or_else(|| "unknown".to_string()),
+        ));
+    }
+
+    String::from_utf8(nix_command_exit.stdout)
+        .wrap_err("Output produced by `nix print-dev-env` was not valid UTF8")
+}
+
+/// The `nix print-dev-env --json` command [`get_raw_nix_dev_env`] runs
+pub fn nix_print_dev_env_command(
+    flake_dir: &FlakeDir,
+    package: Option<&str>,
+    offline: bool,
+    nix_args: &[String],
+) -> color_eyre::Result<Command> {
     let mut nix_command = Command::new("nix");
     nix_command
         .arg("print-dev-env")
         .arg("--json")
         .args(["--extra-experimental-features", "flakes nix-command"])
         .arg("-L")
-        .arg(format!("path://{}", flake_dir.to_str().unwrap()))
+        .arg(flake_dir.installable(package)?)
+        .args(nix_args)
         .stdin(Stdio::inherit())
         .stdout(Stdio::piped())
         .stderr(Stdio::inherit());
+
+    if offline && flake_dir.is_locked() {
+        nix_command.arg("--offline");
+    }
+    Ok(nix_command)
+}
+
+/// Printed before the environment by [`nix_shell_command`], so anything a `shellHook` prints can
+/// be told apart from it
+const NIX_SHELL_ENV_MARKER: &[u8] = b"\0riff-env\0";
+
+/// Variables of the `nix-shell` which describe the shell rather than the environment
+const NIX_SHELL_IGNORED_VARS: [&str; 4] = ["_", "OLDPWD", "PWD", "SHLVL"];
+
+/// Evaluate the `shell.nix` in `flake_dir` with `nix-shell`, for Nix installations without flakes
+///
+/// `nix_args` are passed to `nix-shell` verbatim after the `shell.nix`. Unless `quiet`, a spinner
+/// is shown until `nix-shell` prints anything.
+pub async fn get_nix_shell_dev_env(
+    flake_dir: &FlakeDir,
+    nix_args: &[String],
+    quiet: bool,
+) -> color_eyre::Result<NixDevEnv> {
+    let mut nix_command = nix_shell_command(flake_dir, nix_args);
     tracing::trace!(command = ?nix_command.as_std(), "Running");
+    let spinner = if quiet {
+        indicatif::ProgressBar::hidden()
+    } else {
+        SimpleSpinner::new_with_message(Some(&format!(
+            "Running `{nix_shell}`",
+            nix_shell = "nix-shell".cyan()
+        )))
+        .wrap_err("Failed to construct progress spinner")?
+    };
 
-    // TODO(@hoverbear): Try to enable this somehow. Right now since we don't keep the lock
-    // in a consistent place, we can't reliably pick up a lock generated in online mode.
-    //
-    // If we stored the generated flake/lock in a consistent place this could be enabled.
-    //
-    // if self.offline {
-    //     nix_develop_command.arg("--offline");
-    // }
-
-    let nix_command_exit = match nix_command
-        .spawn()
-        .wrap_err("Failed to spawn `nix develop`")? // This could throw a `EWOULDBLOCK`
-        .wait_with_output()
+    let nix_command_exit = output_with_spinner(&mut nix_command, spinner)
         .await
+        .map_err(|err| SpawnError::new("nix-shell", Tool::Nix, err))?;
+
+    if !nix_command_exit.status.success() {
+        warn_if_oom_killed(
+            "nix-shell",
+            &nix_command_exit.status,
+            Some(&nix_command_exit.stdout),
+        );
+        warn_if_evaluation_failed(flake_dir, "shell.nix", &nix_command_exit.stderr).await;
+        return Err(eyre!(
+            "`nix-shell` exited with code {}",
+            nix_command_exit
+                .status
+                .code()
+                .map(|x| x.to_string())
+                .unwrap_or_else(|| "unknown".to_string()),
+        ));
+    }
+
+    parse_nix_shell_env(&nix_command_exit.stdout)
+}
+
+/// The `nix-shell` command [`get_nix_shell_dev_env`] runs, which prints the environment of the
+/// `shell.nix` after [`NIX_SHELL_ENV_MARKER`]
+///
+/// The shell is pure, so the environment only contains what the `shell.nix` provides, like
+/// `nix print-dev-env`.
+pub fn nix_shell_command(flake_dir: &FlakeDir, nix_args: &[String]) -> Command {
+    let mut nix_command = Command::new("nix-shell");
+    nix_command
+        .arg("--pure")
+        .arg(flake_dir.shell_nix())
+        .args(["--run", r"printf '\0riff-env\0' && env -0"])
+        .args(nix_args)
+        .stdin(Stdio::inherit())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit());
+    nix_command
+}
+
+/// Parse the output of [`nix_shell_command`]: NUL separated `NAME=value` pairs after
+/// [`NIX_SHELL_ENV_MARKER`]
+fn parse_nix_shell_env(output: &[u8]) -> color_eyre::Result<NixDevEnv> {
+    let start = output
+        .windows(NIX_SHELL_ENV_MARKER.len())
+        .rposition(|window| window == NIX_SHELL_ENV_MARKER)
+        .ok_or_else(|| eyre!("`nix-shell` did not print the environment"))?;
+    let env = std::str::from_utf8(&output[start + NIX_SHELL_ENV_MARKER.len()..])
+        .wrap_err("Environment printed by `nix-shell` was not valid UTF8")?;
+    let variables = env
+        .split('\0')
+        .filter_map(|var| var.split_once('='))
+        .filter(|(name, _)| !NIX_SHELL_IGNORED_VARS.contains(name))
+        .map(|(name, value)| (name.to_string(), Variable::Exported(value.to_string())))
+        .collect();
+    Ok(NixDevEnv { variables })
+}
+
+/// Print the `nix` command riff evaluates the flake in `flake_dir` with, and `command` it then runs
+/// in that environment, instead of running them
+///
+/// With `legacy_nix`, the `nix-shell` command evaluating the `shell.nix` is printed instead.
+pub fn print_dry_run(
+    flake_dir: &FlakeDir,
+    package: Option<&str>,
+    offline: bool,
+    nix_args: &[String],
+    legacy_nix: bool,
+    command: &[String],
+) -> color_eyre::Result<()> {
+    let nix_command = if legacy_nix {
+        nix_shell_command(flake_dir, nix_args)
+    } else {
+        nix_print_dev_env_command(flake_dir, package, offline, nix_args)?
+    };
+    let mut run_command = std::process::Command::new(&command[0]);
+    run_command.args(&command[1..]);
+    println!(
+        "# Evaluate the environment of the {kind} in `{flake_dir}`\n{nix_command}\n# Run in that environment\n{run_command}",
+        kind = if legacy_nix { "shell.nix" } else { "flake" },
+        flake_dir = flake_dir.path().display(),
+        nix_command = command_line(nix_command.as_std()),
+        run_command = command_line(&run_command),
+    );
+    Ok(())
+}
+
+/// `command` as a line which can be pasted into a POSIX shell
+pub fn command_line(command: &std::process::Command) -> String {
+    std::iter::once(command.get_program())
+        .chain(command.get_args())
+        .map(|arg| shell_quote(&arg.to_string_lossy()))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// `arg`, single-quoted if a POSIX shell would otherwise split or expand it
+fn shell_quote(arg: &str) -> String {
+    let is_safe = |c: char| c.is_ascii_alphanumeric() || "%+,-./:=@_".contains(c);
+    if !arg.is_empty() && arg.chars().all(is_safe) {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', r"'\''"))
+    }
+}
+
+/// Print a hint if a `nix` command was killed by `SIGKILL` without producing any output
+///
+/// This is almost always the kernel's out-of-memory killer, which is common on memory constrained
+/// CI runners evaluating large flakes. `output` is `None` when the output was not captured.
+pub fn warn_if_oom_killed(command_name: &str, status: &ExitStatus, output: Option<&[u8]>) {
+    #[cfg(unix)]
     {
-        Ok(nix_command_exit) => nix_command_exit,
-        Err(err) => {
-            let err_msg = format!(
+        use std::os::unix::process::ExitStatusExt;
+        const SIGKILL: i32 = 9;
+
+        let produced_output = output.map(|v| !v.is_empty()).unwrap_or(false);
+        if status.signal() == Some(SIGKILL) && !produced_output {
+            eprintln!(
                 "\
-                Could not execute `{nix_print_dev_env}`. Is `{nix}` installed?\n\n\
-                Get instructions for installing Nix: {nix_install_url}\
+                `{command_name}` was killed by `{sigkill}` without producing any output. \
+                This usually means it ran out of memory.\n\n\
+                Try increasing the memory available, or limit how many builds Nix runs at once:\n\
+                \t{nix_config_example}\n\
                 ",
-                nix_print_dev_env = "nix print-dev-env".cyan(),
-                nix = "nix".cyan(),
-                nix_install_url = "https://nixos.org/download.html".blue().underline(),
+                command_name = command_name.cyan(),
+                sigkill = "SIGKILL".red(),
+                nix_config_example = "NIX_CONFIG='max-jobs = 1' riff shell".cyan(),
             );
-            eprintln!("{err_msg}\n\nUnderlying error:\n{err}", err = err.red());
-            std::process::exit(1);
         }
+    }
+    #[cfg(not(unix))]
+    let _ = (command_name, status, output);
+}
+
+/// Explain Nix failing to evaluate the `file_name` riff generated in `flake_dir`, from the
+/// `stderr` of the `nix` command
+///
+/// Riff generates valid Nix, so a failure to evaluate it is almost always a build input which
+/// isn't in Nixpkgs, from `package.metadata.riff`, the registry, or `--build-input`.
+async fn warn_if_evaluation_failed(flake_dir: &FlakeDir, file_name: &str, stderr: &[u8]) {
+    let generated = tokio::fs::read_to_string(flake_dir.path().join(file_name))
+        .await
+        .unwrap_or_default();
+    let cause = match evaluation_error_cause(&String::from_utf8_lossy(stderr), file_name) {
+        Some(cause) => cause,
+        None => return,
     };
+    let path = flake_dir.path().join(file_name);
+    let mut explanation = format!(
+        "{warning} Nix could not evaluate the `{file_name}` riff generated",
+        warning = "!".yellow(),
+        file_name = file_name.cyan(),
+    );
+    if let Some(line) = cause
+        .line
+        .and_then(|line| generated.lines().nth(line.checked_sub(1)?))
+    {
+        explanation.push_str(&format!(", at:\n\n\t{}\n", line.trim().red()));
+    } else {
+        explanation.push('\n');
+    }
+    if let Some(name) = &cause.missing_name {
+        explanation.push_str(&format!(
+            "\n`{name}` isn't in Nixpkgs. Check the `{build_inputs}` and `{runtime_inputs}` in \
+            `{metadata}`, and any `{build_input}`, or find the crate the registry adds it for with \
+            `{riff_check}`.\n",
+            name = name.cyan(),
+            build_inputs = "build-inputs".cyan(),
+            runtime_inputs = "runtime-inputs".cyan(),
+            metadata = "package.metadata.riff".cyan(),
+            build_input = "--build-input".cyan(),
+            riff_check = "riff check --format json".cyan(),
+        ));
+    }
+    match flake_dir {
+        FlakeDir::Persistent(_) => explanation.push_str(&format!(
+            "\nThe generated `{file_name}` is `{path}`.",
+            file_name = file_name.cyan(),
+            path = path.display().to_string().green(),
+        )),
+        FlakeDir::Temporary(_) => explanation.push_str(&format!(
+            "\nPass `{keep_flake}` to keep the generated `{file_name}` for inspecting.",
+            keep_flake = "--keep-flake".cyan(),
+            file_name = file_name.cyan(),
+        )),
+    }
+    eprintln!("{explanation}\n");
+}
 
-    String::from_utf8(nix_command_exit.stdout)
-        .wrap_err("Output produced by `nix print-dev-env` was not valid UTF8")
+/// What Nix reported about failing to evaluate the generated `file_name`
+#[derive(Debug, PartialEq, Eq)]
+struct EvaluationErrorCause {
+    /// The line of the generated file the error is at, starting from 1
+    line: Option<usize>,
+    /// The variable or attribute Nix couldn't find, Eg a build input that isn't in Nixpkgs
+    missing_name: Option<String>,
+}
+
+/// Find the cause of an evaluation error in the generated `file_name` in Nix's `stderr`
+///
+/// Returns `None` if the error isn't in the generated file, Eg a failing build.
+fn evaluation_error_cause(stderr: &str, file_name: &str) -> Option<EvaluationErrorCause> {
+    // Nix reports the position like `at /nix/store/...-source/flake.nix:22:15:`
+    let position_marker = format!("/{file_name}:");
+    let line = stderr.split_once(&position_marker).and_then(|(_, after)| {
+        after
+            .split(':')
+            .next()
+            .and_then(|line| line.parse::<usize>().ok())
+    });
+    let quoted_after = |prefix: &str| {
+        let (_, after) = stderr.split_once(prefix)?;
+        let (name, _) = after.split_once('\'')?;
+        Some(name.to_string())
+    };
+    let missing_name = quoted_after("undefined variable '")
+        .or_else(|| quoted_after("error: attribute '").filter(|_| stderr.contains("' missing")));
+    if line.is_none() && missing_name.is_none() {
+        return None;
+    }
+    Some(EvaluationErrorCause { line, missing_name })
+}
+
+/// The oldest Nix with flakes and `nix print-dev-env`, which riff needs without `--legacy-nix`
+pub const MINIMUM_NIX_VERSION: semver::Version = semver::Version::new(2, 4, 0);
+
+/// Print the minimum Nix version if `nix --version` is older, to explain why a `nix` command failed
+pub async fn warn_if_nix_too_old(command_name: &str) {
+    let output = match Command::new("nix").arg("--version").output().await {
+        Ok(output) if output.status.success() => output.stdout,
+        _ => return,
+    };
+    let version = match parse_nix_version(&String::from_utf8_lossy(&output)) {
+        Some(version) => version,
+        None => return,
+    };
+    if version < MINIMUM_NIX_VERSION {
+        eprintln!(
+            "\
+            `{command_name}` needs Nix {MINIMUM_NIX_VERSION} or newer, but Nix {version} is installed.\n\n\
+            Get instructions for upgrading Nix: {nix_install_url}\n\n\
+            Or pass `{legacy_nix}` to `riff shell` or `riff run` to use `{nix_shell}`, which doesn't need flakes.\n\
+            ",
+            command_name = command_name.cyan(),
+            version = version.red(),
+            nix_install_url = "https://nixos.org/download.html".blue().underline(),
+            legacy_nix = "--legacy-nix".cyan(),
+            nix_shell = "nix-shell".cyan(),
+        );
+    }
+}
+
+/// The version in the output of `nix --version`, Eg `nix (Nix) 2.3.16`
+fn parse_nix_version(output: &str) -> Option<semver::Version> {
+    let version = output.split_whitespace().last()?;
+    // Pre-releases look like `2.4pre20211001_4033203`, which are older than their release
+    let (version, pre) = match version.split_once("pre") {
+        Some((version, _)) => (version, true),
+        None => (version, false),
+    };
+    let mut parts = version.split('.').map(|part| part.parse::<u64>().ok());
+    let mut version = semver::Version::new(
+        parts.next()??,
+        parts.next().flatten().unwrap_or(0),
+        parts.next().flatten().unwrap_or(0),
+    );
+    if pre {
+        version.pre = semver::Prerelease::new("pre").ok()?;
+    }
+    Some(version)
+}
+
+/// Wait for `child` to exit, forwarding `SIGINT` and `SIGTERM` to it in the meantime
+///
+/// Riff keeps running until the child has exited, so the generated flake directory is only
+/// removed afterwards. On Ctrl+C the terminal also signals the child directly, so it may see
+/// `SIGINT` twice.
+pub async fn wait_forwarding_signals(child: &mut Child) -> std::io::Result<ExitStatus> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut interrupt = signal(SignalKind::interrupt())?;
+        let mut terminate = signal(SignalKind::terminate())?;
+        let (sender, mut signals) = tokio::sync::mpsc::channel(1);
+        let received = tokio::spawn(async move {
+            loop {
+                let signum = tokio::select! {
+                    _ = interrupt.recv() => libc::SIGINT,
+                    _ = terminate.recv() => libc::SIGTERM,
+                };
+                if sender.send(signum).await.is_err() {
+                    break;
+                }
+            }
+        });
+        let status = wait_forwarding(child, &mut signals).await;
+        received.abort();
+        status
+    }
+    #[cfg(not(unix))]
+    child.wait().await
+}
+
+/// Wait for `child` to exit, sending it each signal number received from `signals`
+#[cfg(unix)]
+async fn wait_forwarding(
+    child: &mut Child,
+    signals: &mut tokio::sync::mpsc::Receiver<libc::c_int>,
+) -> std::io::Result<ExitStatus> {
+    loop {
+        let signum = tokio::select! {
+            status = child.wait() => return status,
+            Some(signum) = signals.recv() => signum,
+        };
+        tracing::debug!(pid = child.id(), signum, "Forwarding signal to the child");
+        signal_child(child, signum);
+    }
+}
+
+/// How long [`stop_child`] waits for a child to exit after `SIGTERM`, before killing it
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Stop `child`, with `SIGTERM` so it can clean up, then `SIGKILL` if it hasn't exited after
+/// [`STOP_TIMEOUT`]
+pub async fn stop_child(child: &mut Child) -> std::io::Result<ExitStatus> {
+    #[cfg(unix)]
+    if child.id().is_some() {
+        signal_child(child, libc::SIGTERM);
+        if let Ok(status) = tokio::time::timeout(STOP_TIMEOUT, child.wait()).await {
+            return status;
+        }
+        signal_child(child, libc::SIGKILL);
+    }
+    child.kill().await?;
+    child.wait().await
 }
 
+/// Send `signum` to `child`, or to its whole process group if it leads its own
+#[cfg(unix)]
+fn signal_child(child: &Child, signum: libc::c_int) {
+    let pid = match child.id() {
+        Some(pid) => pid as libc::pid_t,
+        None => return,
+    };
+    // SAFETY: `getpgid` and `kill` have no memory safety requirements, and `pid` is our child
+    // which has not been reaped yet
+    unsafe {
+        let target = if libc::getpgid(pid) == pid { -pid } else { pid };
+        libc::kill(target, signum);
+    }
+}
+
+/// The exit code of a process with `status`, like a shell reports it
+///
+/// A process killed by a signal has no exit code, so on Unix this is `128` plus the signal number,
+/// Eg `137` for `SIGKILL`.
+pub fn exit_code(status: &ExitStatus) -> Option<i32> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        status
+            .code()
+            .or_else(|| status.signal().map(|signum| 128 + signum))
+    }
+    #[cfg(not(unix))]
+    status.code()
+}
+
+/// How [`run_in_dev_env`] treats the ambient environment
+#[derive(Debug, Clone, Default)]
+pub struct EnvironmentOptions {
+    /// Do not inherit the ambient environment, like `nix develop --ignore-environment`
+    pub(crate) pure: bool,
+    /// With `pure`, do not keep [`PURE_KEPT_VARS`] either
+    pub(crate) strict_pure: bool,
+    /// Variables whose ambient value is kept in pure mode, which are otherwise inherited anyway
+    ///
+    /// Like the inherited environment, these are overridden by the dev environment's variables,
+    /// or have them prepended, Eg for `PATH`.
+    pub(crate) keep: Vec<String>,
+    /// Variables removed from the environment
+    pub(crate) unset: Vec<String>,
+}
+
+/// Variables kept from the ambient environment in pure mode, without them interactive shells break
+const PURE_KEPT_VARS: [&str; 3] = ["HOME", "TERM", "USER"];
+
 pub async fn run_in_dev_env(
     dev_env: &NixDevEnv,
     command_name: &str,
+    options: &EnvironmentOptions,
 ) -> color_eyre::Result<Command> {
     let mut command = Command::new(command_name);
 
+    if options.pure {
+        command.env_clear();
+        if !options.strict_pure {
+            for name in PURE_KEPT_VARS {
+                if let Ok(value) = std::env::var(name) {
+                    command.env(name, value);
+                }
+            }
+        }
+        for name in &options.keep {
+            if let Ok(value) = std::env::var(name) {
+                command.env(name, value);
+            }
+        }
+    }
+
     // TODO(@edolstra): Copied from develop.cc, would be nice to
     // keep these in sync somehow (e.g. `nix print-dev-env --json`
     // could output them).
-    let prepended_vars = HashSet::from(["PATH".to_owned(), "XDG_DATA_DIRS".to_owned()]);
+    //
+    // `LD_LIBRARY_PATH` is our addition, so the libraries of `runtime-inputs` are found before
+    // any the user already has.
+    let prepended_vars =
+        HashSet::from(["PATH", "XDG_DATA_DIRS", "LD_LIBRARY_PATH"].map(str::to_owned));
 
     let ignored_vars = HashSet::from(
         [
@@ -122,7 +601,7 @@ pub async fn run_in_dev_env(
                 continue;
             }
             let mut value = value.clone();
-            if prepended_vars.contains(name) {
+            if prepended_vars.contains(name) && (!options.pure || options.keep.contains(name)) {
                 if let Ok(old_value) = std::env::var(name) {
                     value = format!("{value}:{old_value}");
                 }
@@ -131,6 +610,10 @@ pub async fn run_in_dev_env(
         }
     }
 
+    for name in &options.unset {
+        command.env_remove(name);
+    }
+
     // Increment $IN_RIFF.
     command.env(
         "IN_RIFF",
@@ -166,3 +649,201 @@ pub async fn get_shell() -> color_eyre::Result<String> {
         .await?
         .unwrap_or_else(|| "bash".to_owned()))
 }
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::process::Command;
+
+    use super::{
+        command_line, evaluation_error_cause, exit_code, parse_nix_shell_env, parse_nix_version,
+        run_in_dev_env, stop_child, wait_forwarding, EnvironmentOptions, EvaluationErrorCause,
+        NixDevEnv, Variable, MINIMUM_NIX_VERSION,
+    };
+
+    #[tokio::test]
+    async fn wait_forwarding_forwards_sigterm() -> eyre::Result<()> {
+        let mut child = Command::new("sh")
+            .args(["-c", "trap 'exit 7' TERM; while true; do sleep 0.1; done"])
+            .spawn()?;
+        let (sender, mut signals) = tokio::sync::mpsc::channel(1);
+        let wait = tokio::spawn(async move { wait_forwarding(&mut child, &mut signals).await });
+        // Give the child time to set its trap
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        sender.send(libc::SIGTERM).await?;
+        let status = tokio::time::timeout(Duration::from_secs(10), wait).await???;
+        assert_eq!(status.code(), Some(7));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stop_child_sends_sigterm() -> eyre::Result<()> {
+        let mut child = Command::new("sh")
+            .args(["-c", "trap 'exit 7' TERM; while true; do sleep 0.1; done"])
+            .spawn()?;
+        // Give the child time to set its trap
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let status = stop_child(&mut child).await?;
+        assert_eq!(status.code(), Some(7));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stop_child_stops_process_group() -> eyre::Result<()> {
+        let mut child = Command::new("sh")
+            .args(["-c", "trap 'exit 7' TERM; while true; do sleep 0.1; done"])
+            .process_group(0)
+            .spawn()?;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let status = stop_child(&mut child).await?;
+        assert_eq!(status.code(), Some(7));
+        Ok(())
+    }
+
+    #[test]
+    fn evaluation_error_cause_finds_missing_input() {
+        let stderr = "\
+error: undefined variable 'bogus'
+
+       at /nix/store/1cj1wl2p1ph7c1rm3hz6wbgpmvwi8b7l-source/flake.nix:22:15:
+
+           21|               bashInteractive
+           22|               bogus
+             |               ^
+";
+        assert_eq!(
+            evaluation_error_cause(stderr, "flake.nix"),
+            Some(EvaluationErrorCause {
+                line: Some(22),
+                missing_name: Some("bogus".into()),
+            })
+        );
+
+        let stderr = "\
+error: attribute 'nope' missing
+
+       at /nix/store/xq1ja7s1ynh3zxbqb0h3vyh7cwl6f5jm-source/flake.nix:23:15:
+";
+        assert_eq!(
+            evaluation_error_cause(stderr, "flake.nix"),
+            Some(EvaluationErrorCause {
+                line: Some(23),
+                missing_name: Some("nope".into()),
+            })
+        );
+        assert_eq!(
+            evaluation_error_cause(stderr, "shell.nix"),
+            Some(EvaluationErrorCause {
+                line: None,
+                missing_name: Some("nope".into()),
+            })
+        );
+    }
+
+    #[test]
+    fn evaluation_error_cause_ignores_build_failures() {
+        let stderr = "error: builder for '/nix/store/4b2lq9zbx8vkkqmnsrm3vvkd0s3dajk1-openssl-3.0.12.drv' failed with exit code 2";
+        assert_eq!(evaluation_error_cause(stderr, "flake.nix"), None);
+    }
+
+    #[tokio::test]
+    async fn exit_code_of_signalled_process() -> eyre::Result<()> {
+        let status = Command::new("sh")
+            .args(["-c", "kill -KILL $$"])
+            .status()
+            .await?;
+        assert_eq!(status.code(), None);
+        assert_eq!(exit_code(&status), Some(128 + 9));
+
+        let status = Command::new("sh").args(["-c", "exit 3"]).status().await?;
+        assert_eq!(exit_code(&status), Some(3));
+        Ok(())
+    }
+
+    #[test]
+    fn parse_nix_version_handles_releases() {
+        assert_eq!(
+            parse_nix_version("nix (Nix) 2.3.16\n"),
+            Some(semver::Version::new(2, 3, 16))
+        );
+        assert_eq!(
+            parse_nix_version("nix (Nix) 2.18.1"),
+            Some(semver::Version::new(2, 18, 1))
+        );
+        assert_eq!(
+            parse_nix_version("nix (Nix) 2.4"),
+            Some(MINIMUM_NIX_VERSION)
+        );
+        assert!(
+            parse_nix_version("nix (Nix) 2.4pre20211001_4033203").unwrap() < MINIMUM_NIX_VERSION
+        );
+        assert_eq!(parse_nix_version("not nix"), None);
+    }
+
+    #[test]
+    fn parse_nix_shell_env_skips_shell_hook_output() -> eyre::Result<()> {
+        let output =
+            b"hello from the shellHook\n\0riff-env\0PATH=/nix/store/a/bin\0MULTI=a\nb\0PWD=/tmp\0";
+        let dev_env = parse_nix_shell_env(output)?;
+        assert!(matches!(
+            dev_env.variables.get("PATH"),
+            Some(Variable::Exported(path)) if path == "/nix/store/a/bin"
+        ));
+        assert!(matches!(
+            dev_env.variables.get("MULTI"),
+            Some(Variable::Exported(multi)) if multi == "a\nb"
+        ));
+        assert!(!dev_env.variables.contains_key("PWD"));
+        assert!(parse_nix_shell_env(b"PATH=/bin\0").is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn run_in_dev_env_keeps_nix_path() -> eyre::Result<()> {
+        let dev_env = NixDevEnv {
+            variables: [(
+                "PATH".to_string(),
+                Variable::Exported("/nix/store/a/bin".to_string()),
+            )]
+            .into(),
+        };
+        let host_path = std::env::var("PATH")?;
+        let keep = vec!["PATH".to_string()];
+        for (pure, keep, expected) in [
+            (false, keep.clone(), format!("/nix/store/a/bin:{host_path}")),
+            (true, keep, format!("/nix/store/a/bin:{host_path}")),
+            (true, vec![], "/nix/store/a/bin".to_string()),
+        ] {
+            let options = EnvironmentOptions {
+                pure,
+                keep,
+                ..Default::default()
+            };
+            let command = run_in_dev_env(&dev_env, "true", &options).await?;
+            let path = command
+                .as_std()
+                .get_envs()
+                .find(|(name, _)| *name == "PATH")
+                .and_then(|(_, value)| value);
+            assert_eq!(path, Some(expected.as_ref()), "{options:?}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn command_line_quotes_arguments() {
+        let mut command = std::process::Command::new("nix");
+        command.args([
+            "--extra-experimental-features",
+            "flakes nix-command",
+            "path:///tmp/riff%20flake",
+            "it's",
+            "",
+        ]);
+        assert_eq!(
+            command_line(&command),
+            r#"nix --extra-experimental-features 'flakes nix-command' path:///tmp/riff%20flake 'it'\''s' ''"#
+        );
+    }
+}
@@ -0,0 +1,210 @@
+//! Rust toolchains pinned with `rust-toolchain.toml`.
+
+use std::path::{Path, PathBuf};
+
+use eyre::{eyre, WrapErr};
+use itertools::Itertools;
+use toml_edit::DocumentMut;
+
+/// The files `rustup` reads a pinned toolchain from, in order of precedence
+const TOOLCHAIN_FILES: [&str; 2] = ["rust-toolchain", "rust-toolchain.toml"];
+
+/// A toolchain pinned in a `rust-toolchain.toml` (or legacy `rust-toolchain`) file
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RustToolchain {
+    /// Eg `stable`, `1.70.0` or `nightly-2023-06-01`
+    pub(crate) channel: String,
+    pub(crate) components: Vec<String>,
+    pub(crate) targets: Vec<String>,
+}
+
+impl RustToolchain {
+    /// Find the toolchain file of the project in `project_dir`, searching parent directories like
+    /// `rustup` does
+    #[tracing::instrument(skip_all, fields(project_dir = %project_dir.display()))]
+    pub(crate) async fn find(project_dir: &Path) -> color_eyre::Result<Option<(PathBuf, Self)>> {
+        let project_dir = tokio::fs::canonicalize(project_dir)
+            .await
+            .unwrap_or_else(|_| project_dir.to_path_buf());
+        for dir in project_dir.ancestors() {
+            for file in TOOLCHAIN_FILES {
+                let path = dir.join(file);
+                let content = match tokio::fs::read_to_string(&path).await {
+                    Ok(content) => content,
+                    Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+                    Err(err) => {
+                        return Err(err)
+                            .wrap_err_with(|| format!("Unable to read `{}`", path.display()))
+                    }
+                };
+                let toolchain = Self::parse(&content)
+                    .wrap_err_with(|| format!("Unable to parse `{}`", path.display()))?;
+                tracing::debug!(path = %path.display(), ?toolchain, "Found a pinned toolchain");
+                return Ok(Some((path, toolchain)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Parse either the TOML form (`[toolchain] channel = "..."`) or the legacy bare channel form
+    pub(crate) fn parse(content: &str) -> color_eyre::Result<Self> {
+        let content = content.trim();
+        if !content.contains(['\n', '=', '[']) {
+            return Ok(Self {
+                channel: parse_name("channel", content)?,
+                ..Default::default()
+            });
+        }
+
+        let document: DocumentMut = content.parse()?;
+        let toolchain = document
+            .get("toolchain")
+            .and_then(|toolchain| toolchain.as_table_like())
+            .ok_or_else(|| eyre!("There is no `[toolchain]` table"))?;
+        if toolchain.contains_key("path") {
+            return Err(eyre!(
+                "Custom toolchains with `path` are not supported, only `channel`"
+            ));
+        }
+        let channel = toolchain
+            .get("channel")
+            .and_then(|channel| channel.as_str())
+            .ok_or_else(|| eyre!("`toolchain.channel` is missing or not a string"))?;
+        let list = |key: &str| -> color_eyre::Result<Vec<String>> {
+            match toolchain.get(key) {
+                None => Ok(vec![]),
+                Some(value) => value
+                    .as_array()
+                    .ok_or_else(|| eyre!("`toolchain.{key}` is not an array"))?
+                    .iter()
+                    .map(|item| {
+                        item.as_str()
+                            .ok_or_else(|| eyre!("`toolchain.{key}` contains a non-string value"))
+                            .and_then(|item| parse_name(key, item))
+                    })
+                    .collect(),
+            }
+        };
+
+        Ok(Self {
+            channel: parse_name("channel", channel)?,
+            components: list("components")?,
+            targets: list("targets")?,
+        })
+    }
+
+    /// A Nix expression for this toolchain using the `rust-bin` attribute of the oxalica
+    /// `rust-overlay`, with any `extra_targets` added
+    pub(crate) fn to_nix(&self, extra_targets: &[&str]) -> String {
+        let quote_all = |items: &mut dyn Iterator<Item = &str>| {
+            items.unique().map(|item| format!("\"{item}\" ")).join("")
+        };
+        format!(
+            "(rust-bin.fromRustupToolchain {{ channel = \"{channel}\"; components = [ {components}]; targets = [ {targets}]; }})",
+            channel = self.channel,
+            components = quote_all(&mut self.components.iter().map(String::as_str)),
+            targets = quote_all(
+                &mut self
+                    .targets
+                    .iter()
+                    .map(String::as_str)
+                    .chain(extra_targets.iter().copied())
+            ),
+        )
+    }
+}
+
+impl From<RustChannel> for Option<RustToolchain> {
+    fn from(channel: RustChannel) -> Self {
+        match channel {
+            RustChannel::None => None,
+            RustChannel::Channel(channel) => Some(RustToolchain {
+                channel,
+                ..Default::default()
+            }),
+        }
+    }
+}
+
+/// A Rust channel chosen with `--rust` or `package.metadata.riff.rust-channel`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RustChannel {
+    /// Riff does not provide a Rust toolchain, the one on the host is used
+    None,
+    /// Eg `stable`, `beta`, `nightly` or `1.75.0`
+    Channel(String),
+}
+
+impl std::str::FromStr for RustChannel {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(RustChannel::None),
+            channel => Ok(RustChannel::Channel(parse_name("channel", channel)?)),
+        }
+    }
+}
+
+/// Check a component passed on the command line (Eg `rust-analyzer`) can be placed in the
+/// generated flake
+pub(crate) fn parse_component(component: &str) -> color_eyre::Result<String> {
+    parse_name("component", component)
+}
+
+/// Check a channel, component or target name can be placed in the generated flake
+fn parse_name(kind: &str, name: &str) -> color_eyre::Result<String> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+    {
+        return Err(eyre!("`{name}` is not a valid toolchain {kind}"));
+    }
+    Ok(name.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RustChannel, RustToolchain};
+
+    #[test]
+    fn rust_toolchain_parse() -> eyre::Result<()> {
+        let toolchain = RustToolchain::parse(
+            r#"
+[toolchain]
+channel = "nightly-2023-06-01"
+components = [ "rustfmt", "rust-src" ]
+targets = [ "wasm32-unknown-unknown" ]
+profile = "minimal"
+            "#,
+        )?;
+        assert_eq!(
+            toolchain,
+            RustToolchain {
+                channel: "nightly-2023-06-01".into(),
+                components: vec!["rustfmt".into(), "rust-src".into()],
+                targets: vec!["wasm32-unknown-unknown".into()],
+            }
+        );
+        assert_eq!(
+            toolchain.to_nix(&["aarch64-unknown-linux-gnu", "wasm32-unknown-unknown"]),
+            r#"(rust-bin.fromRustupToolchain { channel = "nightly-2023-06-01"; components = [ "rustfmt" "rust-src" ]; targets = [ "wasm32-unknown-unknown" "aarch64-unknown-linux-gnu" ]; })"#
+        );
+
+        let toolchain = RustToolchain::parse("1.70.0\n")?;
+        assert_eq!(toolchain.channel, "1.70.0");
+        assert!(toolchain.components.is_empty());
+
+        assert_eq!("none".parse::<RustChannel>()?, RustChannel::None);
+        assert_eq!(
+            "1.75.0".parse::<RustChannel>()?,
+            RustChannel::Channel("1.75.0".into())
+        );
+        assert!("1.75 .0".parse::<RustChannel>().is_err());
+
+        assert!(RustToolchain::parse("[toolchain]\npath = \"/opt/rust\"").is_err());
+        assert!(RustToolchain::parse("[toolchain]\nchannel = \"x\\\"; y\"").is_err());
+        Ok(())
+    }
+}
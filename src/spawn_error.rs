@@ -0,0 +1,62 @@
+//! The error for a tool riff runs, like `nix` or `cargo`, which couldn't be started.
+
+use crate::color::Colorize;
+
+/// The tools riff runs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tool {
+    Nix,
+    Cargo,
+}
+
+/// Running `command` failed before it could report anything, usually because `tool` isn't
+/// installed
+#[derive(Debug, thiserror::Error)]
+#[error("Could not execute `{command}`")]
+pub struct SpawnError {
+    command: &'static str,
+    tool: Tool,
+    #[source]
+    source: std::io::Error,
+}
+
+impl SpawnError {
+    pub(crate) fn new(command: &'static str, tool: Tool, source: std::io::Error) -> Self {
+        Self {
+            command,
+            tool,
+            source,
+        }
+    }
+
+    /// The command which was run, Eg `nix flake lock`
+    pub fn command(&self) -> &str {
+        self.command
+    }
+
+    /// The tool the command belongs to
+    pub fn tool(&self) -> Tool {
+        self.tool
+    }
+
+    /// How to install the tool, for showing along with the error
+    pub fn hint(&self) -> String {
+        match self.tool {
+            Tool::Nix => format!(
+                "Is `{nix}` installed?\n\nGet instructions for installing Nix: {nix_install_url}",
+                nix = "nix".cyan(),
+                nix_install_url = "https://nixos.org/download.html".blue().underline(),
+            ),
+            Tool::Cargo => format!(
+                "Is `{cargo}` installed?\n\nGet instructions for installing Cargo: {rust_install_url}",
+                cargo = "cargo".cyan(),
+                rust_install_url = "https://www.rust-lang.org/tools/install".blue().underline(),
+            ),
+        }
+    }
+
+    /// The code riff exits with
+    pub fn exit_code(&self) -> u8 {
+        1
+    }
+}
@@ -1,17 +1,33 @@
+use std::io::Write;
+use std::process::{Output, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::Duration;
 
-use indicatif::{ProgressBar, ProgressStyle};
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+use tokio::io::AsyncReadExt;
+use tokio::process::Command;
 
 const LEADER: char = ' ';
 const DRUM: char = '🥁';
 const QUARTER_NOTE: char = '♩';
 const EIGHTH_NOTE: char = '♪';
 
+/// If `--no-progress` was passed
+static DISABLED: AtomicBool = AtomicBool::new(false);
+
+/// Don't show any progress spinners, for `--no-progress`
+pub fn disable() {
+    DISABLED.store(true, Ordering::Relaxed);
+}
+
 pub struct SimpleSpinner;
 
 impl SimpleSpinner {
     pub fn new_with_message(msg: Option<&str>) -> color_eyre::Result<ProgressBar> {
         let spinner = ProgressBar::new_spinner();
+        if DISABLED.load(Ordering::Relaxed) || !crate::color::enabled() {
+            spinner.set_draw_target(ProgressDrawTarget::hidden());
+        }
         spinner.enable_steady_tick(Duration::from_millis(260));
         spinner.set_style(
             ProgressStyle::with_template("{msg}{spinner}")?.tick_strings(&[
@@ -39,3 +55,57 @@ impl SimpleSpinner {
         Ok(spinner)
     }
 }
+
+/// Run `command` to completion, collecting its stdout and stderr, with `spinner` shown until it
+/// writes to stderr
+///
+/// The command's stderr is forwarded to ours as it is written, as well as collected, so errors
+/// can be explained afterwards. The spinner is cleared before it is forwarded, so the two don't
+/// interleave.
+pub async fn output_with_spinner(
+    command: &mut Command,
+    spinner: ProgressBar,
+) -> std::io::Result<Output> {
+    let mut child = command.stderr(Stdio::piped()).spawn()?;
+    let mut child_stderr = child.stderr.take().expect("stderr is piped");
+    let forward_stderr = async {
+        let mut stderr = Vec::new();
+        let mut buffer = [0; 8192];
+        loop {
+            let read = child_stderr.read(&mut buffer).await?;
+            if read == 0 {
+                return Ok::<_, std::io::Error>(stderr);
+            }
+            spinner.finish_and_clear();
+            std::io::stderr().write_all(&buffer[..read])?;
+            stderr.extend_from_slice(&buffer[..read]);
+        }
+    };
+    let (output, stderr) = tokio::join!(child.wait_with_output(), forward_stderr);
+    spinner.finish_and_clear();
+    let stderr = stderr?;
+    output.map(|output| Output { stderr, ..output })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::Stdio;
+
+    use indicatif::ProgressBar;
+    use tokio::process::Command;
+
+    use super::output_with_spinner;
+
+    #[tokio::test]
+    async fn output_with_spinner_collects_output() -> eyre::Result<()> {
+        let mut command = Command::new("sh");
+        command
+            .args(["-c", "echo err >&2; echo out"])
+            .stdout(Stdio::piped());
+        let output = output_with_spinner(&mut command, ProgressBar::new_spinner()).await?;
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"out\n");
+        assert_eq!(output.stderr, b"err\n");
+        Ok(())
+    }
+}
@@ -1,6 +1,6 @@
 use std::{collections::HashSet, path::Path, time::Duration};
 
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 use eyre::eyre;
 use reqwest::Response;
 use secrecy::Secret;
@@ -21,7 +21,35 @@ This ID is completely random and contains no personally identifiable information
 You can delete this file at any time to create a new ID.
 You can also disable ID generation; see the documentation on telemetry to see how to do so.";
 static TELEMETRY_REMOTE_URL: &str = "https://registry.riff.determinate.systems/telemetry";
+/// Set to send telemetry somewhere other than [`TELEMETRY_REMOTE_URL`], Eg a self-hosted endpoint
+static TELEMETRY_URL_VAR: &str = "RIFF_TELEMETRY_URL";
 pub static TELEMETRY_HEADER_NAME: &str = "X-RIFF-Client-Info";
+/// Environment variables which turn telemetry off when set, whatever the command line says
+static TELEMETRY_OPT_OUT_VARS: [&str; 2] = ["DO_NOT_TRACK", "RIFF_DISABLE_TELEMETRY"];
+
+/// How much telemetry is sent, the values of `--telemetry-level`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum TelemetryLevel {
+    /// Everything in [`Telemetry`]
+    #[default]
+    Full,
+    /// Only what counts versions and platforms, without the installation's ID or what the
+    /// project uses
+    Minimal,
+    /// Nothing, the same as `--disable-telemetry`
+    None,
+}
+
+impl std::fmt::Display for TelemetryLevel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let level = match self {
+            TelemetryLevel::Full => "full",
+            TelemetryLevel::Minimal => "minimal",
+            TelemetryLevel::None => "none",
+        };
+        f.write_str(level)
+    }
+}
 
 #[derive(Default, Debug, Clone, Copy, Serialize)]
 struct DistinctId(Uuid);
@@ -32,7 +60,12 @@ impl secrecy::SerializableSecret for DistinctId {}
 impl secrecy::DebugSecret for DistinctId {}
 
 #[derive(Debug, Serialize)]
-pub(crate) struct Telemetry {
+pub struct Telemetry {
+    /// Where this is sent, which isn't part of the data sent
+    #[serde(skip)]
+    url: String,
+    #[serde(skip)]
+    level: TelemetryLevel,
     /// Stored in `$XDG_DATA_HOME/riff/distinct_id` as a UUIDv4
     distinct_id: Option<Secret<DistinctId>>,
     system_os: String,
@@ -54,13 +87,22 @@ pub(crate) struct Telemetry {
 }
 
 impl Telemetry {
-    pub(crate) async fn from_clap_parse_result(command: Option<&crate::Commands>) -> Self {
-        let distinct_id = match distinct_id().await {
-            Ok(distinct_id) => Some(Secret::new(DistinctId(distinct_id))),
-            Err(err) => {
-                tracing::debug!(err = %eyre::eyre!(err), "Could get distinct ID for telemetry");
-                None
-            }
+    /// With the distinct ID kept in `config_dir`, or else `$XDG_CONFIG_HOME/riff`
+    pub(crate) async fn from_clap_parse_result(
+        command: Option<&crate::Commands>,
+        level: TelemetryLevel,
+        config_dir: Option<&Path>,
+    ) -> Self {
+        // The ID identifies the installation, so it isn't even created with `minimal`.
+        let distinct_id = match level {
+            TelemetryLevel::Full => match distinct_id(config_dir).await {
+                Ok(distinct_id) => Some(Secret::new(DistinctId(distinct_id))),
+                Err(err) => {
+                    tracing::debug!(err = %eyre::eyre!(err), "Could get distinct ID for telemetry");
+                    None
+                }
+            },
+            TelemetryLevel::Minimal | TelemetryLevel::None => None,
         };
 
         let system_os = std::env::consts::OS.to_string();
@@ -84,10 +126,21 @@ impl Telemetry {
             Some(Commands::Shell(_)) => Some("shell".to_string()),
             Some(Commands::Run(_)) => Some("run".to_string()),
             Some(Commands::PrintDevEnv(_)) => Some("print-dev-env".to_string()),
+            Some(Commands::Check(_)) => Some("check".to_string()),
+            Some(Commands::PrintFlake(_)) => Some("print-flake".to_string()),
+            Some(Commands::Direnv(_)) => Some("direnv".to_string()),
+            Some(Commands::Explain(_)) => Some("explain".to_string()),
+            Some(Commands::Init(_)) => Some("init".to_string()),
+            Some(Commands::Doctor(_)) => Some("doctor".to_string()),
+            Some(Commands::Cache(_)) => Some("cache".to_string()),
+            Some(Commands::Completions(_)) => Some("completions".to_string()),
+            Some(Commands::Telemetry(_)) => Some("telemetry".to_string()),
             None => None,
         };
 
         Self {
+            url: url_from_env().unwrap_or_else(|| TELEMETRY_REMOTE_URL.to_string()),
+            level,
             distinct_id,
             system_os,
             system_arch,
@@ -105,39 +158,108 @@ impl Telemetry {
     /// Create a new `Telemetry` without any pre-existing information
     ///
     /// This is not very performant and may do things like re-invoke `nix` or reparse the `$ARG`s.
-    pub(crate) async fn new() -> Self {
-        let cli = Cli::try_parse().ok().map(|c| c.command);
+    /// It only reads local files and runs `nix --version`, nothing connects out until [`send`],
+    /// which is never called with `--offline`.
+    ///
+    /// [`send`]: Telemetry::send
+    pub async fn new() -> Self {
+        let cli = Cli::try_parse().ok();
+        // Without the parsed arguments, only `RIFF_TELEMETRY_LEVEL` is known
+        let level = match &cli {
+            Some(cli) => cli.telemetry_level,
+            None => level_from_env(),
+        };
+
+        Self::from_clap_parse_result(cli.as_ref().map(|cli| &cli.command), level, None).await
+    }
+
+    /// Where this is sent
+    pub fn url(&self) -> &str {
+        &self.url
+    }
 
-        Self::from_clap_parse_result(cli.as_ref()).await
+    /// How much of this is sent
+    pub fn level(&self) -> TelemetryLevel {
+        self.level
     }
 
+    /// Send this to `url` from the configuration file, unless `RIFF_TELEMETRY_URL` is set
+    pub(crate) fn with_configured_url(mut self, url: Option<&str>) -> Self {
+        if let (None, Some(url)) = (url_from_env(), url) {
+            self.url = url.to_string();
+        }
+        self
+    }
+
+    /// Add the languages of the project, unless the level is `minimal`
     pub(crate) fn with_detected_languages(mut self, languages: &HashSet<DetectedLanguage>) -> Self {
-        self.detected_languages = languages.iter().cloned().collect();
+        if self.level == TelemetryLevel::Full {
+            self.detected_languages = languages.iter().cloned().collect();
+        }
         self
     }
 
     #[tracing::instrument(skip_all)]
-    pub(crate) async fn send(&self) -> eyre::Result<Response> {
-        tracing::trace!(data = ?self, "Sending telemetry data to {TELEMETRY_REMOTE_URL}");
+    pub async fn send(&self) -> eyre::Result<Response> {
+        if disabled_by_env() || self.level == TelemetryLevel::None {
+            return Err(eyre!("Telemetry is disabled"));
+        }
+        tracing::trace!(data = ?self, "Sending telemetry data to {}", self.url);
         let header_data = self.as_header_data()?;
         let http_client = reqwest::Client::new();
         let req = http_client
-            .post(TELEMETRY_REMOTE_URL)
+            .post(&self.url)
             .header(TELEMETRY_HEADER_NAME, &header_data)
             .timeout(Duration::from_millis(250));
         let res = req.send().await?;
-        tracing::debug!(telemetry = ?self, "Sent telemetry data to {TELEMETRY_REMOTE_URL}");
+        tracing::debug!(telemetry = ?self, "Sent telemetry data to {}", self.url);
         Ok(res)
     }
 
-    pub(crate) fn as_header_data(&self) -> Result<String, serde_json::Error> {
+    /// The value of the [`TELEMETRY_HEADER_NAME`] header, exactly as it is sent
+    pub fn as_header_data(&self) -> Result<String, serde_json::Error> {
         serde_json::to_string(&self)
     }
 }
 
-async fn distinct_id() -> eyre::Result<Uuid> {
-    let xdg_dirs = xdg::BaseDirectories::with_prefix(RIFF_XDG_PREFIX)?;
-    let distinct_id_path = xdg_dirs.place_config_file(Path::new(TELEMETRY_DISTINCT_ID_PATH))?;
+/// If telemetry is turned off by `DO_NOT_TRACK` (see <https://consoledonottrack.com>) or
+/// `RIFF_DISABLE_TELEMETRY`, set to anything but `false`, `0` or nothing
+pub fn disabled_by_env() -> bool {
+    TELEMETRY_OPT_OUT_VARS
+        .iter()
+        .any(|name| opts_out(std::env::var(name).ok().as_deref()))
+}
+
+/// The level set with `RIFF_TELEMETRY_LEVEL`, or `full`
+fn level_from_env() -> TelemetryLevel {
+    std::env::var("RIFF_TELEMETRY_LEVEL")
+        .ok()
+        .and_then(|level| TelemetryLevel::from_str(&level, true).ok())
+        .unwrap_or_default()
+}
+
+/// The URL set with `RIFF_TELEMETRY_URL`, if it is set and not empty
+fn url_from_env() -> Option<String> {
+    std::env::var(TELEMETRY_URL_VAR)
+        .ok()
+        .filter(|url| !url.is_empty())
+}
+
+fn opts_out(value: Option<&str>) -> bool {
+    !matches!(value, None | Some("" | "0" | "false"))
+}
+
+/// The ID of this installation, kept in `config_dir` or else `$XDG_CONFIG_HOME/riff`, created on
+/// first use
+async fn distinct_id(config_dir: Option<&Path>) -> eyre::Result<Uuid> {
+    let distinct_id_path = match config_dir {
+        Some(config_dir) => {
+            tokio::fs::create_dir_all(config_dir).await?;
+            config_dir.join(TELEMETRY_DISTINCT_ID_PATH)
+        }
+        None => xdg::BaseDirectories::with_prefix(RIFF_XDG_PREFIX)?
+            .place_config_file(Path::new(TELEMETRY_DISTINCT_ID_PATH))?,
+    };
 
     let mut distinct_id_file = OpenOptions::new()
         .read(true)
@@ -192,3 +314,48 @@ async fn nix_version() -> eyre::Result<Option<String>> {
         Err(err) => Err(err.into()),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::{distinct_id, opts_out, Telemetry, TelemetryLevel, TELEMETRY_DISTINCT_ID_PATH};
+    use crate::dev_env::DetectedLanguage;
+
+    #[tokio::test]
+    async fn minimal_leaves_out_identity() -> eyre::Result<()> {
+        let config_dir = tempfile::TempDir::new()?;
+        let telemetry = Telemetry::from_clap_parse_result(
+            None,
+            TelemetryLevel::Minimal,
+            Some(config_dir.path()),
+        )
+        .await
+        .with_detected_languages(&HashSet::from([DetectedLanguage::Rust]));
+        let header: serde_json::Value = serde_json::from_str(&telemetry.as_header_data()?)?;
+        assert!(header["distinct_id"].is_null());
+        assert_eq!(header["detected_languages"], serde_json::json!([]));
+        assert_eq!(header["riff_version"], env!("CARGO_PKG_VERSION"));
+        assert!(!config_dir.path().join(TELEMETRY_DISTINCT_ID_PATH).exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn distinct_id_is_kept() -> eyre::Result<()> {
+        let config_dir = tempfile::TempDir::new()?;
+        let id = distinct_id(Some(config_dir.path())).await?;
+        assert!(config_dir.path().join(TELEMETRY_DISTINCT_ID_PATH).exists());
+        assert_eq!(distinct_id(Some(config_dir.path())).await?, id);
+        Ok(())
+    }
+
+    #[test]
+    fn opts_out_values() {
+        assert!(opts_out(Some("1")));
+        assert!(opts_out(Some("true")));
+        assert!(!opts_out(Some("0")));
+        assert!(!opts_out(Some("false")));
+        assert!(!opts_out(Some("")));
+        assert!(!opts_out(None));
+    }
+}